@@ -0,0 +1,32 @@
+use std::env;
+use std::process::Command;
+
+// Records build information for the /version endpoint, see src/version.rs
+// -- GIT_HASH -> the commit being built, left unset when git or the repository isn't available (i.e building from a tarball)
+// -- RUSTC_VERSION -> the compiler doing the build
+fn main() {
+    if let Some(hash) = output("git", &["rev-parse", "--short", "HEAD"]) {
+        println!("cargo:rustc-env=GIT_HASH={}", hash);
+    }
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    if let Some(version) = output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=RUSTC_VERSION={}", version);
+    }
+
+    // Rebuild when a commit is made or checked out
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+// Trimmed stdout of `program args`, None when it can't run or fails
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let text = String::from_utf8(output.stdout).ok()?;
+    Some(text.trim().to_string()).filter(|text| !text.is_empty())
+}
@@ -0,0 +1,167 @@
+use crate::message::Message;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::Deserialize;
+use rocket::{Request, Response};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Level the access log is written at, "off" disables it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn log(self, line: fmt::Arguments<'_>) {
+        match self {
+            LogLevel::Off => {}
+            LogLevel::Error => error!("{}", line),
+            LogLevel::Warn => warn!("{}", line),
+            LogLevel::Info => info!("{}", line),
+            LogLevel::Debug => debug!("{}", line),
+        }
+    }
+}
+
+// Access log of who posts to /message and who connects to /events
+// -- posts log their room, username and body length, never the body itself
+// -- streams log when they open and, with how long they lasted, when they close
+// -- `usernames` can be turned off to keep usernames out of the logs, they are logged as "-"
+// Managed for handlers to log streams, and attached as a fairing to log posts once their response is ready
+#[derive(Debug, Clone, Copy)]
+pub struct AccessLog {
+    level: LogLevel,
+    usernames: bool,
+}
+
+impl AccessLog {
+    pub fn new(level: LogLevel, usernames: bool) -> AccessLog {
+        AccessLog { level, usernames }
+    }
+
+    fn username<'a>(&self, username: Option<&'a str>) -> &'a str {
+        match username {
+            Some(username) if self.usernames => username,
+            _ => "-",
+        }
+    }
+
+    // Log an /events stream opening, the returned guard logs it closing
+    // Move the guard into the stream so it drops on every way the stream can end
+    pub fn stream(
+        &self,
+        room: Option<&str>,
+        username: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> StreamLog {
+        let room = room.unwrap_or("*").to_string();
+        let username = self.username(username).to_string();
+        let ip = ip.map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        self.level.log(format_args!(
+            "events stream opened room={} username={} ip={}",
+            room, username, ip
+        ));
+
+        StreamLog {
+            level: self.level,
+            started: Instant::now(),
+            room,
+            username,
+            ip,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access Log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if req.method() != Method::Post || req.uri().path() != "/message" {
+            return;
+        }
+
+        let posted = req.local_cache(Posted::default).0.lock().unwrap().take();
+        match posted {
+            Some(posted) => self.level.log(format_args!(
+                "message posted id={} room={} username={} bytes={}",
+                posted.id,
+                posted.room,
+                self.username(Some(&posted.username)),
+                posted.bytes
+            )),
+            None => self.level.log(format_args!(
+                "message rejected status={}",
+                res.status().code
+            )),
+        }
+    }
+}
+
+// Request local record of the message a /message post published, read back by the AccessLog fairing
+// Request guard, posting handlers record the message once it's published
+#[derive(Default)]
+pub struct Posted(Mutex<Option<PostRecord>>);
+
+struct PostRecord {
+    id: u64,
+    room: String,
+    username: String,
+    bytes: usize,
+}
+
+impl Posted {
+    pub fn record(&self, msg: &Message) {
+        *self.0.lock().unwrap() = Some(PostRecord {
+            id: msg.id,
+            room: msg.room.clone(),
+            username: msg.username.clone(),
+            bytes: msg.message.len(),
+        });
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Posted {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(Posted::default))
+    }
+}
+
+// Logs an /events stream closing when dropped, see AccessLog::stream
+pub struct StreamLog {
+    level: LogLevel,
+    started: Instant,
+    room: String,
+    username: String,
+    ip: String,
+}
+
+impl Drop for StreamLog {
+    fn drop(&mut self) {
+        self.level.log(format_args!(
+            "events stream closed room={} username={} ip={} after {:.1}s",
+            self.room,
+            self.username,
+            self.ip,
+            self.started.elapsed().as_secs_f64()
+        ));
+    }
+}
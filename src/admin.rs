@@ -0,0 +1,906 @@
+use crate::auth::{secret_matches, Reservations, SessionToken};
+use crate::bans::Bans;
+use crate::channels::{ChannelStats, Channels};
+use crate::config::ChatConfig;
+use crate::error::ApiError;
+use crate::lock::RoomLocks;
+use crate::message::{check_name, normalize_room, Message, MessageIds};
+use crate::metrics::Metrics;
+use crate::moderators::RoomModerators;
+use crate::motd::Motd;
+use crate::presence::{Presence, PresentStream, RoomCaps};
+use crate::queries::QuerySlot;
+use crate::slow_mode::SlowMode;
+use crate::store::Store;
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::stream::TextStream;
+use rocket::serde::json::{self, Json};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Shutdown, State};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// Header a moderator presents the configured admin_token in
+pub const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+// Messages /admin/export reads from the store at a time
+const EXPORT_PAGE_SIZE: usize = 500;
+
+// Request guard protecting the /admin endpoints
+// Fails with 403 Forbidden when the X-Admin-Token header is missing or doesn't match,
+// and always when no admin_token is configured, the endpoints are disabled then
+#[derive(Debug, Clone, Copy)]
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match req.guard::<&State<ChatConfig>>().await {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        match &config.admin_token {
+            None => Outcome::Error((Status::Forbidden, ())),
+            Some(token) if secret_matches(req.headers().get_one(ADMIN_TOKEN_HEADER), token) => {
+                Outcome::Success(Admin)
+            }
+            Some(_) => Outcome::Error((Status::Forbidden, ())),
+        }
+    }
+}
+
+// Request guard for the moderation endpoints scoped to a room (lock, slow mode, closing it, banning from it, deleting its messages)
+// -- Admin -> the X-Admin-Token header matched, moderates every room
+// -- Room -> the session token (see SessionToken) of a registered username, which moderates the rooms it was made a moderator of
+// Which room a request is about is only known from its body, so handlers call `check` once they have it
+// Fails with 403 Forbidden when the client presented neither
+#[derive(Debug, Clone)]
+pub enum Moderator {
+    Admin,
+    Room(String),
+}
+
+impl Moderator {
+    pub fn moderates(&self, room: &str, moderators: &RoomModerators) -> bool {
+        match self {
+            Moderator::Admin => true,
+            Moderator::Room(username) => moderators.is_moderator(room, username),
+        }
+    }
+
+    // 403 unless the moderator moderates `room`
+    pub fn check(&self, room: &str, moderators: &RoomModerators) -> Result<(), ApiError> {
+        if !self.moderates(room, moderators) {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                format!("you don't moderate {}", room),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Moderator {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Outcome::Success(Admin) = req.guard::<Admin>().await {
+            return Outcome::Success(Moderator::Admin);
+        }
+
+        let reservations = match req.guard::<&State<Reservations>>().await {
+            Outcome::Success(reservations) => reservations,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        let Outcome::Success(SessionToken(Some(token))) = req.guard::<SessionToken>().await else {
+            return Outcome::Error((Status::Forbidden, ()));
+        };
+        match reservations.holder(&token) {
+            Some(username) => Outcome::Success(Moderator::Room(username)),
+            None => Outcome::Error((Status::Forbidden, ())),
+        }
+    }
+}
+
+// The username a moderation is logged under
+impl std::fmt::Display for Moderator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Moderator::Admin => f.write_str("admin"),
+            Moderator::Room(username) => f.write_str(username),
+        }
+    }
+}
+
+// JSON body accepted by /admin/moderators
+// -- i.e {"room":"lobby","username":"alice","moderator":true} makes alice a moderator of lobby, false takes it back
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ModeratorForm {
+    pub room: String,
+    pub username: String,
+    pub moderator: bool,
+}
+
+// Endpoint to make a User a Room Moderator
+// Only with the admin token, room moderators can't appoint others
+// The username has to be registered (see /register), its session token is what it moderates with
+#[post("/admin/moderators", format = "json", data = "<json>")]
+pub fn moderators(
+    _admin: Admin,
+    json: Result<Json<ModeratorForm>, json::Error<'_>>,
+    reservations: &State<Reservations>,
+    moderators: &State<RoomModerators>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    check_name("room", &form.room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    check_name("username", &form.username)
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    if form.moderator && !reservations.is_reserved(&form.username) {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            format!(
+                "username {} isn't registered, only registered users can moderate",
+                form.username
+            ),
+        ));
+    }
+    let room = normalize_room(&form.room);
+
+    moderators.set(&room, &form.username, form.moderator);
+    if form.moderator {
+        info!("made {} a moderator of {}", form.username, room);
+    } else {
+        info!("{} is no longer a moderator of {}", form.username, room);
+    }
+
+    Ok(Status::NoContent)
+}
+
+// JSON body accepted by /admin/ban, a username, an IP or both, and the room to ban them from if not every room
+// -- i.e {"username":"mallory"}, {"ip":"203.0.113.7"} or {"username":"mallory","room":"lobby"}
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BanForm {
+    pub username: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub room: Option<String>,
+}
+
+// Endpoint to Ban a User
+// Takes effect immediately, open /events and /ws connections of the user are closed within a second (see BAN_CHECK_INTERVAL)
+// -- with a room, the user is only banned from that room, open to the room's moderators (see Moderator)
+// -- without one, the user is banned from every room, which only the admin token can do
+// Bans only live in memory, they are lifted when the server restarts
+#[post("/admin/ban", format = "json", data = "<json>")]
+pub fn ban(
+    moderator: Moderator,
+    json: Result<Json<BanForm>, json::Error<'_>>,
+    bans: &State<Bans>,
+    moderators: &State<RoomModerators>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    let username = form
+        .username
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+    if username.is_none() && form.ip.is_none() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "a username or an ip to ban is required",
+        ));
+    }
+
+    let Some(room) = form.room else {
+        if !matches!(moderator, Moderator::Admin) {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                "only the admin can ban from every room",
+            ));
+        }
+        if let Some(username) = username {
+            bans.ban_username(username);
+            info!("banned username {}", username);
+        }
+        if let Some(ip) = form.ip {
+            bans.ban_ip(ip);
+            info!("banned ip {}", ip);
+        }
+        return Ok(Status::NoContent);
+    };
+
+    check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&room);
+    moderator.check(&room, moderators)?;
+    if let Some(username) = username {
+        bans.ban_username_from(&room, username);
+        info!("{} banned username {} from {}", moderator, username, room);
+    }
+    if let Some(ip) = form.ip {
+        bans.ban_ip_from(&room, ip);
+        info!("{} banned ip {} from {}", moderator, ip, room);
+    }
+
+    Ok(Status::NoContent)
+}
+
+// JSON body accepted by /admin/slowmode
+// -- i.e {"room":"lobby","seconds":10} lets every user post to lobby once every 10 seconds, 0 turns slow mode off
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SlowModeForm {
+    pub room: String,
+    pub seconds: u64,
+}
+
+// Endpoint to put a Room in Slow Mode
+// Overrides the configured slow_mode for that room, until the server restarts
+// Open to the room's moderators, see Moderator
+#[post("/admin/slowmode", format = "json", data = "<json>")]
+pub fn slow_mode(
+    moderator: Moderator,
+    json: Result<Json<SlowModeForm>, json::Error<'_>>,
+    slow_mode: &State<SlowMode>,
+    moderators: &State<RoomModerators>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    check_name("room", &form.room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&form.room);
+    moderator.check(&room, moderators)?;
+
+    slow_mode.set(&room, form.seconds);
+    info!(
+        "{} set slow mode of {} to {}s",
+        moderator, room, form.seconds
+    );
+
+    Ok(Status::NoContent)
+}
+
+// JSON body accepted by /admin/motd
+// -- i.e {"motd":"Welcome! Be nice."}, null or a blank string clears it
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MotdForm {
+    pub motd: Option<String>,
+}
+
+// Endpoint to change the Message of the Day
+// Replaces the configured motd until the server restarts, streams opening from now on get the new one
+#[post("/admin/motd", format = "json", data = "<json>")]
+pub fn motd(
+    _admin: Admin,
+    json: Result<Json<MotdForm>, json::Error<'_>>,
+    motd: &State<Motd>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+
+    motd.set(form.motd);
+    info!("motd set to {:?}", motd.get());
+
+    Ok(Status::NoContent)
+}
+
+// JSON body accepted by /admin/roomcap
+// -- i.e {"room":"lobby","max_users":50} lets at most 50 users in lobby at once, null lifts the cap
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RoomCapForm {
+    pub room: String,
+    pub max_users: Option<usize>,
+}
+
+// Endpoint to cap how many Users a Room holds
+// Overrides the configured max_users_per_room for that room, until the server restarts
+// Users already in the room stay, only new /events connections are turned down
+#[post("/admin/roomcap", format = "json", data = "<json>")]
+pub fn room_cap(
+    _admin: Admin,
+    json: Result<Json<RoomCapForm>, json::Error<'_>>,
+    room_caps: &State<RoomCaps>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    check_name("room", &form.room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&form.room);
+
+    room_caps.set(&room, form.max_users);
+    info!("user cap of {} set to {:?}", room, form.max_users);
+
+    Ok(Status::NoContent)
+}
+
+// Largest channel capacity /admin/room-config accepts, every slot of a channel is allocated up front
+pub const MAX_ROOM_CAPACITY: usize = 65536;
+
+// JSON body accepted by /admin/room-config
+// -- i.e {"room":"lobby","capacity":8192} lets lobby's subscribers fall 8192 messages behind before they lag, null goes back to channel_capacity
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RoomConfigForm {
+    pub room: String,
+    pub capacity: Option<usize>,
+}
+
+// Endpoint to size a Room's broadcast channel
+// Overrides the configured channel_capacity for that room, until the server restarts
+// The room's current channel is replaced, its subscribers get a system message asking them to reconnect before their stream ends
+#[post("/admin/room-config", format = "json", data = "<json>")]
+pub fn room_config(
+    _admin: Admin,
+    json: Result<Json<RoomConfigForm>, json::Error<'_>>,
+    queue: &State<Channels>,
+    ids: &State<MessageIds>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    check_name("room", &form.room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    if let Some(capacity) = form.capacity {
+        if !(1..=MAX_ROOM_CAPACITY).contains(&capacity) {
+            return Err(ApiError::new(
+                Status::UnprocessableEntity,
+                format!("capacity must be between 1 and {}", MAX_ROOM_CAPACITY),
+            ));
+        }
+    }
+    let room = normalize_room(&form.room);
+
+    let notice = Message::system(
+        ids.next(),
+        &room,
+        "this room was reconfigured, reconnect to keep receiving its messages".into(),
+    );
+    queue.set_room_capacity(&room, form.capacity, notice);
+    info!("channel capacity of {} set to {:?}", room, form.capacity);
+
+    Ok(Status::NoContent)
+}
+
+// JSON body accepted by /admin/close-room
+// -- i.e {"room":"old-project"}
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CloseRoomForm {
+    pub room: String,
+}
+
+// Endpoint to Close a Room
+// Every /events stream and socket of the room gets a "room_closed" event and is then ended, so its users leave the room
+// Nothing else changes: the room's history is kept, and clients may join it again (lock it first to keep them from posting)
+// Open to the room's moderators, see Moderator
+#[post("/admin/close-room", format = "json", data = "<json>")]
+pub fn close_room(
+    moderator: Moderator,
+    json: Result<Json<CloseRoomForm>, json::Error<'_>>,
+    queue: &State<Channels>,
+    ids: &State<MessageIds>,
+    moderators: &State<RoomModerators>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    check_name("room", &form.room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&form.room);
+    moderator.check(&room, moderators)?;
+
+    queue.close_room(&room, Message::room_closed(ids.next(), &room));
+    info!("{} closed {}", moderator, room);
+
+    Ok(Status::NoContent)
+}
+
+// JSON body accepted by /admin/lock
+// -- i.e {"room":"announcements","locked":true} lets only moderators post to announcements, false unlocks it
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LockForm {
+    pub room: String,
+    pub locked: bool,
+}
+
+// Endpoint to make a Room Read-Only
+// Posts to a locked room need to come from one of its moderators, until it's unlocked or the server restarts
+// Reading it through /events, /ws and /history isn't affected
+// Open to the room's moderators, see Moderator
+#[post("/admin/lock", format = "json", data = "<json>")]
+pub fn lock(
+    moderator: Moderator,
+    json: Result<Json<LockForm>, json::Error<'_>>,
+    locks: &State<RoomLocks>,
+    moderators: &State<RoomModerators>,
+) -> Result<Status, ApiError> {
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    check_name("room", &form.room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&form.room);
+    moderator.check(&room, moderators)?;
+
+    locks.set(&room, form.locked);
+    info!(
+        "{} {} {}",
+        moderator,
+        if form.locked { "locked" } else { "unlocked" },
+        room
+    );
+
+    Ok(Status::NoContent)
+}
+
+// Endpoint to Shut the Server Down
+// Every room is told with a system message, then Rocket shuts down gracefully as it does on Ctrl-C:
+// open streams get their "shutdown" event (after shutdown_drain_ms) and the process exits once connections are done
+// Answers 202 since the shutdown is still under way when the response is sent
+#[post("/admin/shutdown")]
+pub fn shutdown(
+    _admin: Admin,
+    queue: &State<Channels>,
+    ids: &State<MessageIds>,
+    end: Shutdown,
+) -> Status {
+    queue.announce(|room| Message::system(ids.next(), room, "the server is shutting down".into()));
+    info!("moderator requested shutdown");
+    end.notify();
+
+    Status::Accepted
+}
+
+// Endpoint to Export a Room's History
+// Streams every public message of `room`, oldest first, as newline-delimited JSON (one Message per line)
+// -- Read from the store a page at a time, so large rooms are never held in memory at once
+// -- Answers 404 when the store has no message in `room`
+// -- Takes one of the QueryLimit's slots for as long as the export streams, 503 when none is free
+#[get("/admin/export?<room>")]
+pub async fn export(
+    _admin: Admin,
+    room: String,
+    slot: QuerySlot,
+    store: &State<Store>,
+) -> Result<(ContentType, TextStream![String]), ApiError> {
+    check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&room);
+    let failed = |e: sqlx::Error| {
+        error!("failed to export {}: {}", room, e);
+        ApiError::new(Status::InternalServerError, "failed to export history")
+    };
+
+    let first = store
+        .chunk(&room, 0, EXPORT_PAGE_SIZE)
+        .await
+        .map_err(failed)?;
+    if first.is_empty() {
+        return Err(ApiError::new(
+            Status::NotFound,
+            format!("room {} has no history", room),
+        ));
+    }
+
+    let store = store.inner().clone();
+    let ndjson = ContentType::new("application", "x-ndjson");
+    Ok((
+        ndjson,
+        TextStream! {
+            let _slot = slot;
+            let mut page = first;
+            loop {
+                let Some(last) = page.last().map(|msg| msg.id) else {
+                    break;
+                };
+                for msg in &page {
+                    let mut line = json::to_string(msg).expect("messages serialize to json");
+                    line.push('\n');
+                    yield line;
+                }
+
+                // The export is already under way, all that's left to do on an error is to cut it short
+                page = match store.chunk(&room, last, EXPORT_PAGE_SIZE).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        error!("failed to export {} after message {}: {}", room, last, e);
+                        break;
+                    }
+                };
+            }
+        },
+    ))
+}
+
+// Snapshot returned by /admin/stats
+// -- rooms -> number of rooms someone is connected to
+// -- subscribers -> open /events streams per room
+// -- messages_posted -> chat messages accepted since the server started
+// -- uptime_seconds -> time since the server started
+// -- bans -> number of usernames and IPs banned
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Stats {
+    pub rooms: usize,
+    pub subscribers: BTreeMap<String, usize>,
+    pub messages_posted: u64,
+    pub uptime_seconds: u64,
+    pub bans: usize,
+}
+
+// Snapshot returned by /debug/channel, to tune channel_capacity
+// -- capacity -> the configured channel_capacity
+// -- all -> the channel of subscribers to every room
+// -- rooms -> the channel of each room, by room
+// -- users -> the direct message channel of each user, by username
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ChannelsDebug {
+    pub capacity: usize,
+    pub all: ChannelStats,
+    pub rooms: BTreeMap<String, ChannelStats>,
+    pub users: BTreeMap<String, ChannelStats>,
+}
+
+// Endpoint to see how full the Broadcast Channels run
+// A channel whose `queued` keeps reaching `capacity` has subscribers getting "lagged" events
+#[get("/debug/channel")]
+pub fn channel(_admin: Admin, queue: &State<Channels>) -> Json<ChannelsDebug> {
+    Json(ChannelsDebug {
+        capacity: queue.capacity(),
+        all: queue.all_stats(),
+        rooms: queue.room_stats(),
+        users: queue.user_stats(),
+    })
+}
+
+// Endpoint to see who is Connected to a Room, and with what
+// Like /users, but with one entry per open /events stream, giving when it connected and its client's User-Agent
+#[get("/admin/users?<room>")]
+pub fn users(
+    _admin: Admin,
+    room: String,
+    presence: &State<Presence>,
+) -> Result<Json<Vec<PresentStream>>, ApiError> {
+    check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+
+    Ok(Json(presence.streams(&normalize_room(&room))))
+}
+
+// Endpoint for an Ops Dashboard
+#[get("/admin/stats")]
+pub fn stats(
+    _admin: Admin,
+    presence: &State<Presence>,
+    metrics: &State<Metrics>,
+    bans: &State<Bans>,
+) -> Json<Stats> {
+    let subscribers: BTreeMap<String, usize> = presence.rooms().into_iter().collect();
+
+    Json(Stats {
+        rooms: subscribers.len(),
+        subscribers,
+        messages_posted: metrics.posted(),
+        uptime_seconds: metrics.uptime().as_secs(),
+        bans: bans.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ADMIN_TOKEN_HEADER;
+    use crate::guards::MAX_USER_AGENT_LEN;
+    use crate::message::now_millis;
+    use crate::testing::{admin_token, as_admin, TestChat, LOCAL_IP, WAIT};
+    use rocket::http::{Header, Status};
+    use rocket::serde::json::{json, Value};
+    use rocket::tokio::time::Duration;
+
+    fn admin(chat: &TestChat, uri: &str, body: Value) -> Status {
+        let request = as_admin(chat.post_json(uri, &body));
+        chat.send(request).status
+    }
+
+    #[test]
+    fn banned_usernames_cant_post() {
+        let chat = TestChat::with_admin();
+        let _events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "mallory", "hi"), Status::Ok);
+
+        assert_eq!(
+            admin(&chat, "/admin/ban", json!({ "username": "mallory" })),
+            Status::NoContent
+        );
+        assert_eq!(chat.post("lobby", "mallory", "hi again"), Status::Forbidden);
+        assert_eq!(chat.post("lobby", "alice", "hi"), Status::Ok);
+    }
+
+    #[test]
+    fn banned_ips_cant_post_or_listen() {
+        let chat = TestChat::with_admin();
+        let _events = chat.events("room=lobby");
+        assert_eq!(
+            admin(&chat, "/admin/ban", json!({ "ip": LOCAL_IP })),
+            Status::NoContent
+        );
+
+        assert_eq!(chat.post("lobby", "alice", "hi"), Status::Forbidden);
+        let reply = chat.send(chat.get("/events?room=lobby"));
+        assert_eq!(reply.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn streams_of_a_newly_banned_user_end() {
+        let chat = TestChat::with_admin();
+        let mut events = chat.events("room=lobby&username=mallory");
+        admin(&chat, "/admin/ban", json!({ "username": "mallory" }));
+
+        assert!(events.find("banned", WAIT).is_some());
+        assert!(events.ended(WAIT));
+    }
+
+    #[test]
+    fn banning_takes_the_admin_token() {
+        let chat = TestChat::with_admin();
+        let body = json!({ "username": "mallory" });
+        let wrong = chat
+            .post_json("/admin/ban", &body)
+            .header(Header::new(ADMIN_TOKEN_HEADER, "guess"));
+        assert_eq!(chat.send(wrong).status, Status::Forbidden);
+        assert_eq!(
+            chat.send(chat.post_json("/admin/ban", &body)).status,
+            Status::Forbidden
+        );
+
+        // Without an admin_token the endpoints are disabled altogether
+        let chat = TestChat::new();
+        let request = as_admin(chat.post_json("/admin/ban", &body));
+        assert_eq!(chat.send(request).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn a_ban_takes_a_username_or_an_ip() {
+        let chat = TestChat::with_admin();
+        assert_eq!(
+            admin(&chat, "/admin/ban", json!({ "username": "  " })),
+            Status::UnprocessableEntity
+        );
+    }
+
+    #[test]
+    fn stats_sum_up_rooms_posts_and_bans() {
+        let chat = TestChat::with_admin();
+        let _alice = chat.events("room=lobby&username=alice");
+        let _bob = chat.events("room=lobby&username=bob");
+        let _carol = chat.events("room=games&username=carol");
+        chat.post_id("lobby", "alice", "one");
+        chat.post_id("games", "carol", "two");
+        admin(&chat, "/admin/ban", json!({ "username": "mallory" }));
+
+        let request = as_admin(chat.get("/admin/stats"));
+        let reply = chat.send(request);
+        assert_eq!(reply.status, Status::Ok);
+        let stats = reply.json();
+        assert_eq!(stats["rooms"], 2);
+        assert_eq!(stats["subscribers"], json!({ "games": 1, "lobby": 2 }));
+        assert_eq!(stats["messages_posted"], 2);
+        assert_eq!(stats["bans"], 1);
+        assert!(stats["uptime_seconds"].is_u64());
+    }
+
+    #[test]
+    fn stats_take_the_admin_token() {
+        let chat = TestChat::with_admin();
+        assert_eq!(
+            chat.send(chat.get("/admin/stats")).status,
+            Status::Forbidden
+        );
+        let wrong = chat
+            .get("/admin/stats")
+            .header(Header::new(ADMIN_TOKEN_HEADER, "guess"));
+        assert_eq!(chat.send(wrong).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn export_streams_a_room_as_ndjson_oldest_first() {
+        let chat = TestChat::with_admin();
+        let _events = chat.events("room=lobby");
+        for message in ["one", "two", "three"] {
+            chat.post_id("lobby", "alice", message);
+        }
+        let dm = json!({ "room": "lobby", "username": "alice", "to": "bob", "message": "psst" });
+        chat.send(chat.post_json("/message", &dm));
+
+        let request = as_admin(chat.get("/admin/export?room=Lobby"));
+        let reply = chat.send(request);
+        assert_eq!(reply.status, Status::Ok);
+        assert_eq!(reply.header("Content-Type"), Some("application/x-ndjson"));
+        let bodies: Vec<String> = reply
+            .body
+            .lines()
+            .map(|line| {
+                let msg: Value = rocket::serde::json::from_str(line).unwrap();
+                msg["message"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(bodies, ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn export_takes_the_admin_token_and_a_room_with_history() {
+        let chat = TestChat::with_admin();
+        assert_eq!(
+            chat.send(chat.get("/admin/export?room=lobby")).status,
+            Status::Forbidden
+        );
+        let request = as_admin(chat.get("/admin/export?room=nowhere"));
+        assert_eq!(chat.send(request).status, Status::NotFound);
+    }
+
+    #[test]
+    fn debug_channel_reports_the_configured_capacity_and_backlog() {
+        let chat = TestChat::configured(|figment| {
+            admin_token(figment).merge(("chat.channel_capacity", 16))
+        });
+        let _events = chat.events("room=lobby");
+        chat.post_id("lobby", "alice", "one");
+        chat.post_id("lobby", "alice", "two");
+
+        assert_eq!(
+            chat.send(chat.get("/debug/channel")).status,
+            Status::Forbidden
+        );
+        let request = as_admin(chat.get("/debug/channel"));
+        let stats = chat.send(request).json();
+        assert_eq!(stats["capacity"], 16);
+        assert_eq!(
+            stats["rooms"]["lobby"],
+            json!({ "queued": 2, "receivers": 1 })
+        );
+    }
+
+    #[test]
+    fn rooms_can_get_a_bigger_channel() {
+        let chat = TestChat::configured(|figment| {
+            admin_token(figment).merge(("chat.channel_capacity", 2))
+        });
+        let mut before = chat.events("room=lobby");
+        assert_eq!(
+            admin(
+                &chat,
+                "/admin/room-config",
+                json!({ "room": "Lobby", "capacity": 16 })
+            ),
+            Status::NoContent
+        );
+
+        // Streams on the old channel are asked to reconnect, then end
+        let notice = before.messages(1, WAIT).remove(0);
+        assert!(notice.system);
+        assert_eq!(
+            notice.message,
+            "this room was reconfigured, reconnect to keep receiving its messages"
+        );
+        assert!(before.ended(WAIT));
+
+        // Reconnected, five posts no longer make it lag
+        let mut events = chat.events("room=lobby");
+        for i in 0..5 {
+            chat.post_id("lobby", "alice", &format!("message {}", i));
+        }
+        let events = events.events(6, WAIT);
+        assert!(events.iter().all(|event| event.name() != "lagged"));
+        let bodies: Vec<_> = events
+            .iter()
+            .filter_map(|event| event.message())
+            .map(|msg| msg.message)
+            .collect();
+        assert_eq!(bodies.len(), 5);
+        assert_eq!(bodies[4], "message 4");
+    }
+
+    #[test]
+    fn room_capacities_are_bounded() {
+        let chat = TestChat::with_admin();
+        for capacity in [0, super::MAX_ROOM_CAPACITY + 1] {
+            let body = json!({ "room": "lobby", "capacity": capacity });
+            assert_eq!(
+                admin(&chat, "/admin/room-config", body),
+                Status::UnprocessableEntity
+            );
+        }
+        let body = json!({ "room": "lobby", "capacity": null });
+        assert_eq!(admin(&chat, "/admin/room-config", body), Status::NoContent);
+    }
+
+    #[test]
+    fn closing_a_room_ends_its_streams_and_empties_it() {
+        let chat = TestChat::with_admin();
+        let mut alice = chat.events("room=lobby&username=alice");
+        let mut games = chat.events("room=games&username=bob");
+        assert_eq!(
+            admin(&chat, "/admin/close-room", json!({ "room": "Lobby" })),
+            Status::NoContent
+        );
+
+        let closed = alice.find("room_closed", WAIT).unwrap().message().unwrap();
+        assert_eq!(closed.room, "lobby");
+        assert!(alice.ended(WAIT));
+        assert_eq!(chat.send(chat.get("/users?room=lobby")).json(), json!([]));
+
+        // Other rooms carry on, and the room can be joined again
+        assert!(!games.ended(Duration::from_millis(300)));
+        let _again = chat.events("room=lobby&username=alice");
+        assert_eq!(chat.post("lobby", "alice", "back"), Status::Ok);
+    }
+
+    #[test]
+    fn users_lists_each_stream_with_its_user_agent() {
+        let chat = TestChat::with_admin();
+        let agent = format!("Bot/1.0\r\n{}", "x".repeat(300));
+        let request = chat
+            .get("/events?room=lobby&username=alice")
+            .header(Header::new("User-Agent", agent));
+        let alice = chat.stream(request);
+        let _anonymous = chat.events("room=lobby");
+        let users = || {
+            let request = as_admin(chat.get("/admin/users?room=lobby"));
+            chat.send(request).json()
+        };
+
+        let listed = users();
+        let streams = listed.as_array().unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0]["username"], "alice");
+        let user_agent = streams[0]["user_agent"].as_str().unwrap();
+        assert!(user_agent.starts_with("Bot/1.0xxx"));
+        assert_eq!(user_agent.chars().count(), MAX_USER_AGENT_LEN);
+        let connected_at = streams[0]["connected_at"].as_u64().unwrap();
+        assert!(connected_at.abs_diff(now_millis()) < 60_000);
+
+        chat.block_on(async { drop(alice) });
+        assert_eq!(users(), json!([]));
+        assert_eq!(
+            chat.send(chat.get("/admin/users?room=lobby")).status,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn shutdown_tells_every_room_and_stops_the_server() {
+        let chat = TestChat::with_admin();
+        let mut lobby = chat.events("room=lobby");
+        let mut games = chat.events("room=games");
+        assert_eq!(
+            chat.send(chat.post_form("/admin/shutdown", "")).status,
+            Status::Forbidden
+        );
+        let request = as_admin(chat.post_form("/admin/shutdown", ""));
+        assert_eq!(chat.send(request).status, Status::Accepted);
+
+        for events in [&mut lobby, &mut games] {
+            let notice = events.messages(1, WAIT).remove(0);
+            assert_eq!(notice.message, "the server is shutting down");
+            assert!(events.find("shutdown", WAIT).is_some());
+            assert!(events.ended(WAIT));
+        }
+        let stopped = chat.block_on(async {
+            rocket::tokio::time::timeout(WAIT, chat.client().rocket().shutdown()).await
+        });
+        assert!(stopped.is_ok());
+        assert_eq!(
+            chat.post("lobby", "alice", "hi"),
+            Status::ServiceUnavailable
+        );
+    }
+}
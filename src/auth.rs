@@ -0,0 +1,323 @@
+use crate::config::ChatConfig;
+use crate::error::ApiError;
+use crate::message::{max_graphemes, not_reserved, safe_name, MAX_USERNAME_LEN};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::form::{self, Form};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+
+// Header and cookie a client presents its session token in
+pub const TOKEN_HEADER: &str = "X-Session-Token";
+pub const TOKEN_COOKIE: &str = "session_token";
+
+// Private (encrypted and signed) cookie holding the username a browser signed in as through /session
+pub const USER_COOKIE: &str = "username";
+
+// Header integrations present the configured api_key in
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+// Whether the secret a client presented (a token or key) is `expected`
+// Compared in constant time, so how long the comparison takes doesn't tell how much of a guess was right
+// Only its length can leak, the session tokens /register hands out all have the same one
+pub fn secret_matches(given: Option<&str>, expected: &str) -> bool {
+    given.is_some_and(|given| bool::from(given.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+// Usernames claimed through /register, username -> session token
+// Reserved usernames can only be posted as by whoever holds the token, unreserved ones stay open to anyone
+#[derive(Default)]
+pub struct Reservations(Mutex<HashMap<String, String>>);
+
+impl Reservations {
+    // Reserve `username`, returning its new token, or None if someone already holds it
+    pub fn reserve(&self, username: &str) -> Option<String> {
+        let mut reserved = self.0.lock().unwrap();
+        if reserved.contains_key(username) {
+            return None;
+        }
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        reserved.insert(username.to_string(), token.clone());
+
+        Some(token)
+    }
+
+    // Whether someone registered `username`
+    pub fn is_reserved(&self, username: &str) -> bool {
+        self.0.lock().unwrap().contains_key(username)
+    }
+
+    // The username `token` was handed out for, if any
+    pub fn holder(&self, token: &str) -> Option<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, reserved)| secret_matches(Some(token), reserved))
+            .map(|(username, _)| username.clone())
+    }
+
+    // Whether a client presenting `token` may post as `username`
+    pub fn authorize(&self, username: &str, token: Option<&str>) -> bool {
+        match self.0.lock().unwrap().get(username) {
+            Some(reserved) => secret_matches(token, reserved),
+            None => true,
+        }
+    }
+}
+
+// Request guard picking up the session token from the X-Session-Token header, or else the session_token cookie
+// Never fails, a request without a token just can't post as a reserved username
+pub struct SessionToken(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = req
+            .headers()
+            .get_one(TOKEN_HEADER)
+            .map(String::from)
+            .or_else(|| {
+                req.cookies()
+                    .get(TOKEN_COOKIE)
+                    .map(|c| c.value().to_string())
+            });
+
+        Outcome::Success(SessionToken(token))
+    }
+}
+
+// Request guard enforcing the api_key on /message
+// -- Open -> no api_key is configured, anyone may post as before
+// -- Verified -> the X-API-Key header matched, the poster may use any username, reserved or not
+// Fails with 401 Unauthorized when an api_key is configured and the header is missing or doesn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiKey {
+    Open,
+    Verified,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match req.guard::<&State<ChatConfig>>().await {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        match &config.api_key {
+            None => Outcome::Success(ApiKey::Open),
+            Some(key) if secret_matches(req.headers().get_one(API_KEY_HEADER), key) => {
+                Outcome::Success(ApiKey::Verified)
+            }
+            Some(_) => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Request guard picking up the username a browser signed in as through /session
+// Forwards when there is no such cookie (or it was tampered with), take it as an Option to fall back on a username field
+pub struct User(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.cookies().get_private(USER_COOKIE) {
+            Some(cookie) => Outcome::Success(User(cookie.value().to_string())),
+            None => Outcome::Forward(Status::Unauthorized),
+        }
+    }
+}
+
+// Form data accepted by /session
+#[derive(Debug, FromForm)]
+pub struct SessionForm {
+    #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    #[field(validate = not_reserved())]
+    pub username: String,
+}
+
+// Endpoint to Sign In as a Username
+// Remembers the username in a private cookie, so /message and /events use it instead of whatever username the browser sends
+// -- Signing in as a reserved username takes its session token, and posting as it still does
+// -- Answers 204, or 403 for a reserved username without its token
+#[post("/session", data = "<form>")]
+pub fn session(
+    form: Result<Form<SessionForm>, form::Errors<'_>>,
+    token: SessionToken,
+    reservations: &State<Reservations>,
+    cookies: &CookieJar<'_>,
+) -> Result<Status, ApiError> {
+    let username = form.map_err(ApiError::from_form)?.into_inner().username;
+    if !reservations.authorize(&username, token.0.as_deref()) {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            format!("username {} is reserved", username),
+        ));
+    }
+
+    cookies.add_private(Cookie::new(USER_COOKIE, username));
+    Ok(Status::NoContent)
+}
+
+// Form data accepted by /register
+#[derive(Debug, FromForm)]
+pub struct Registration {
+    #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    #[field(validate = not_reserved())]
+    pub username: String,
+}
+
+// Body returned from /register, the token has to accompany every post as the username
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RegisterResponse {
+    pub username: String,
+    pub token: String,
+}
+
+// Endpoint to Reserve a Username
+// Hands out a session token (also set as a cookie for browsers) or answers 409 when the name is already taken
+#[post("/register", data = "<form>")]
+pub fn register(
+    form: Result<Form<Registration>, form::Errors<'_>>,
+    reservations: &State<Reservations>,
+    cookies: &CookieJar<'_>,
+) -> Result<Json<RegisterResponse>, ApiError> {
+    let username = form.map_err(ApiError::from_form)?.into_inner().username;
+    let token = reservations.reserve(&username).ok_or_else(|| {
+        ApiError::new(
+            Status::Conflict,
+            format!("username {} is already reserved", username),
+        )
+    })?;
+
+    cookies.add(Cookie::new(TOKEN_COOKIE, token.clone()));
+    Ok(Json(RegisterResponse { username, token }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageKind;
+    use crate::testing::{TestChat, WAIT};
+    use rocket::http::Header;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn secrets_only_match_exactly() {
+        assert!(secret_matches(Some("s3cret"), "s3cret"));
+        assert!(!secret_matches(Some("s3cre"), "s3cret"));
+        assert!(!secret_matches(Some("s3cret!"), "s3cret"));
+        assert!(!secret_matches(Some("S3cret"), "s3cret"));
+        assert!(!secret_matches(None, "s3cret"));
+    }
+
+    fn post_with_key(chat: &TestChat, username: &str, key: Option<&str>) -> Status {
+        let body = json!({ "room": "lobby", "username": username, "message": "alert" });
+        let mut request = chat.post_json("/message", &body);
+        if let Some(key) = key {
+            request = request.header(Header::new(API_KEY_HEADER, key.to_string()));
+        }
+        chat.send(request).status
+    }
+
+    #[test]
+    fn posts_take_the_configured_api_key() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.api_key", "bot-key")));
+        let _events = chat.events("room=lobby");
+        assert_eq!(post_with_key(&chat, "bot", Some("bot-key")), Status::Ok);
+        assert_eq!(
+            post_with_key(&chat, "bot", Some("guess")),
+            Status::Unauthorized
+        );
+        assert_eq!(post_with_key(&chat, "bot", None), Status::Unauthorized);
+    }
+
+    #[test]
+    fn the_api_key_bypasses_reservations() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.api_key", "bot-key")));
+        let _events = chat.events("room=lobby");
+        let reservations = chat.client().rocket().state::<Reservations>().unwrap();
+        assert!(reservations.reserve("alice").is_some());
+        assert_eq!(post_with_key(&chat, "alice", Some("bot-key")), Status::Ok);
+    }
+
+    #[test]
+    fn posts_are_open_without_an_api_key() {
+        let chat = TestChat::new();
+        let _events = chat.events("room=lobby");
+        assert_eq!(post_with_key(&chat, "bot", None), Status::Ok);
+        assert_eq!(post_with_key(&chat, "bot", Some("anything")), Status::Ok);
+    }
+
+    #[test]
+    fn sessions_sign_posts_and_streams_in_as_their_user() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.post_form("/session", "username=alice"));
+        assert_eq!(reply.status, Status::NoContent);
+        let cookie = reply.header("Set-Cookie").unwrap();
+        assert!(cookie.starts_with("username=") && !cookie.contains("alice"));
+
+        // The tracked client sends the cookie back from now on
+        let mut events = chat.events("room=lobby&username=mallory");
+        let users = chat.send(chat.get("/users?room=lobby")).json();
+        assert_eq!(users, json!(["alice"]));
+        assert_eq!(chat.post("lobby", "mallory", "hi"), Status::Ok);
+
+        // After the announcement of alice joining
+        let messages = events.messages(2, WAIT);
+        assert_eq!(messages[0].kind, MessageKind::System);
+        assert_eq!(messages[1].username, "alice");
+    }
+
+    #[test]
+    fn forged_session_cookies_are_ignored() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let body = json!({ "room": "lobby", "username": "bob", "message": "hi" });
+        let request = chat
+            .post_json("/message", &body)
+            .cookie(Cookie::new(USER_COOKIE, "alice"));
+        assert_eq!(chat.send(request).status, Status::Ok);
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages[0].username, "bob");
+    }
+
+    #[test]
+    fn sessions_take_a_valid_unreserved_username() {
+        let chat = TestChat::new();
+        for form in ["username=", "username=a%2Fb", "username=system"] {
+            let reply = chat.send(chat.post_form("/session", form));
+            assert_eq!(reply.status, Status::UnprocessableEntity, "{}", form);
+        }
+
+        let reservations = chat.client().rocket().state::<Reservations>().unwrap();
+        let token = reservations.reserve("alice").unwrap();
+        let reply = chat.send(chat.post_form("/session", "username=alice"));
+        assert_eq!(reply.status, Status::Forbidden);
+        let request = chat
+            .post_form("/session", "username=alice")
+            .header(Header::new(TOKEN_HEADER, token));
+        assert_eq!(chat.send(request).status, Status::NoContent);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// How often open /events and /ws connections check whether their user has been banned since they connected
+pub const BAN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Usernames and IPs banned by a moderator through /admin/ban
+// -- from every room by the admin, banned clients get a 403 from /message, /events and friends, and their open connections are closed
+// -- from a single room by one of its moderators, only what's about that room is turned down and only its connections are closed
+#[derive(Default)]
+pub struct Bans {
+    usernames: Mutex<HashSet<String>>,
+    ips: Mutex<HashSet<IpAddr>>,
+    rooms: Mutex<HashMap<String, RoomBans>>,
+}
+
+// Who is banned from one room
+#[derive(Default)]
+struct RoomBans {
+    usernames: HashSet<String>,
+    ips: HashSet<IpAddr>,
+}
+
+impl Bans {
+    // Ban `username`, returns false if it already was
+    pub fn ban_username(&self, username: &str) -> bool {
+        self.usernames.lock().unwrap().insert(username.to_string())
+    }
+
+    // Ban `ip`, returns false if it already was
+    pub fn ban_ip(&self, ip: IpAddr) -> bool {
+        self.ips.lock().unwrap().insert(ip)
+    }
+
+    // Ban `username` from `room` only, returns false if it already was
+    pub fn ban_username_from(&self, room: &str, username: &str) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        let bans = rooms.entry(room.to_string()).or_default();
+        bans.usernames.insert(username.to_string())
+    }
+
+    // Ban `ip` from `room` only, returns false if it already was
+    pub fn ban_ip_from(&self, room: &str, ip: IpAddr) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.to_string()).or_default().ips.insert(ip)
+    }
+
+    // Number of usernames and IPs banned, from every room or from one
+    pub fn len(&self) -> usize {
+        let from_rooms: usize = self
+            .rooms
+            .lock()
+            .unwrap()
+            .values()
+            .map(|bans| bans.usernames.len() + bans.ips.len())
+            .sum();
+        self.usernames.lock().unwrap().len() + self.ips.lock().unwrap().len() + from_rooms
+    }
+
+    // Whether a client connecting from `ip` as `username` is banned from every room, by either
+    pub fn is_banned(&self, username: Option<&str>, ip: Option<IpAddr>) -> bool {
+        username.is_some_and(|name| self.usernames.lock().unwrap().contains(name))
+            || ip.is_some_and(|ip| self.ips.lock().unwrap().contains(&ip))
+    }
+
+    // Whether a client connecting from `ip` as `username` is banned from `room`, from every room when None is given
+    pub fn is_banned_from(
+        &self,
+        room: Option<&str>,
+        username: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> bool {
+        if self.is_banned(username, ip) {
+            return true;
+        }
+
+        let rooms = self.rooms.lock().unwrap();
+        room.and_then(|room| rooms.get(room)).is_some_and(|bans| {
+            username.is_some_and(|name| bans.usernames.contains(name))
+                || ip.is_some_and(|ip| bans.ips.contains(&ip))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn room_bans_only_cover_their_room() {
+        let bans = Bans::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        assert!(bans.ban_username_from("lobby", "mallory"));
+        assert!(!bans.ban_username_from("lobby", "mallory"));
+        assert!(bans.ban_ip_from("games", ip));
+
+        assert!(bans.is_banned_from(Some("lobby"), Some("mallory"), None));
+        assert!(!bans.is_banned_from(Some("games"), Some("mallory"), None));
+        assert!(!bans.is_banned_from(None, Some("mallory"), None));
+        assert!(!bans.is_banned(Some("mallory"), None));
+        assert!(bans.is_banned_from(Some("games"), Some("alice"), Some(ip)));
+        assert!(!bans.is_banned_from(Some("lobby"), Some("alice"), Some(ip)));
+        assert_eq!(bans.len(), 2);
+
+        // Bans from every room cover each of them
+        bans.ban_username("eve");
+        assert!(bans.is_banned_from(Some("lobby"), Some("eve"), None));
+        assert!(bans.is_banned_from(None, Some("eve"), None));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::admin::Moderator;
+use crate::auth::{ApiKey, SessionToken, User};
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::guards::ClientIp;
+use crate::message::{MessageForm, PostResponse};
+use crate::rate_limit::{RateLimited, RateLimiter};
+use rocket::http::Status;
+use rocket::serde::json::{self, Json};
+use rocket::serde::Serialize;
+use rocket::State;
+
+// Outcome of one message of a batch, in the same position as the message in the request
+// -- Posted -> accepted, i.e {"status":"posted","id":12}, with the same fields /message returns
+// -- Failed -> rejected, i.e {"status":"failed","error":"message can't be empty","code":422}
+//    `code` is the status /message would have answered that message with
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde", tag = "status", rename_all = "lowercase")]
+pub enum BatchResult {
+    Posted(PostResponse),
+    Failed { error: String, code: u16 },
+}
+
+impl From<Result<PostResponse, ApiError>> for BatchResult {
+    fn from(result: Result<PostResponse, ApiError>) -> BatchResult {
+        match result {
+            Ok(response) => BatchResult::Posted(response),
+            Err(e) => BatchResult::Failed {
+                error: e.message,
+                code: e.status.code,
+            },
+        }
+    }
+}
+
+// Endpoint to Send Several Messages at Once
+// Takes a JSON array of messages shaped like the body of /message, i.e [{"room":"lobby","username":"alice","message":"hi"}, ...]
+// -- Every message is checked and published on its own, a bad one only fails its own entry of the response
+// -- Each message past the first takes another token from the sender's rate limit, the ones the limit runs out for fail with a 429
+// -- The whole batch gets a 422 when it is empty or holds more than max_batch_size messages
+#[post("/messages", format = "json", data = "<json>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn post_batch(
+    _limit: RateLimited,
+    key: ApiKey,
+    moderator: Option<Moderator>,
+    json: Result<Json<Vec<MessageForm>>, json::Error<'_>>,
+    user: Option<User>,
+    token: SessionToken,
+    ip: ClientIp,
+    limiter: &State<RateLimiter>,
+    chat: Chat<'_>,
+) -> Result<Json<Vec<BatchResult>>, ApiError> {
+    let ClientIp(ip) = ip;
+    let forms = json
+        .map_err(|e| ApiError::from_json(e, chat.config.max_body_size))?
+        .into_inner();
+    if forms.is_empty() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "a batch needs at least one message",
+        ));
+    }
+    if forms.len() > chat.config.max_batch_size {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            format!(
+                "a batch holds at most {} messages, got {}",
+                chat.config.max_batch_size,
+                forms.len()
+            ),
+        ));
+    }
+
+    let mut results = Vec::with_capacity(forms.len());
+    for (i, mut form) in forms.into_iter().enumerate() {
+        // The RateLimited guard already took the first message's token
+        if i > 0 && ip.is_some_and(|ip| !limiter.check(ip)) {
+            results.push(BatchResult::from(Err(ApiError::new(
+                Status::TooManyRequests,
+                "rate limit exceeded",
+            ))));
+            continue;
+        }
+        if let Some(User(username)) = &user {
+            form.username = username.clone();
+        }
+
+        let result = match form.validate() {
+            Ok(()) => chat
+                .submit(form, token.0.as_deref(), key, moderator.as_ref(), ip)
+                .await
+                .map(PostResponse::from),
+            Err(e) => Err(ApiError::new(Status::UnprocessableEntity, e)),
+        };
+        results.push(BatchResult::from(result));
+    }
+
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{unlimited, TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::{json, Value};
+
+    fn msg(username: &str, message: &str) -> Value {
+        json!({ "room": "lobby", "username": username, "message": message })
+    }
+
+    #[test]
+    fn every_message_of_a_valid_batch_is_posted() {
+        let chat = TestChat::configured(unlimited);
+        let mut events = chat.events("room=lobby");
+        let batch = json!([msg("alice", "one"), msg("bob", "two")]);
+
+        let reply = chat.send(chat.post_json("/messages", &batch));
+        assert_eq!(reply.status, Status::Ok);
+        let results = reply.json();
+        assert_eq!(results[0]["status"], "posted");
+        assert_eq!(results[1]["status"], "posted");
+        let bodies: Vec<_> = events
+            .messages(2, WAIT)
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect();
+        assert_eq!(bodies, ["one", "two"]);
+    }
+
+    #[test]
+    fn a_bad_message_only_fails_its_own_entry() {
+        let chat = TestChat::configured(unlimited);
+        let _events = chat.events("room=lobby");
+        let batch = json!([
+            msg("alice", "one"),
+            msg("alice", "   "),
+            msg("a/b", "two"),
+            msg("bob", "three")
+        ]);
+
+        let results = chat.send(chat.post_json("/messages", &batch)).json();
+        let statuses: Vec<_> = results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| (result["status"].as_str().unwrap(), result["code"].as_u64()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("posted", None),
+                ("failed", Some(422)),
+                ("failed", Some(422)),
+                ("posted", None)
+            ]
+        );
+        assert!(results[1]["error"].as_str().is_some_and(|e| !e.is_empty()));
+    }
+
+    #[test]
+    fn batches_are_capped_at_max_batch_size() {
+        let chat =
+            TestChat::configured(|figment| unlimited(figment).merge(("chat.max_batch_size", 2)));
+        let batch = json!([
+            msg("alice", "one"),
+            msg("alice", "two"),
+            msg("alice", "three")
+        ]);
+        let reply = chat.send(chat.post_json("/messages", &batch));
+        assert_eq!(reply.status, Status::UnprocessableEntity);
+        assert_eq!(
+            reply.json()["error"],
+            "a batch holds at most 2 messages, got 3"
+        );
+
+        let reply = chat.send(chat.post_json("/messages", &json!([])));
+        assert_eq!(reply.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn messages_past_the_rate_limit_fail_with_429() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.rate_limit_burst", 2)));
+        let _events = chat.events("room=lobby");
+        let batch = json!([
+            msg("alice", "one"),
+            msg("alice", "two"),
+            msg("alice", "three")
+        ]);
+        let results = chat.send(chat.post_json("/messages", &batch)).json();
+        assert_eq!(results[1]["status"], "posted");
+        assert_eq!(results[2]["code"], 429);
+    }
+}
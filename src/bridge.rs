@@ -0,0 +1,285 @@
+use crate::channels::Channels;
+use crate::config::ChatConfig;
+use crate::message::{MessageForm, MessageKind};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::serde::json::{self, Value};
+use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Orbit, Rocket, Shutdown};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Longest line, in bytes, a bridge client may send, longer ones close its connection
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+// Fairing opening a plain TCP, line oriented, way into the chat for scripts and legacy tools (i.e `nc localhost 9000`)
+// On liftoff, when `tcp_bridge_port` is configured, listens on that port of Rocket's address until the server shuts down
+// -- every line a client sends, `room|username|message`, is posted like a JSON body to /message would be
+//    a rejected line is answered with `error|<reason>`
+// -- every public chat message and announcement, of every room, is written to the client as a `room|username|message` line
+pub struct TcpBridge;
+
+#[rocket::async_trait]
+impl Fairing for TcpBridge {
+    fn info(&self) -> Info {
+        Info {
+            name: "TCP Bridge",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (Some(config), Some(queue)) =
+            (rocket.state::<ChatConfig>(), rocket.state::<Channels>())
+        else {
+            return;
+        };
+        let Some(port) = config.tcp_bridge_port else {
+            return;
+        };
+
+        let address = rocket.config().address;
+        let listener = match TcpListener::bind((address, port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to open the tcp bridge on port {}: {}", port, e);
+                return;
+            }
+        };
+        info!("tcp bridge listening on {}:{}", address, port);
+
+        // Lines are posted to our own HTTP server, so they go through exactly the checks (rate limits, bans, filter...) of /message
+        // The ip header tells it which client a line came from, like a reverse proxy would
+        let server = Loopback {
+            addr: SocketAddr::new(loopback(address), rocket.config().port),
+            ip_header: rocket.config().ip_header.as_ref().map(|h| h.to_string()),
+        };
+        let queue = queue.clone();
+        let mut end = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            loop {
+                select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, peer)) => {
+                            rocket::tokio::spawn(serve(stream, peer.ip(), server.clone(), queue.clone(), end.clone()));
+                        }
+                        Err(e) => warn!("failed to accept a tcp bridge connection: {}", e),
+                    },
+                    _ = &mut end => break,
+                }
+            }
+        });
+    }
+}
+
+// Talk to one bridge client until it disconnects or the server shuts down
+async fn serve(
+    stream: TcpStream,
+    peer: IpAddr,
+    server: Loopback,
+    queue: Channels,
+    mut end: Shutdown,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut rx = queue.subscribe(None, None);
+    let mut line = Vec::new();
+
+    loop {
+        // A line from the client, cut off at MAX_LINE_LEN
+        // Bytes read before another branch wins stay in `line`, the next pass goes on from there
+        let mut limited = (&mut read).take(MAX_LINE_LEN);
+        select! {
+            read = limited.read_until(b'\n', &mut line) => {
+                match read {
+                    Ok(0) => break,
+                    Ok(_) if line.last() != Some(&b'\n') && line.len() as u64 >= MAX_LINE_LEN => {
+                        let _ = write.write_all(b"error|line is too long\n").await;
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+
+                let text = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+                line.clear();
+                if text.trim().is_empty() {
+                    continue;
+                }
+                if let Err(reason) = post_line(&server, peer, &text).await {
+                    let reply = format!("error|{}\n", one_line(&reason));
+                    if write.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            },
+
+            // A message broadcast by anyone
+            msg = rx.recv() => match msg {
+                Ok(msg) if matches!(msg.kind, MessageKind::Chat | MessageKind::System) => {
+                    let out = format!("{}|{}|{}\n", msg.room, msg.username, one_line(&msg.message));
+                    if write.write_all(out.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+
+            _ = &mut end => break,
+        }
+    }
+}
+
+// Post a `room|username|message` line, the message itself may contain `|`
+// Fails with the reason the server gave for rejecting it
+async fn post_line(server: &Loopback, peer: IpAddr, line: &str) -> Result<(), String> {
+    let mut fields = line.splitn(3, '|');
+    let (Some(room), Some(username), Some(message)) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Err("expected room|username|message".into());
+    };
+
+    let form = MessageForm {
+        room: room.to_string(),
+        username: username.to_string(),
+        message: message.to_string(),
+        to: None,
+        markdown: false,
+        client_msg_id: None,
+        reply_to: None,
+        attachment_url: None,
+    };
+    server.post(peer, &form).await
+}
+
+// Keeps a body from breaking the line protocol
+fn one_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+// Where Rocket serves HTTP, reachable from this process
+#[derive(Clone)]
+struct Loopback {
+    addr: SocketAddr,
+    ip_header: Option<String>,
+}
+
+impl Loopback {
+    // POST `form` as JSON to /message on behalf of `peer`, one connection per request
+    async fn post(&self, peer: IpAddr, form: &MessageForm) -> Result<(), String> {
+        let body = json::to_string(form).expect("forms serialize to json");
+        let forwarded = match &self.ip_header {
+            Some(header) => format!("{}: {}\r\n", header, peer),
+            None => String::new(),
+        };
+        let request = format!(
+            "POST /message HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            self.addr,
+            body.len(),
+            forwarded,
+            body
+        );
+
+        let failed = |e: std::io::Error| {
+            error!("tcp bridge failed to reach {}: {}", self.addr, e);
+            "server unavailable".to_string()
+        };
+        let mut stream = TcpStream::connect(self.addr).await.map_err(failed)?;
+        stream.write_all(request.as_bytes()).await.map_err(failed)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.map_err(failed)?;
+
+        // i.e "HTTP/1.1 422 Unprocessable Entity", the body follows the headers
+        let status = response.split(' ').nth(1).unwrap_or_default();
+        if status.starts_with('2') {
+            return Ok(());
+        }
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        let reason = json::from_str::<Value>(body)
+            .ok()
+            .and_then(|body| body.get("error")?.as_str().map(String::from))
+            .unwrap_or_else(|| format!("rejected with status {}", status));
+        Err(reason)
+    }
+}
+
+// An address of this host to reach a server bound to `address`, the loopback one when it's bound to every interface
+fn loopback(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(v4) if v4.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(v6) if v6.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        address => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{connect, free_port, TestServer, WAIT};
+    use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use rocket::tokio::net::TcpStream;
+    use rocket::tokio::time::timeout;
+
+    // Read from `stream` until what was read contains `needle`
+    async fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+        let mut read = Vec::new();
+        timeout(WAIT, async {
+            let mut buf = [0; 1024];
+            while !String::from_utf8_lossy(&read).contains(needle) {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "the connection closed");
+                read.extend_from_slice(&buf[..n]);
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{:?} never came", needle));
+        String::from_utf8_lossy(&read).into_owned()
+    }
+
+    // The bridge posts through the HTTP server, so this launches the app on real sockets, unlike TestChat
+    #[rocket::async_test]
+    async fn lines_sent_to_the_bridge_reach_event_streams() {
+        let bridge = free_port();
+        let server =
+            TestServer::launch(|figment| figment.merge(("chat.tcp_bridge_port", bridge))).await;
+
+        let mut events = server.connect().await;
+        events
+            .write_all(b"GET /events?room=lobby HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        read_until(&mut events, "retry:").await;
+
+        let mut client = BufReader::new(connect(bridge).await);
+        client
+            .get_mut()
+            .write_all(b"lobby|alice|hi from nc\nlobby|a/b|hi\n")
+            .await
+            .unwrap();
+        let received = read_until(&mut events, "hi from nc").await;
+        assert!(received.contains("\"username\":\"alice\""), "{}", received);
+
+        // The bridge client gets the broadcast back as a line, and the refused line's reason
+        let mut lines = Vec::new();
+        for _ in 0..2 {
+            let mut line = String::new();
+            timeout(WAIT, client.read_line(&mut line))
+                .await
+                .unwrap()
+                .unwrap();
+            lines.push(line);
+        }
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "error|username must be made of letters, digits, '_' and '-' only\n",
+                "lobby|alice|hi from nc\n",
+            ]
+        );
+
+        drop(events);
+        server.stop().await;
+    }
+}
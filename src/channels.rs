@@ -0,0 +1,588 @@
+use crate::message::{Message, MessageKind};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::select;
+use rocket::tokio::spawn;
+use rocket::tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use rocket::tokio::sync::broadcast::{channel, Receiver, Sender};
+use rocket::tokio::sync::mpsc::{self, error::TrySendError};
+use rocket::tokio::task::JoinHandle;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// How long, and how many times, a best-effort-retry post waits for a full channel to drain
+pub const OVERFLOW_RETRY_DELAY: Duration = Duration::from_millis(20);
+pub const OVERFLOW_RETRIES: usize = 5;
+
+// What happens to a post when a channel it goes to is full, its slowest subscriber not having read the last channel_capacity messages
+// -- Drop -> send anyway, the slowest subscribers skip the oldest messages and get a "lagged" event
+// -- Reject -> answer 503 so the client tries again later, nobody skips anything
+// -- BestEffortRetry -> wait a little (OVERFLOW_RETRIES times OVERFLOW_RETRY_DELAY) for the subscribers to catch up, then send anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "kebab-case")]
+pub enum Overflow {
+    #[default]
+    Drop,
+    Reject,
+    BestEffortRetry,
+}
+
+// Error returned by Channels::send when nobody received the message
+#[derive(Debug)]
+pub struct NoSubscribers;
+
+// How full a broadcast channel runs
+// -- queued -> messages its slowest subscriber has yet to read, reaching the capacity makes subscribers lag
+// -- receivers -> subscriptions reading from it
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ChannelStats {
+    pub queued: usize,
+    pub receivers: usize,
+}
+
+impl ChannelStats {
+    fn of(tx: &Sender<Message>) -> ChannelStats {
+        ChannelStats {
+            queued: tx.len(),
+            receivers: tx.receiver_count(),
+        }
+    }
+}
+
+// Broadcast channels carrying Messages to subscribers, so a busy room can't make subscribers of quiet rooms lag
+// -- one channel per room, created on first use, carrying the room's public messages
+//    with the capacity a moderator set for the room through /admin/room-config, the default one otherwise
+// -- one channel per username, created on first use, carrying the direct messages sent to or by that user
+// -- one channel carrying the public messages of every room, for subscribers that didn't pick a room
+// The room channels and the every room one each come with a priority channel for announcements (see MessageKind::is_priority)
+// -- subscriptions always read it first, and chat traffic making them lag can't make them skip what it carries
+// Cloning hands out another handle to the same channels, so streams can hold on to it after the request ends
+#[derive(Clone)]
+pub struct Channels(Arc<Inner>);
+
+struct Inner {
+    capacity: usize,
+    room_capacities: Mutex<HashMap<String, usize>>,
+    all: Sender<Message>,
+    all_priority: Sender<Message>,
+    rooms: Mutex<HashMap<String, Sender<Message>>>,
+    priority: Mutex<HashMap<String, Sender<Message>>>,
+    users: Mutex<HashMap<String, Sender<Message>>>,
+}
+
+impl Channels {
+    // Every channel retains up to `capacity` messages for subscribers that fall behind
+    pub fn new(capacity: usize) -> Channels {
+        let capacity = capacity.max(1);
+        Channels(Arc::new(Inner {
+            capacity,
+            room_capacities: Mutex::default(),
+            all: channel(capacity).0,
+            all_priority: channel(capacity).0,
+            rooms: Mutex::default(),
+            priority: Mutex::default(),
+            users: Mutex::default(),
+        }))
+    }
+
+    // Send `msg` to every subscriber who should get it, returning how many did
+    // -- a public message goes to its room's channel and the every room channel, their priority ones for announcements
+    // -- a direct message goes to its sender's and recipient's channels, a mention only to its recipient's
+    // Fails, like a single broadcast channel would, when there was nobody to receive it
+    pub fn send(&self, msg: Message) -> Result<usize, NoSubscribers> {
+        let reached = match &msg.to {
+            None if msg.kind.is_priority() => {
+                self.0.all_priority.send(msg.clone()).unwrap_or(0)
+                    + send_to(&self.0.priority, &msg.room, &msg)
+            }
+            None => {
+                self.0.all.send(msg.clone()).unwrap_or(0) + send_to(&self.0.rooms, &msg.room, &msg)
+            }
+            Some(to) if *to == msg.username || msg.kind == MessageKind::Mention => {
+                send_to(&self.0.users, to, &msg)
+            }
+            Some(to) => {
+                send_to(&self.0.users, to, &msg) + send_to(&self.0.users, &msg.username, &msg)
+            }
+        };
+
+        if reached == 0 {
+            Err(NoSubscribers)
+        } else {
+            Ok(reached)
+        }
+    }
+
+    // Whether a channel a message posted to `room` by `username` (to `to` for a direct message) would go to is full
+    // Sending it now would make the slowest subscriber of that channel skip a message
+    pub fn is_full(&self, room: &str, username: &str, to: Option<&str>) -> bool {
+        match to {
+            None => {
+                self.0.all.len() >= self.0.capacity
+                    || backlog_of(&self.0.rooms, room) >= self.room_capacity(room)
+            }
+            Some(to) => {
+                backlog_of(&self.0.users, to).max(backlog_of(&self.0.users, username))
+                    >= self.0.capacity
+            }
+        }
+    }
+
+    // Subscribe to the public messages of `room` (every room when None)
+    // and, when `username` is given, to the direct messages sent to or by that user
+    pub fn subscribe(&self, room: Option<&str>, username: Option<&str>) -> Subscription {
+        let (messages, priority) = match room {
+            Some(room) => {
+                let capacity = self.room_capacity(room);
+                (
+                    subscribe_to(&self.0.rooms, room, capacity),
+                    subscribe_to(&self.0.priority, room, capacity),
+                )
+            }
+            None => (self.0.all.subscribe(), self.0.all_priority.subscribe()),
+        };
+        let direct =
+            username.map(|username| subscribe_to(&self.0.users, username, self.0.capacity));
+
+        Subscription {
+            channels: self.clone(),
+            room: room.map(String::from),
+            username: username.map(String::from),
+            messages,
+            priority,
+            direct,
+            lagged: None,
+        }
+    }
+
+    // The capacity every channel was created with, unless it's the channel of a room with its own
+    pub fn capacity(&self) -> usize {
+        self.0.capacity
+    }
+
+    // The capacity the channel of `room` is created with
+    pub fn room_capacity(&self, room: &str) -> usize {
+        let capacities = self.0.room_capacities.lock().unwrap();
+        capacities.get(room).copied().unwrap_or(self.0.capacity)
+    }
+
+    // Give the channel of `room` its own capacity, None goes back to the default one
+    // A channel the room already has is replaced by one with the new capacity, its subscribers get `notice` and then see it close
+    // -- their streams end, so clients reconnect and subscribe to the new channel
+    pub fn set_room_capacity(&self, room: &str, capacity: Option<usize>, notice: Message) {
+        let mut capacities = self.0.room_capacities.lock().unwrap();
+        match capacity {
+            Some(capacity) => capacities.insert(room.to_string(), capacity.max(1)),
+            None => capacities.remove(room),
+        };
+        drop(capacities);
+
+        // The next subscription creates the new channel, see subscribe_to
+        self.close_room(room, notice);
+    }
+
+    // Close the channels of `room`, if it has any, after sending `notice` on its priority one
+    // Its subscribers get the notice and then see the channels close, ending their streams
+    // Subscribing to the room again later creates new channels
+    pub fn close_room(&self, room: &str, notice: Message) {
+        let closed = self.0.priority.lock().unwrap().remove(room);
+        if let Some(tx) = closed {
+            let _res = tx.send(notice);
+        }
+        self.0.rooms.lock().unwrap().remove(room);
+    }
+
+    // Send a message to every room with a channel, and once to the subscribers of every room
+    // `notice` makes the message for a room, and for the subscribers of every room with an empty one
+    pub fn announce(&self, notice: impl Fn(&str) -> Message) {
+        for (room, tx) in self.0.priority.lock().unwrap().iter() {
+            let _res = tx.send(notice(room));
+        }
+        let _res = self.0.all_priority.send(notice(""));
+    }
+
+    // ChannelStats of the every room channel
+    pub fn all_stats(&self) -> ChannelStats {
+        ChannelStats::of(&self.0.all)
+    }
+
+    // ChannelStats of every room channel, by room
+    pub fn room_stats(&self) -> BTreeMap<String, ChannelStats> {
+        stats_of(&self.0.rooms)
+    }
+
+    // ChannelStats of every direct message channel, by username
+    pub fn user_stats(&self) -> BTreeMap<String, ChannelStats> {
+        stats_of(&self.0.users)
+    }
+
+    // Number of subscriptions reading the public messages of `room` (of every room when None)
+    pub fn subscribers(&self, room: Option<&str>) -> usize {
+        match room {
+            Some(room) => self
+                .0
+                .rooms
+                .lock()
+                .unwrap()
+                .get(room)
+                .map_or(0, Sender::receiver_count),
+            None => self.0.all.receiver_count(),
+        }
+    }
+
+    // Number of subscriptions currently open
+    pub fn receiver_count(&self) -> usize {
+        self.0.all.receiver_count()
+            + self
+                .0
+                .rooms
+                .lock()
+                .unwrap()
+                .values()
+                .map(Sender::receiver_count)
+                .sum::<usize>()
+    }
+}
+
+// Send `msg` on the channel of `key`, if anyone ever subscribed to it
+// A channel nobody listens to anymore is dropped, it's created again on the next subscription
+fn send_to(channels: &Mutex<HashMap<String, Sender<Message>>>, key: &str, msg: &Message) -> usize {
+    let mut channels = channels.lock().unwrap();
+    let Some(tx) = channels.get(key) else {
+        return 0;
+    };
+
+    match tx.send(msg.clone()) {
+        Ok(reached) => reached,
+        Err(_) => {
+            channels.remove(key);
+            0
+        }
+    }
+}
+
+fn stats_of(channels: &Mutex<HashMap<String, Sender<Message>>>) -> BTreeMap<String, ChannelStats> {
+    channels
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, tx)| (key.clone(), ChannelStats::of(tx)))
+        .collect()
+}
+
+// Messages in the channel of `key` its slowest subscriber has yet to read
+fn backlog_of(channels: &Mutex<HashMap<String, Sender<Message>>>, key: &str) -> usize {
+    channels.lock().unwrap().get(key).map_or(0, Sender::len)
+}
+
+fn subscribe_to(
+    channels: &Mutex<HashMap<String, Sender<Message>>>,
+    key: &str,
+    capacity: usize,
+) -> Receiver<Message> {
+    channels
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_insert_with(|| channel(capacity).0)
+        .subscribe()
+}
+
+// Drop the channel of `key` if the subscription being dropped is its last receiver
+fn unsubscribe_from(channels: &Mutex<HashMap<String, Sender<Message>>>, key: &str) {
+    let mut channels = channels.lock().unwrap();
+    if channels.get(key).is_some_and(|tx| tx.receiver_count() <= 1) {
+        channels.remove(key);
+    }
+}
+
+// The receiving end of Channels::subscribe, yielding messages from the room (or every room) and direct channels
+pub struct Subscription {
+    channels: Channels,
+    room: Option<String>,
+    username: Option<String>,
+    messages: Receiver<Message>,
+    priority: Receiver<Message>,
+    direct: Option<Receiver<Message>>,
+    // Messages skipped as try_recv found out, reported by the next recv
+    lagged: Option<u64>,
+}
+
+impl Subscription {
+    // The next message from any of the subscribed channels, with the same errors as a broadcast Receiver
+    // -- the priority channel is read first, whatever waits on the others
+    // -- Lagged only counts the messages skipped on the channel that fell behind
+    pub async fn recv(&mut self) -> Result<Message, RecvError> {
+        if let Some(skipped) = self.lagged.take() {
+            return Err(RecvError::Lagged(skipped));
+        }
+        match &mut self.direct {
+            Some(direct) => select! {
+                biased;
+                msg = self.priority.recv() => msg,
+                msg = self.messages.recv() => msg,
+                msg = direct.recv() => msg,
+            },
+            None => select! {
+                biased;
+                msg = self.priority.recv() => msg,
+                msg = self.messages.recv() => msg,
+            },
+        }
+    }
+
+    // A message that's already waiting on one of the subscribed channels, None when there's none
+    // Falling behind or the channel closing is left for the next recv to report
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let receivers = [&mut self.priority, &mut self.messages]
+            .into_iter()
+            .chain(self.direct.as_mut());
+        for rx in receivers {
+            match rx.try_recv() {
+                Ok(msg) => return Some(msg),
+                Err(TryRecvError::Lagged(skipped)) => {
+                    self.lagged = Some(skipped);
+                    return None;
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => {}
+            }
+        }
+        None
+    }
+}
+
+impl Subscription {
+    // Read this subscription ahead into a queue of at most `max` messages, or read it as it is when None
+    pub fn buffered(self, max: Option<usize>) -> Feed {
+        let Some(max) = max else {
+            return Feed::Live(self);
+        };
+
+        let (tx, queue) = mpsc::channel(max.max(1));
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let task = spawn(forward(self, tx, overflowed.clone()));
+        Feed::Buffered {
+            queue,
+            overflowed,
+            task,
+        }
+    }
+}
+
+// Move what `subscription` receives into `tx` until its queue is full, the subscription then drops and frees its channel
+async fn forward(
+    mut subscription: Subscription,
+    tx: mpsc::Sender<Message>,
+    overflowed: Arc<AtomicBool>,
+) {
+    loop {
+        match subscription.recv().await {
+            Ok(msg) => match tx.try_send(msg) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => overflowed.store(true, Ordering::Relaxed),
+                Err(TrySendError::Closed(_)) => {}
+            },
+            Err(RecvError::Lagged(_)) => overflowed.store(true, Ordering::Relaxed),
+            Err(RecvError::Closed) => {}
+        }
+        break;
+    }
+}
+
+// Messages of a Subscription as an /events stream reads them, see max_buffered_events
+// -- Live -> straight from the subscription, a client reading too slowly holds its channel back until it lags
+// -- Buffered -> through a bounded queue a task of its own fills, the slow client lags behind its queue instead of the channel
+//    once the queue is full the task drops the subscription, recv hands out what was queued and then fails with Lagged
+pub enum Feed {
+    Live(Subscription),
+    Buffered {
+        queue: mpsc::Receiver<Message>,
+        overflowed: Arc<AtomicBool>,
+        task: JoinHandle<()>,
+    },
+}
+
+impl Feed {
+    // The next message, with the same errors as Subscription::recv
+    pub async fn recv(&mut self) -> Result<Message, RecvError> {
+        match self {
+            Feed::Live(subscription) => subscription.recv().await,
+            Feed::Buffered {
+                queue, overflowed, ..
+            } => match queue.recv().await {
+                Some(msg) => Ok(msg),
+                None if overflowed.load(Ordering::Relaxed) => Err(RecvError::Lagged(0)),
+                None => Err(RecvError::Closed),
+            },
+        }
+    }
+
+    // A message that's already waiting, like Subscription::try_recv
+    pub fn try_recv(&mut self) -> Option<Message> {
+        match self {
+            Feed::Live(subscription) => subscription.try_recv(),
+            Feed::Buffered { queue, .. } => queue.try_recv().ok(),
+        }
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        if let Feed::Buffered { task, .. } = self {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // A room channel closed by close_room has no sender left, the one in the map now is someone else's
+        if let Some(room) = &self.room {
+            if self.messages.sender_strong_count() > 0 {
+                unsubscribe_from(&self.channels.0.rooms, room);
+            }
+            if self.priority.sender_strong_count() > 0 {
+                unsubscribe_from(&self.channels.0.priority, room);
+            }
+        }
+        if let Some(username) = &self.username {
+            unsubscribe_from(&self.channels.0.users, username);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{unlimited, TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+    use rocket::tokio::time::Duration;
+    use std::time::Instant;
+
+    // A chat whose lobby has a stream nobody reads, so its channel of 2 fills up after two posts
+    fn full_chat(overflow: &str) -> TestChat {
+        TestChat::configured(|figment| {
+            unlimited(figment)
+                .merge(("chat.channel_capacity", 2))
+                .merge(("chat.overflow", overflow.to_string()))
+        })
+    }
+
+    #[test]
+    fn drop_sends_anyway_and_the_stream_lags() {
+        let chat = full_chat("drop");
+        let mut events = chat.events("room=lobby");
+        for message in ["one", "two", "three"] {
+            assert_eq!(chat.post("lobby", "alice", message), Status::Ok);
+        }
+        assert_eq!(
+            events.find("lagged", WAIT).unwrap().data.as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn reject_answers_503_with_retry_after() {
+        let chat = full_chat("reject");
+        let mut events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "one"), Status::Ok);
+        assert_eq!(chat.post("lobby", "alice", "two"), Status::Ok);
+
+        let body = json!({ "room": "lobby", "username": "alice", "message": "three" });
+        let reply = chat.send(chat.post_json("/message", &body));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+        assert_eq!(reply.header("Retry-After"), Some("1"));
+
+        // Once the stream catches up there's room again
+        assert_eq!(events.messages(2, WAIT).len(), 2);
+        assert_eq!(chat.post("lobby", "alice", "three"), Status::Ok);
+    }
+
+    #[test]
+    fn best_effort_retry_waits_then_sends_anyway() {
+        let chat = full_chat("best-effort-retry");
+        let mut events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "one"), Status::Ok);
+        assert_eq!(chat.post("lobby", "alice", "two"), Status::Ok);
+
+        let started = Instant::now();
+        assert_eq!(chat.post("lobby", "alice", "three"), Status::Ok);
+        assert!(started.elapsed() >= OVERFLOW_RETRY_DELAY * OVERFLOW_RETRIES as u32);
+        assert_eq!(
+            events.find("lagged", WAIT).unwrap().data.as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn clients_reading_too_slowly_are_disconnected_past_max_buffered_events() {
+        let chat = TestChat::configured(|figment| {
+            unlimited(figment).merge(("chat.max_buffered_events", 3))
+        });
+        let mut slow = chat.events("room=lobby");
+        let mut fast = chat.events("room=lobby");
+
+        // The slow stream isn't read while the posts go out, the fast one keeps up
+        let mut received = 0;
+        for i in 0..10 {
+            assert_eq!(
+                chat.post("lobby", "alice", &format!("message {}", i)),
+                Status::Ok
+            );
+            received += fast.messages(1, WAIT).len();
+        }
+        assert_eq!(received, 10);
+
+        // What was queued comes first, then the stream is told why it ends
+        let events = slow.events(20, WAIT);
+        let last = events.last().unwrap();
+        assert_eq!(last.name(), "slow_consumer");
+        let queued = events.iter().filter_map(|event| event.message()).count();
+        assert_eq!(queued, 3);
+        assert!(slow.ended(WAIT));
+    }
+
+    #[test]
+    fn unbuffered_slow_clients_lag_instead() {
+        let chat =
+            TestChat::configured(|figment| unlimited(figment).merge(("chat.channel_capacity", 4)));
+        let mut slow = chat.events("room=lobby");
+        for i in 0..10 {
+            chat.post_id("lobby", "alice", &format!("message {}", i));
+        }
+
+        assert!(slow.find("lagged", WAIT).is_some());
+        assert!(!slow.ended(Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn announcements_survive_chat_lagging() {
+        let chat =
+            TestChat::configured(|figment| unlimited(figment).merge(("chat.channel_capacity", 2)));
+        let mut flooded = chat.events("room=lobby");
+        let flood = |from: usize| {
+            for i in from..from + 5 {
+                chat.post_id("lobby", "alice", &format!("message {}", i));
+            }
+        };
+
+        flood(0);
+        let carol = chat.events("room=lobby&username=carol");
+        flood(5);
+        chat.block_on(async { drop(carol) });
+        flood(10);
+
+        let events = flooded.events(20, Duration::from_millis(500));
+        assert!(events.iter().any(|event| event.name() == "lagged"));
+        let announcements: Vec<_> = events
+            .iter()
+            .filter(|event| event.name() == "system")
+            .filter_map(|event| event.message())
+            .map(|msg| msg.message)
+            .collect();
+        assert_eq!(announcements, ["carol joined lobby", "carol left lobby"]);
+    }
+}
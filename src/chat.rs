@@ -0,0 +1,472 @@
+use crate::access_log::AccessLog;
+use crate::admin::Moderator;
+use crate::auth::{ApiKey, Reservations};
+use crate::bans::Bans;
+use crate::channels::{Channels, NoSubscribers, Overflow, OVERFLOW_RETRIES, OVERFLOW_RETRY_DELAY};
+use crate::config::ChatConfig;
+use crate::dedupe::{Claim, RecentPosts};
+use crate::drain::Draining;
+use crate::error::ApiError;
+use crate::filter::WordFilter;
+use crate::instance::InstanceId;
+use crate::lock::RoomLocks;
+use crate::message::{
+    mentions, now_millis, Message, MessageForm, MessageIds, MessageKind, PostResponse,
+    RoomSequences,
+};
+use crate::metrics::Metrics;
+use crate::moderators::RoomModerators;
+use crate::motd::Motd;
+use crate::mute::Mutes;
+use crate::pipeline::{self, Context, Draft};
+use crate::presence::{Presence, RoomCaps};
+use crate::rate_limit::UsernameLimiter;
+#[cfg(feature = "redis")]
+use crate::relay::Relay;
+use crate::rooms::RoomRegistry;
+use crate::signature::Signer;
+use crate::slow_mode::SlowMode;
+use crate::store::Store;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::time::sleep;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// Everything handlers need to accept and deliver messages, gathered from managed state by a single request guard
+// Saves every handler from listing each piece of state as its own argument
+pub struct Chat<'r> {
+    pub config: &'r ChatConfig,
+    pub reservations: &'r Reservations,
+    pub bans: &'r Bans,
+    pub filter: &'r WordFilter,
+    pub ids: &'r MessageIds,
+    pub seqs: &'r RoomSequences,
+    pub store: &'r Store,
+    pub presence: &'r Presence,
+    pub room_caps: &'r RoomCaps,
+    pub metrics: &'r Metrics,
+    pub queue: &'r Channels,
+    pub access_log: &'r AccessLog,
+    pub slow_mode: &'r SlowMode,
+    pub recent: &'r RecentPosts,
+    pub mutes: &'r Mutes,
+    pub motd: &'r Motd,
+    pub instance: &'r InstanceId,
+    pub usernames: &'r UsernameLimiter,
+    pub locks: &'r RoomLocks,
+    pub moderators: &'r RoomModerators,
+    pub rooms: &'r RoomRegistry,
+    pub draining: &'r Draining,
+    pub signer: Option<&'r Signer>,
+    #[cfg(feature = "redis")]
+    pub relay: Option<&'r Relay>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Chat<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let rocket = req.rocket();
+        let chat = (|| {
+            Some(Chat {
+                config: rocket.state()?,
+                reservations: rocket.state()?,
+                bans: rocket.state()?,
+                filter: rocket.state()?,
+                ids: rocket.state()?,
+                seqs: rocket.state()?,
+                store: rocket.state()?,
+                presence: rocket.state()?,
+                room_caps: rocket.state()?,
+                metrics: rocket.state()?,
+                queue: rocket.state()?,
+                access_log: rocket.state()?,
+                slow_mode: rocket.state()?,
+                recent: rocket.state()?,
+                mutes: rocket.state()?,
+                motd: rocket.state()?,
+                instance: rocket.state()?,
+                usernames: rocket.state()?,
+                locks: rocket.state()?,
+                moderators: rocket.state()?,
+                rooms: rocket.state()?,
+                draining: rocket.state()?,
+                signer: rocket.state(),
+                #[cfg(feature = "redis")]
+                relay: rocket.state(),
+            })
+        })();
+
+        match chat {
+            Some(chat) => Outcome::Success(chat),
+            None => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+// Why Chat::publish failed
+// -- NotStored -> the store failed to save the message, it was broadcast all the same
+// -- NoSubscribers -> the message was stored, as the Message it carries, but nobody was subscribed to receive it
+#[derive(Debug)]
+pub enum PublishError {
+    NotStored,
+    NoSubscribers(Box<Message>),
+}
+
+// A message accepted by Chat::submit
+// -- message -> the broadcast Message
+// -- deduped -> the post duplicated one the user just made, `message` is that earlier one and nothing was broadcast again
+pub struct Submitted {
+    pub message: Message,
+    pub deduped: bool,
+}
+
+impl From<Submitted> for PostResponse {
+    fn from(submitted: Submitted) -> PostResponse {
+        PostResponse {
+            id: submitted.message.id,
+            timestamp: submitted.message.timestamp,
+            client_msg_id: submitted.message.client_msg_id,
+            deduped: submitted.deduped,
+        }
+    }
+}
+
+impl<'r> Chat<'r> {
+    // Accept a message submitted by a client, however it arrived (form, JSON or WebSocket)
+    // -- `form` must have passed the field checks already (form attributes or MessageForm::validate)
+    // -- `token` is the session token the client presented, needed to post as a reserved username
+    // -- `key` is whether the client presented the api_key, which lets it post as any username without a token
+    // -- `moderator` is set when the client presented the admin token or a moderator's session token, moderators of the room may post to it when it's locked (see RoomLocks)
+    // -- `ip` is where the client connects from, checked against the bans along with the username
+    // A duplicate of the message the user just posted to the room (see RecentPosts) is accepted without being published again
+    // Users the message mentions as @username get a "mention" of it, see notify_mentions
+    // Errors are 422 for a blank/too long body, 403 for a banned user, a reserved username without its token or a locked room it doesn't moderate,
+    // 404 when replying to a message the user can't see and 422 when it is in another room,
+    // 429 when the room is in slow mode and the user posted there too recently or the IP posted as too many usernames (see UsernameLimiter),
+    // 500 when the store failed to save it (it's still broadcast, but won't be part of the history),
+    // and 503 when the server is shutting down (see Draining), when the room is new and max_rooms are in use (see RoomRegistry), when nobody received it or, with the "reject" overflow policy, when its subscribers are falling behind
+    pub async fn submit(
+        &self,
+        form: MessageForm,
+        token: Option<&str>,
+        key: ApiKey,
+        moderator: Option<&Moderator>,
+        ip: Option<IpAddr>,
+    ) -> Result<Submitted, ApiError> {
+        if self.draining.is_draining() {
+            return Err(ApiError::new(
+                Status::ServiceUnavailable,
+                "server is shutting down",
+            ));
+        }
+        let mut form = form.trimmed();
+        form.validate_content()
+            .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+        let draft = self.prepare(form.message, form.markdown)?;
+        form.message = draft.message;
+        self.check_ban(Some(&form.room), Some(&form.username), ip)?;
+        if key != ApiKey::Verified && !self.reservations.authorize(&form.username, token) {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                format!("username {} is reserved", form.username),
+            ));
+        }
+        let moderates = moderator.is_some_and(|m| m.moderates(&form.room, self.moderators));
+        if !moderates && self.locks.is_locked(&form.room) {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                format!("{} is read-only, only moderators can post to it", form.room),
+            ));
+        }
+        self.check_room(&form.room)?;
+        if let (ApiKey::Open, Some(ip)) = (key, ip) {
+            if let Err(wait) = self.usernames.check(ip, &form.username) {
+                let secs = wait.as_secs_f64().ceil() as u64;
+                return Err(ApiError::new(
+                    Status::TooManyRequests,
+                    format!(
+                        "too many usernames from your address, wait {}s before posting as {}",
+                        secs, form.username
+                    ),
+                )
+                .with_retry_after(secs));
+            }
+        }
+        self.check_reply(&form).await?;
+        let reservation = match self.recent.claim(&form).await {
+            Claim::Fresh(reservation) => reservation,
+            Claim::Duplicate(message) => {
+                return Ok(Submitted {
+                    message: *message,
+                    deduped: true,
+                })
+            }
+        };
+        if let Err(wait) = self.slow_mode.check(&form.room, &form.username) {
+            let secs = wait.as_secs_f64().ceil() as u64;
+            return Err(ApiError::new(
+                Status::TooManyRequests,
+                format!(
+                    "{} is in slow mode, wait {}s before posting again",
+                    form.room, secs
+                ),
+            )
+            .with_retry_after(secs));
+        }
+        self.check_overflow(&form).await?;
+
+        let message = match self.publish(form.clone(), draft.html, key).await {
+            Ok(message) => message,
+            Err(PublishError::NotStored) => {
+                return Err(ApiError::new(
+                    Status::InternalServerError,
+                    "failed to store message",
+                ))
+            }
+            // The message is in the history all the same, a retry of the post gets it back rather than storing it twice
+            Err(PublishError::NoSubscribers(message)) => {
+                reservation.record(&message);
+                return Err(ApiError::new(
+                    Status::ServiceUnavailable,
+                    "no subscribers are listening",
+                ));
+            }
+        };
+        reservation.record(&message);
+        self.notify_mentions(&message);
+
+        Ok(Submitted {
+            message,
+            deduped: false,
+        })
+    }
+
+    // Run a message body through the configured pipeline, see Step
+    // `markdown` is set when the post asked for its body to be rendered, render_markdown has every body rendered
+    // 422 when a step rejected it
+    pub fn prepare(&self, message: String, markdown: bool) -> Result<Draft, ApiError> {
+        let draft = Draft {
+            message,
+            markdown: markdown || self.config.render_markdown,
+            html: None,
+        };
+        let context = Context {
+            filter: self.filter,
+            max_len: self.config.max_message_len,
+        };
+        pipeline::run(&self.config.pipeline, draft, &context)
+            .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))
+    }
+
+    // 403 when the client is banned from `room`, or from every room when None is given, by username or IP
+    pub fn check_ban(
+        &self,
+        room: Option<&str>,
+        username: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Result<(), ApiError> {
+        if self.bans.is_banned_from(room, username, ip) {
+            return Err(ApiError::new(Status::Forbidden, "you are banned"));
+        }
+
+        Ok(())
+    }
+
+    // Send a mention to every user a public message mentions who has an /events stream open in its room, besides its author
+    // Presence is local to this instance, so with a relay only the users connected here are told
+    fn notify_mentions(&self, msg: &Message) {
+        if msg.to.is_some() {
+            return;
+        }
+        for username in mentions(&msg.message) {
+            if username != msg.username && self.presence.is_present(&msg.room, &username) {
+                let _res = self
+                    .queue
+                    .send(Message::mention(self.ids.next(), msg, &username));
+            }
+        }
+    }
+
+    // 503 when `room` is new and there's no space left for it, see RoomRegistry
+    pub fn check_room(&self, room: &str) -> Result<(), ApiError> {
+        self.rooms.admit(room, self.queue).map_err(|_| {
+            ApiError::new(
+                Status::ServiceUnavailable,
+                format!("too many rooms in use, {} can't be created", room),
+            )
+        })
+    }
+
+    // Load what a client opening /events or /ws hasn't seen yet, as `room` and `username` would receive it
+    // -- A reconnecting client gets everything after `last_id`, the last id it received
+    // -- A new client gets the most recent messages (up to history_limit) so it can catch up
+    // A store that fails to load is logged and the client starts without a backlog
+    pub async fn backlog(
+        &self,
+        room: Option<&str>,
+        username: Option<&str>,
+        last_id: Option<u64>,
+    ) -> Vec<Message> {
+        let history = match last_id {
+            Some(id) => self.store.since(room, username, id).await,
+            None => {
+                let limit = self.config.history_limit;
+                self.store.recent(room, username, limit).await
+            }
+        };
+        history.unwrap_or_else(|e| {
+            error!("failed to load message history: {}", e);
+            Vec::new()
+        })
+    }
+
+    // A reply must be to a stored message the user can see, in the same room
+    async fn check_reply(&self, form: &MessageForm) -> Result<(), ApiError> {
+        let Some(id) = form.reply_to else {
+            return Ok(());
+        };
+
+        let parent = self
+            .store
+            .get(id)
+            .await
+            .map_err(|e| {
+                error!("failed to load message {}: {}", id, e);
+                ApiError::new(
+                    Status::InternalServerError,
+                    "failed to load the replied message",
+                )
+            })?
+            .filter(|parent| !parent.deleted && parent.visible_to(None, Some(&form.username)))
+            .ok_or_else(|| ApiError::new(Status::NotFound, format!("no message with id {}", id)))?;
+        if parent.room != form.room {
+            return Err(ApiError::new(
+                Status::UnprocessableEntity,
+                format!("message {} is in another room", id),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Apply the overflow policy when a channel `form` goes to is full, see Overflow
+    async fn check_overflow(&self, form: &MessageForm) -> Result<(), ApiError> {
+        let is_full = || {
+            self.queue
+                .is_full(&form.room, &form.username, form.to.as_deref())
+        };
+
+        match self.config.overflow {
+            Overflow::Drop => {}
+            Overflow::Reject => {
+                if is_full() {
+                    return Err(ApiError::new(
+                        Status::ServiceUnavailable,
+                        "subscribers are falling behind, try again shortly",
+                    )
+                    .with_retry_after(1));
+                }
+            }
+            Overflow::BestEffortRetry => {
+                for _ in 0..OVERFLOW_RETRIES {
+                    if !is_full() {
+                        break;
+                    }
+                    sleep(OVERFLOW_RETRY_DELAY).await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Turn a submitted message into a Message, persist it and broadcast it to every subscriber
+    // -- The body has already been through the pipeline (see prepare), `html` is the rendering it made, if any
+    // -- It's signed when posted with the api_key (`key`) and a signing_secret is configured, see Signer
+    // -- Returns the broadcast Message once it's stored, see PublishError for when it isn't or nobody received it
+    pub async fn publish(
+        &self,
+        form: MessageForm,
+        html: Option<String>,
+        key: ApiKey,
+    ) -> Result<Message, PublishError> {
+        // Only public messages are numbered within their room
+        let (id, seq) = match form.to {
+            None => {
+                let (id, seq) = self.seqs.next(&form.room, self.ids);
+                (id, Some(seq))
+            }
+            Some(_) => (self.ids.next(), None),
+        };
+        let mut msg = Message {
+            id,
+            seq,
+            timestamp: now_millis(),
+            room: form.room,
+            username: form.username,
+            message: form.message,
+            to: form.to,
+            system: false,
+            kind: MessageKind::Chat,
+            target: None,
+            html,
+            deleted: false,
+            edited_at: None,
+            reactions: BTreeMap::new(),
+            client_msg_id: form.client_msg_id,
+            timestamp_iso: None,
+            reply_to: form.reply_to,
+            origin_instance: Some(self.instance.0.clone()),
+            attachment_url: form.attachment_url,
+            signature: None,
+        };
+        if let (ApiKey::Verified, Some(signer)) = (key, self.signer) {
+            msg.signature = Some(signer.sign(&msg));
+        }
+
+        // Persist the message so clients connecting later can replay it
+        // This happens whether or not anyone is listening, and a storage failure doesn't stop live delivery, it's reported once the message is sent
+        let stored = self.store.insert(&msg).await.map_err(|e| {
+            error!("failed to store message {}: {}", msg.id, e);
+            PublishError::NotStored
+        });
+        self.metrics.message_posted();
+
+        let delivered = self.deliver(&msg).await;
+        if delivered.is_err() {
+            if let Some(dropped) = self.metrics.dropped_no_subscribers() {
+                warn!(
+                    "message {} to {} reached no subscribers, {} such messages since launch",
+                    msg.id, msg.room, dropped
+                );
+            }
+        }
+        stored?;
+        match delivered {
+            Ok(()) => Ok(msg),
+            Err(NoSubscribers) => Err(PublishError::NoSubscribers(Box::new(msg))),
+        }
+    }
+
+    // Broadcast `msg` to every subscriber
+    async fn deliver(&self, msg: &Message) -> Result<(), NoSubscribers> {
+        // With a relay the message reaches this instance's subscribers back through Redis, like every other instance's
+        // -- Redis can't tell whether anyone is listening so this never fails, if Redis is down the message is only delivered locally
+        #[cfg(feature = "redis")]
+        if let Some(relay) = self.relay {
+            match relay.publish(msg).await {
+                Ok(()) => return Ok(()),
+                Err(e) => error!(
+                    "failed to relay message {}, delivering locally: {}",
+                    msg.id, e
+                ),
+            }
+        }
+
+        // Send fails if there are no active subscribers
+        self.queue.send(msg.clone()).map(|_| ())
+    }
+}
@@ -0,0 +1,86 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+// Length of the random nonce stored in front of every ciphertext
+const NONCE_LEN: usize = 12;
+
+// Symmetric encryption of message bodies at rest, see store_encryption_key
+// -- ChaCha20-Poly1305 with a fresh random nonce per body, so equal bodies don't give equal ciphertexts
+// -- stored as base64 of the nonce followed by the ciphertext, to fit the existing TEXT columns
+// Only bodies are encrypted, rooms and usernames stay plaintext so they can still be queried
+#[derive(Clone)]
+pub struct BodyCipher(ChaCha20Poly1305);
+
+impl BodyCipher {
+    // `key` is 32 bytes encoded as base64, i.e the output of `openssl rand -base64 32`
+    pub fn from_base64(key: &str) -> Result<BodyCipher, String> {
+        let key = STANDARD
+            .decode(key.trim())
+            .map_err(|e| format!("store_encryption_key is not valid base64: {}", e))?;
+        ChaCha20Poly1305::new_from_slice(&key)
+            .map(BodyCipher)
+            .map_err(|_| format!("store_encryption_key must be 32 bytes, got {}", key.len()))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("encrypting a message body");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        STANDARD.encode(sealed)
+    }
+
+    // Fails when `sealed` wasn't encrypted with this key or was tampered with
+    pub fn decrypt(&self, sealed: &str) -> Result<String, String> {
+        let sealed = STANDARD
+            .decode(sealed)
+            .map_err(|e| format!("encrypted body is not valid base64: {}", e))?;
+        if sealed.len() < NONCE_LEN {
+            return Err("encrypted body is too short".into());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                "failed to decrypt body, is store_encryption_key the one it was stored with?"
+            })?;
+        String::from_utf8(plaintext).map_err(|e| format!("decrypted body is not UTF-8: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn keys_are_32_bytes_of_base64() {
+        assert!(BodyCipher::from_base64(KEY).is_ok());
+        assert!(BodyCipher::from_base64("not base64!").is_err());
+        assert_eq!(
+            BodyCipher::from_base64("AAEC").err().unwrap(),
+            "store_encryption_key must be 32 bytes, got 3"
+        );
+    }
+
+    #[test]
+    fn bodies_only_decrypt_with_their_key() {
+        let cipher = BodyCipher::from_base64(KEY).unwrap();
+        let sealed = cipher.encrypt("hello");
+        assert_ne!(cipher.encrypt("hello"), sealed);
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), "hello");
+
+        let other = BodyCipher::from_base64(&STANDARD.encode([7u8; 32])).unwrap();
+        assert!(other.decrypt(&sealed).is_err());
+        assert!(cipher.decrypt("AAAA").is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression as Level;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header};
+use rocket::{Request, Response};
+use std::io::{Cursor, Write};
+
+// Bodies smaller than this many bytes are sent as is, compressing them saves next to nothing
+const MIN_SIZE: usize = 256;
+
+// Encodings we can compress a body with, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    // The preferred encoding among those listed in Accept-Encoding headers, skipping the ones refused with q=0
+    // i.e "gzip, deflate;q=0.5" -> Gzip, "deflate, gzip;q=0" -> Deflate
+    fn negotiate<'a>(accepted: impl Iterator<Item = &'a str>) -> Option<Encoding> {
+        let accepted: Vec<&str> = accepted
+            .flat_map(|header| header.split(','))
+            .filter_map(|entry| {
+                let mut parts = entry.split(';').map(str::trim);
+                let name = parts.next()?;
+                let refused = parts.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .collect();
+
+        [Encoding::Gzip, Encoding::Deflate]
+            .into_iter()
+            .find(|encoding| {
+                accepted
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(encoding.name()))
+            })
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Fairing compressing JSON responses (/history, /search, /rooms, /users...) for clients sending Accept-Encoding: gzip or deflate
+// -- Only JSON bodies of at least MIN_SIZE bytes are compressed
+// -- /events is never compressed, an event stream never ends so its body can't be compressed up front,
+//    and buffering compressed events would delay them until enough of them piled up
+pub struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if res.content_type() != Some(ContentType::JSON)
+            || res.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+        // The body depends on Accept-Encoding from now on, caches need to know
+        res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        let Some(encoding) = Encoding::negotiate(req.headers().get("Accept-Encoding")) else {
+            return;
+        };
+
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("failed to read response body to compress: {}", e);
+                return;
+            }
+        };
+        if body.len() < MIN_SIZE {
+            res.set_sized_body(body.len(), Cursor::new(body));
+            return;
+        }
+
+        match encoding.compress(&body) {
+            Ok(compressed) => {
+                res.set_header(Header::new("Content-Encoding", encoding.name()));
+                res.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Err(e) => {
+                error!("failed to compress response body: {}", e);
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{unlimited, TestChat};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn the_preferred_accepted_encoding_is_picked() {
+        let negotiate = |headers: &[&str]| Encoding::negotiate(headers.iter().copied());
+        assert_eq!(negotiate(&["gzip, deflate;q=0.5"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&["deflate, gzip;q=0"]), Some(Encoding::Deflate));
+        assert_eq!(negotiate(&["deflate", "GZIP"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&["br, identity"]), None);
+        assert_eq!(negotiate(&[]), None);
+    }
+
+    #[test]
+    fn large_json_bodies_are_gzipped_when_accepted() {
+        let chat = TestChat::configured(unlimited);
+        let _events = chat.events("room=lobby");
+        for i in 0..10 {
+            chat.post_id("lobby", "alice", &format!("message number {}", i));
+        }
+
+        let plain = chat.send(chat.get("/history?room=lobby"));
+        assert!(plain.bytes.len() >= MIN_SIZE);
+        assert_eq!(plain.header("Content-Encoding"), None);
+        assert_eq!(plain.header("Vary"), Some("Accept-Encoding"));
+
+        let request = chat
+            .get("/history?room=lobby")
+            .header(Header::new("Accept-Encoding", "gzip"));
+        let gzipped = chat.send(request);
+        assert_eq!(gzipped.status, rocket::http::Status::Ok);
+        assert_eq!(gzipped.header("Content-Encoding"), Some("gzip"));
+        let mut body = String::new();
+        GzDecoder::new(&gzipped.bytes[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, plain.body);
+    }
+
+    #[test]
+    fn small_bodies_are_sent_as_is() {
+        let chat = TestChat::new();
+        let request = chat
+            .get("/history?room=empty")
+            .header(Header::new("Accept-Encoding", "gzip, deflate"));
+        let reply = chat.send(request);
+        assert!(reply.bytes.len() < MIN_SIZE);
+        assert_eq!(reply.header("Content-Encoding"), None);
+    }
+}
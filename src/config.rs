@@ -0,0 +1,188 @@
+use crate::access_log::LogLevel;
+use crate::channels::Overflow;
+use crate::pipeline::{Step, DEFAULT_PIPELINE};
+use crate::templates::Templates;
+use rocket::serde::Deserialize;
+
+// Chat specific settings, read from the `chat` table of Rocket's figment configuration
+// Every key is optional and falls back to the value in the Default impl below
+// -- i.e in Rocket.toml
+//    [default.chat]
+//    history_limit = 100
+// -- or through the environment: ROCKET_CHAT='{history_limit=100}'
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ChatConfig {
+    // SQLite database messages are persisted to, created if missing
+    pub database_url: String,
+    // Key message bodies are encrypted with before they're stored, 32 bytes as base64 (i.e `openssl rand -base64 32`)
+    // Unset stores them as plaintext, rooms and usernames are always stored as plaintext
+    pub store_encryption_key: Option<String>,
+    // How many messages /events replays on connect and /history returns by default
+    pub history_limit: usize,
+    // Message of the day greeting every /events stream as it opens, unset sends none
+    // Moderators can change it through /admin/motd
+    pub motd: Option<String>,
+    // Seconds of silence on an /events stream before a "ping" event is sent to keep it alive
+    pub heartbeat_interval: u64,
+    // Seconds between two checks that an /events client is still connected, 0 only notices it left with the next event or ping
+    // Until then a closed stream still counts, i.e its user stays in /users
+    pub disconnect_check_interval: u64,
+    // Seconds between two "stats" events telling an /events stream how many are listening, 0 sends none
+    pub stats_interval: u64,
+    // Milliseconds a browser should wait before reconnecting a dropped /events stream
+    pub retry_ms: u64,
+    // Longer reconnect delay, in milliseconds, sent when the server shuts down or a stream falls behind
+    pub backoff_retry_ms: u64,
+    // Milliseconds /events streams stay open once shutdown is requested, delivering what was already broadcast while posts get a 503
+    // 0 ends them right away, keep it under Rocket's shutdown.grace
+    pub shutdown_drain_ms: u64,
+    // Messages per second a single IP may sustain on /message and /ws
+    pub rate_limit_per_second: u32,
+    // Messages a single IP may send in a burst before the per second rate applies
+    pub rate_limit_burst: u32,
+    // Most distinct usernames a single IP may post as within username_window seconds, further ones get a 429, unset means no limit
+    // Posts with the api_key aren't limited
+    pub max_usernames_per_ip: Option<usize>,
+    pub username_window: u64,
+    // Milliseconds within which a post repeating the user's last one in the room (same body, recipient and attachment) isn't broadcast again, 0 turns it off
+    pub dedupe_window_ms: u64,
+    // Seconds a user has to wait between two posts to the same room, 0 turns slow mode off
+    // Moderators can change it per room through /admin/slowmode
+    pub slow_mode: u64,
+    // Typing notifications per second a single IP may send to /typing, also its burst
+    pub typing_rate_limit_per_second: u32,
+    // Words censored out of message bodies
+    pub blacklist: Vec<String>,
+    // File with more words to censor, one per line
+    pub blacklist_file: Option<String>,
+    // Longest message body, in characters (grapheme clusters, see graphemes), accepted by /message and /ws
+    pub max_message_len: usize,
+    // Steps message bodies go through before they're published, in order: "trim", "length", "filter" and "markdown", see Step
+    pub pipeline: Vec<Step>,
+    // Largest form or JSON body, in bytes, accepted by /message and the other endpoints, larger ones get a 413
+    // Sets Rocket's "form" and "json" data limits
+    pub max_body_size: u64,
+    // Most messages a single POST /messages may carry
+    pub max_batch_size: usize,
+    // Most history, search and export queries running at once, further ones get a 503 so they can't starve posting of store connections, unset means no limit
+    pub max_concurrent_queries: Option<usize>,
+    // How many messages each broadcast channel retains for subscribers that fall behind, see Channels
+    pub channel_capacity: usize,
+    // What a post does when a channel it goes to is full: "drop", "reject" or "best-effort-retry", see Overflow
+    pub overflow: Overflow,
+    // Origins other than our own allowed to call the API from a browser, "*" allows any
+    pub cors_allowed_origins: Vec<String>,
+    // Directory the frontend is served from, the repository's static directory when unset
+    pub static_dir: Option<String>,
+    // Token moderators send in the X-Admin-Token header to use the /admin endpoints and delete any message, unset disables them
+    pub admin_token: Option<String>,
+    // Key integrations (i.e a bot posting alerts) send in the X-API-Key header to post to /message, unset leaves /message open to anyone
+    // Once set every post needs it, and posts with it may use any username, reserved or not
+    pub api_key: Option<String>,
+    // Secret signing the messages posted with the api_key, consumers holding it can check their `signature` (see Signer), unset signs nothing
+    pub signing_secret: Option<String>,
+    // Render every message body from markdown to sanitized HTML, otherwise only messages posted with `markdown` set are
+    pub render_markdown: bool,
+    // Seconds between two passes pruning old messages from the store
+    pub retention_interval: u64,
+    // Seconds a message is kept in the store, unset keeps them forever
+    pub retention_max_age: Option<u64>,
+    // Messages kept in the store per room, the oldest are pruned first, unset keeps them all
+    pub retention_max_messages: Option<usize>,
+    // Level posts to /message and /events streams are logged at, one of "off", "error", "warn", "info" and "debug"
+    pub access_log_level: LogLevel,
+    // Whether the access log includes usernames
+    pub access_log_usernames: bool,
+    // Most messages an /events stream may have waiting for it to read, a client reading slower than that is sent a "slow_consumer" event and disconnected
+    // Unset leaves it to fall behind until it skips messages (a "lagged" event), see channel_capacity
+    pub max_buffered_events: Option<usize>,
+    // Most chat messages an /events stream asking for batch=true gathers into one "messages" event
+    pub max_event_batch: usize,
+    // Seconds after which an /events stream is closed with a "timeout" event for the client to reconnect, 0 keeps streams open indefinitely
+    pub max_stream_duration: u64,
+    // Most users present in a room at once, further /events connections to it get a 503, unset means no limit
+    // Only streams giving a username count, like /users; moderators can change it per room through /admin/roomcap
+    pub max_users_per_room: Option<usize>,
+    // Most rooms in use at once, posting to or streaming a new room beyond that gets a 503 while existing rooms keep working, unset means no limit
+    // A new room first evicts the rooms nobody is subscribed to that saw no post for room_idle_timeout seconds, 0 never evicts any
+    pub max_rooms: Option<usize>,
+    pub room_idle_timeout: u64,
+    // Most /events streams open at once, further connections get a 503, unset means no limit
+    pub max_sse_connections: Option<usize>,
+    // Most /events streams a single IP may have open at once, further connections from it get a 429, unset means no limit
+    pub max_sse_connections_per_ip: Option<usize>,
+    // Take the client IP from the first address of the X-Forwarded-For header, for servers behind a reverse proxy that sets it
+    // Leave it off otherwise, clients could send the header themselves to dodge bans and rate limits
+    pub trust_forwarded: bool,
+    // Port of a plain TCP bridge where each line a client sends, `room|username|message`, is posted and every message is written back as such a line
+    // Listens on Rocket's address, unset keeps it closed
+    pub tcp_bridge_port: Option<u16>,
+    // Id of this instance, sent along with every chat message it accepts as `origin_instance`, unset picks a random UUID at launch
+    pub instance_id: Option<String>,
+    // How the server words its announcements (joins, leaves...), see Templates
+    pub templates: Templates,
+    // Redis server to relay messages between instances through, i.e "redis://127.0.0.1/"
+    // Unset keeps messages in this process, setting it needs a build with the `redis` feature
+    pub redis_url: Option<String>,
+    // Redis pub/sub channel the instances share
+    pub redis_channel: String,
+}
+
+impl Default for ChatConfig {
+    fn default() -> ChatConfig {
+        ChatConfig {
+            database_url: "sqlite://chat.db".into(),
+            store_encryption_key: None,
+            history_limit: 50,
+            motd: None,
+            heartbeat_interval: 30,
+            disconnect_check_interval: 5,
+            stats_interval: 10,
+            retry_ms: 1000,
+            backoff_retry_ms: 5000,
+            shutdown_drain_ms: 0,
+            rate_limit_per_second: 5,
+            rate_limit_burst: 5,
+            max_usernames_per_ip: None,
+            username_window: 60,
+            dedupe_window_ms: 2000,
+            slow_mode: 0,
+            typing_rate_limit_per_second: 10,
+            blacklist: Vec::new(),
+            blacklist_file: None,
+            max_message_len: 2000,
+            pipeline: DEFAULT_PIPELINE.to_vec(),
+            max_body_size: 32 * 1024,
+            max_batch_size: 20,
+            max_concurrent_queries: None,
+            channel_capacity: 1024,
+            overflow: Overflow::Drop,
+            cors_allowed_origins: Vec::new(),
+            static_dir: None,
+            admin_token: None,
+            api_key: None,
+            signing_secret: None,
+            render_markdown: false,
+            retention_interval: 3600,
+            retention_max_age: None,
+            retention_max_messages: None,
+            access_log_level: LogLevel::Info,
+            access_log_usernames: true,
+            max_event_batch: 50,
+            max_stream_duration: 0,
+            max_buffered_events: None,
+            max_users_per_room: None,
+            max_rooms: None,
+            room_idle_timeout: 600,
+            max_sse_connections: None,
+            max_sse_connections_per_ip: None,
+            trust_forwarded: false,
+            tcp_bridge_port: None,
+            instance_id: None,
+            templates: Templates::default(),
+            redis_url: None,
+            redis_channel: "chat".into(),
+        }
+    }
+}
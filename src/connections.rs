@@ -0,0 +1,135 @@
+use crate::guards::ClientIp;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use rocket::State;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// Managed state capping how many /events streams may be open at once
+// -- in total, None means there is no cap
+// -- from a single client IP, so one client can't take every slot, None means there is no cap either
+pub struct ConnectionLimit {
+    total: Option<Arc<Semaphore>>,
+    per_ip: Option<usize>,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimit {
+    // Allow up to `max` streams at once and `max_per_ip` of them from the same IP, or any number when None
+    pub fn new(max: Option<usize>, max_per_ip: Option<usize>) -> ConnectionLimit {
+        let max = max.map(|max| max.min(Semaphore::MAX_PERMITS));
+        ConnectionLimit {
+            total: max.map(|max| Arc::new(Semaphore::new(max))),
+            per_ip: max_per_ip,
+            open: Arc::default(),
+        }
+    }
+
+    // Count one more stream from `ip`, None when it already has `per_ip` of them open
+    fn open_from(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if self.per_ip.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+
+        Some(IpSlot {
+            ip,
+            open: self.open.clone(),
+        })
+    }
+}
+
+// One stream counted against its IP's cap, uncounted when dropped
+struct IpSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            // Forget IPs without open streams, keeps the map from growing with every client ever seen
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Request guard holding one of the ConnectionLimit's slots
+// -- fails with 429 Too Many Requests when the client's IP already has as many streams open as it may
+// -- fails with 503 Service Unavailable when all the slots are taken
+// The slot is freed when the guard drops, so move it into the stream to hold it for as long as the stream lives
+// Requests without a known remote address only count against the total
+pub struct Connection {
+    _permit: Option<OwnedSemaphorePermit>,
+    _ip: Option<IpSlot>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Connection {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limit = match req.guard::<&State<ConnectionLimit>>().await {
+            Outcome::Success(limit) => limit,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        let ip = match ClientIp::of(req) {
+            Some(ip) if limit.per_ip.is_some() => match limit.open_from(ip) {
+                Some(slot) => Some(slot),
+                None => return Outcome::Error((Status::TooManyRequests, ())),
+            },
+            _ => None,
+        };
+
+        match &limit.total {
+            None => Outcome::Success(Connection {
+                _permit: None,
+                _ip: ip,
+            }),
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Outcome::Success(Connection {
+                    _permit: Some(permit),
+                    _ip: ip,
+                }),
+                Err(_) => Outcome::Error((Status::ServiceUnavailable, ())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestChat;
+    use rocket::http::Status;
+
+    #[test]
+    fn ips_get_429_past_max_sse_connections_per_ip() {
+        let chat =
+            TestChat::configured(|figment| figment.merge(("chat.max_sse_connections_per_ip", 2)));
+        let first = chat.events("room=lobby");
+        let _second = chat.events("room=lobby");
+        let reply = chat.send(chat.get("/events?room=lobby"));
+        assert_eq!(reply.status, Status::TooManyRequests);
+
+        // Closing a stream gives its slot back
+        chat.block_on(async { drop(first) });
+        let _third = chat.events("room=lobby");
+    }
+
+    #[test]
+    fn streams_past_max_sse_connections_get_503() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.max_sse_connections", 1)));
+        let _first = chat.events("room=lobby");
+        let reply = chat.send(chat.get("/events?room=other"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+    }
+}
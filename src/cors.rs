@@ -0,0 +1,128 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+
+// Headers a cross-origin client may send
+const ALLOWED_HEADERS: &str =
+    "Content-Type, Last-Event-ID, X-Session-Token, X-Admin-Token, X-API-Key";
+const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+
+// Fairing adding CORS headers for configured origins, so a separately hosted frontend can use /events and /message
+// -- Requests from an origin that isn't allowed get no CORS headers, so browsers refuse to hand them the response
+// -- "*" in the list allows every origin
+pub struct Cors {
+    origins: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(origins: Vec<String>) -> Cors {
+        Cors { origins }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|o| o == "*" || o == origin)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let origin = match req.headers().get_one("Origin") {
+            Some(origin) if self.allows(origin) => origin,
+            _ => return,
+        };
+
+        res.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        res.adjoin_header(Header::new("Vary", "Origin"));
+        if req.method() == Method::Options {
+            res.set_header(Header::new("Access-Control-Allow-Methods", ALLOWED_METHODS));
+            res.set_header(Header::new("Access-Control-Allow-Headers", ALLOWED_HEADERS));
+            res.set_header(Header::new("Access-Control-Max-Age", "86400"));
+        }
+    }
+}
+
+// Answers preflight requests for every path, the Cors fairing decides whether they carry the headers to succeed
+#[options("/<_..>")]
+pub fn preflight() -> Status {
+    Status::NoContent
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestChat;
+    use rocket::http::{Header, Status};
+
+    fn chat() -> TestChat {
+        TestChat::configured(|figment| {
+            figment.merge(("chat.cors_allowed_origins", ["https://app.example"]))
+        })
+    }
+
+    #[test]
+    fn preflights_from_allowed_origins_succeed() {
+        let chat = chat();
+        let request = chat
+            .client()
+            .options("/message")
+            .header(Header::new("Origin", "https://app.example"))
+            .header(Header::new("Access-Control-Request-Method", "POST"));
+        let reply = chat.send(request);
+
+        assert_eq!(reply.status, Status::NoContent);
+        assert_eq!(
+            reply.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert!(reply
+            .header("Access-Control-Allow-Methods")
+            .is_some_and(|methods| methods.contains("POST")));
+        assert!(reply
+            .header("Access-Control-Allow-Headers")
+            .is_some_and(|headers| headers.contains("Content-Type")));
+    }
+
+    #[test]
+    fn only_allowed_origins_get_cors_headers() {
+        let chat = chat();
+        let allowed = chat.send(
+            chat.get("/rooms")
+                .header(Header::new("Origin", "https://app.example")),
+        );
+        assert_eq!(
+            allowed.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+
+        let other = chat.send(
+            chat.get("/rooms")
+                .header(Header::new("Origin", "https://evil.example")),
+        );
+        assert_eq!(other.status, Status::Ok);
+        assert_eq!(other.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn a_wildcard_allows_every_origin() {
+        let chat =
+            TestChat::configured(|figment| figment.merge(("chat.cors_allowed_origins", ["*"])));
+        let reply = chat.send(
+            chat.get("/rooms")
+                .header(Header::new("Origin", "https://any.example")),
+        );
+        assert_eq!(
+            reply.header("Access-Control-Allow-Origin"),
+            Some("https://any.example")
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::message::{Message, MessageForm};
+use rocket::tokio::sync::watch;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Managed state remembering the last message each user posted to each room, so a double-clicked send or a client retry isn't broadcast twice
+// -- a post with the same body, recipient and attachment_url as the user's last one in that room, within `window`, is a duplicate
+// -- a window of 0 turns it off
+// A post is remembered from the moment it's checked (see claim), so two identical posts arriving together can't both be published
+pub struct RecentPosts {
+    window: Duration,
+    posts: Mutex<HashMap<(String, String), Recent>>,
+    claims: AtomicU64,
+}
+
+// A post remembered by RecentPosts
+// -- claim -> tells the Reservation which made it from a later one for the same room and user
+// -- published -> None while the post is still being published, then the Message it was published as
+struct Recent {
+    claim: u64,
+    at: Instant,
+    body: String,
+    to: Option<String>,
+    attachment_url: Option<String>,
+    published: watch::Sender<Option<Message>>,
+}
+
+impl Recent {
+    fn is_pending(&self) -> bool {
+        self.published.borrow().is_none()
+    }
+
+    fn duplicates(&self, form: &MessageForm) -> bool {
+        self.body == form.message
+            && self.to == form.to
+            && self.attachment_url == form.attachment_url
+    }
+}
+
+// What RecentPosts::claim made of a post
+// -- Fresh -> it duplicates nothing, publish it and record the Message it became with the Reservation
+// -- Duplicate -> the user just posted the same, this is the Message that post became
+pub enum Claim<'a> {
+    Fresh(Reservation<'a>),
+    Duplicate(Box<Message>),
+}
+
+impl RecentPosts {
+    pub fn new(window_ms: u64) -> RecentPosts {
+        RecentPosts {
+            window: Duration::from_millis(window_ms),
+            posts: Mutex::new(HashMap::new()),
+            claims: AtomicU64::new(0),
+        }
+    }
+
+    // Check whether `form` (trimmed) duplicates the user's last post to the room, reserving its place when it doesn't
+    // A duplicate of a post that is still being published waits for it, and takes its place if it fails
+    pub async fn claim(&self, form: &MessageForm) -> Claim<'_> {
+        let key = (form.room.clone(), form.username.clone());
+        loop {
+            let mut published = {
+                let now = Instant::now();
+                let mut posts = self.posts.lock().unwrap();
+                // Forget posts nothing can duplicate anymore, keeps the map from growing with every user ever seen
+                posts.retain(|_, recent| {
+                    recent.is_pending() || now.duration_since(recent.at) < self.window
+                });
+
+                match posts.get(&key).filter(|recent| recent.duplicates(form)) {
+                    Some(recent) => recent.published.subscribe(),
+                    None => {
+                        let claim = self.claims.fetch_add(1, Ordering::Relaxed);
+                        if !self.window.is_zero() {
+                            let recent = Recent {
+                                claim,
+                                at: now,
+                                body: form.message.clone(),
+                                to: form.to.clone(),
+                                attachment_url: form.attachment_url.clone(),
+                                published: watch::Sender::new(None),
+                            };
+                            posts.insert(key.clone(), recent);
+                        }
+                        return Claim::Fresh(Reservation {
+                            posts: self,
+                            key,
+                            claim,
+                            recorded: false,
+                        });
+                    }
+                }
+            };
+
+            // The sender is dropped along with the entry when the earlier post failed, then this one goes ahead instead
+            let message = match published.wait_for(Option::is_some).await {
+                Ok(message) => message.clone(),
+                Err(_) => None,
+            };
+            if let Some(message) = message {
+                return Claim::Duplicate(Box::new(message));
+            }
+        }
+    }
+}
+
+// The place a fresh post holds in RecentPosts while it's published
+// Dropping it without recording a Message (the post failed) frees the place for the next identical post
+pub struct Reservation<'a> {
+    posts: &'a RecentPosts,
+    key: (String, String),
+    claim: u64,
+    recorded: bool,
+}
+
+impl Reservation<'_> {
+    // Remember that the post was published as `message`, duplicates within the window from now on get it back
+    pub fn record(mut self, message: &Message) {
+        self.recorded = true;
+        let mut posts = self.posts.posts.lock().unwrap();
+        if let Some(recent) = posts.get_mut(&self.key) {
+            if recent.claim == self.claim {
+                recent.at = Instant::now();
+                recent.published.send_replace(Some(message.clone()));
+            }
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.recorded {
+            return;
+        }
+
+        let mut posts = self.posts.posts.lock().unwrap();
+        if posts
+            .get(&self.key)
+            .is_some_and(|recent| recent.claim == self.claim)
+        {
+            posts.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::{self, json};
+    use rocket::tokio::{join, time::sleep};
+
+    fn form(username: &str, message: &str) -> MessageForm {
+        json::from_value(json!({ "room": "lobby", "username": username, "message": message }))
+            .unwrap()
+    }
+
+    fn published(id: u64, form: &MessageForm) -> Message {
+        Message {
+            username: form.username.clone(),
+            ..Message::system(id, &form.room, form.message.clone())
+        }
+    }
+
+    // Claim `form`, expecting it to be fresh, and record it as published with `id`
+    async fn post(recent: &RecentPosts, form: &MessageForm, id: u64) {
+        match recent.claim(form).await {
+            Claim::Fresh(reservation) => reservation.record(&published(id, form)),
+            Claim::Duplicate(message) => {
+                panic!("{} was a duplicate of {}", form.message, message.id)
+            }
+        }
+    }
+
+    async fn duplicated(recent: &RecentPosts, form: &MessageForm) -> Option<u64> {
+        match recent.claim(form).await {
+            Claim::Fresh(_) => None,
+            Claim::Duplicate(message) => Some(message.id),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn the_same_post_within_the_window_is_a_duplicate() {
+        let recent = RecentPosts::new(2000);
+        post(&recent, &form("alice", "hi"), 1).await;
+        assert_eq!(duplicated(&recent, &form("alice", "hi")).await, Some(1));
+        assert_eq!(duplicated(&recent, &form("alice", "hi again")).await, None);
+        assert_eq!(duplicated(&recent, &form("bob", "hi")).await, None);
+    }
+
+    #[rocket::async_test]
+    async fn posts_stop_being_duplicates_once_the_window_is_over() {
+        let recent = RecentPosts::new(50);
+        post(&recent, &form("alice", "hi"), 1).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(duplicated(&recent, &form("alice", "hi")).await, None);
+
+        let off = RecentPosts::new(0);
+        post(&off, &form("alice", "hi"), 1).await;
+        assert_eq!(duplicated(&off, &form("alice", "hi")).await, None);
+    }
+
+    #[rocket::async_test]
+    async fn a_duplicate_of_a_post_being_published_waits_for_it() {
+        let recent = RecentPosts::new(2000);
+        let hi = form("alice", "hi");
+        let Claim::Fresh(reservation) = recent.claim(&hi).await else {
+            panic!("the first post was a duplicate");
+        };
+
+        let publish = async {
+            sleep(Duration::from_millis(50)).await;
+            reservation.record(&published(7, &hi));
+        };
+        let (_, duplicate) = join!(publish, duplicated(&recent, &hi));
+        assert_eq!(duplicate, Some(7));
+    }
+
+    #[rocket::async_test]
+    async fn a_duplicate_of_a_failed_post_goes_ahead() {
+        let recent = RecentPosts::new(2000);
+        let hi = form("alice", "hi");
+        let Claim::Fresh(reservation) = recent.claim(&hi).await else {
+            panic!("the first post was a duplicate");
+        };
+
+        let fail = async {
+            sleep(Duration::from_millis(50)).await;
+            drop(reservation);
+        };
+        let (_, duplicate) = join!(fail, duplicated(&recent, &hi));
+        assert_eq!(duplicate, None);
+    }
+
+    #[test]
+    fn duplicate_posts_are_answered_without_a_broadcast() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let body = json!({ "room": "lobby", "username": "alice", "message": "hi" });
+
+        let first = chat.send(chat.post_json("/message", &body)).json();
+        assert!(first.get("deduped").is_none());
+        let second = chat.send(chat.post_json("/message", &body)).json();
+        assert_eq!(second["deduped"], true);
+        assert_eq!(second["id"], first["id"]);
+
+        let other = json!({ "room": "lobby", "username": "alice", "message": "bye" });
+        assert_eq!(
+            chat.send(chat.post_json("/message", &other)).status,
+            Status::Ok
+        );
+        let bodies: Vec<_> = events
+            .messages(2, WAIT)
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect();
+        assert_eq!(bodies, ["hi", "bye"]);
+    }
+
+    #[test]
+    fn identical_posts_sent_together_are_broadcast_once() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let body = json!({ "room": "lobby", "username": "alice", "message": "hi" });
+
+        let (first, second) = chat.block_on(async {
+            join!(
+                chat.post_json("/message", &body).dispatch(),
+                chat.post_json("/message", &body).dispatch()
+            )
+        });
+        assert_eq!((first.status(), second.status()), (Status::Ok, Status::Ok));
+        let messages = events.messages(2, Duration::from_millis(300));
+        assert_eq!(messages.len(), 1);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::admin::Moderator;
+use crate::auth::SessionToken;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::guards::ClientIp;
+use crate::message::{check_name, Message};
+use crate::rate_limit::RateLimited;
+use rocket::http::Status;
+
+// Endpoint to Delete Messages
+// Turns a stored message into a tombstone and broadcasts a Message of kind Delete, received as a "delete" event on /events
+// -- The author deletes by naming themselves, i.e DELETE /message/12?username=alice
+//    a reserved username still needs its session token and banned users get a 403
+// -- A moderator of the message's room (see Moderator) may delete anyone's message there, without a username
+// -- 403 when the username isn't the author's, 404 when there is no stored message with that id or it was already deleted
+#[delete("/message/<id>?<username>")]
+pub async fn delete(
+    id: u64,
+    username: Option<String>,
+    moderator: Option<Moderator>,
+    _limit: RateLimited,
+    token: SessionToken,
+    ip: ClientIp,
+    chat: Chat<'_>,
+) -> Result<Status, ApiError> {
+    let ClientIp(ip) = ip;
+    let storage = |e: sqlx::Error| {
+        error!("failed to delete message {}: {}", id, e);
+        ApiError::new(Status::InternalServerError, "failed to delete message")
+    };
+    let msg = chat
+        .store
+        .get(id)
+        .await
+        .map_err(storage)?
+        .filter(|msg| !msg.deleted)
+        .ok_or_else(|| ApiError::new(Status::NotFound, format!("no message with id {}", id)))?;
+
+    // Which room the message is in is only known once it's loaded, so is whether the moderator may delete it
+    let moderator = moderator.filter(|m| m.moderates(&msg.room, chat.moderators));
+    if moderator.is_none() {
+        let username = username.as_deref().unwrap_or_default();
+        check_name("username", username)
+            .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+        chat.check_ban(Some(&msg.room), Some(username), ip)?;
+        if !chat.reservations.authorize(username, token.0.as_deref()) {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                format!("username {} is reserved", username),
+            ));
+        }
+        if username != msg.username {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                "only the author of a message or a moderator can delete it",
+            ));
+        }
+    }
+
+    chat.store.delete(id).await.map_err(storage)?;
+    if let Some(moderator) = moderator {
+        info!("{} deleted message {} by {}", moderator, id, msg.username);
+    }
+
+    // The deletion is stored, nobody listening just means nobody to tell right now
+    let _res = chat.queue.send(Message::delete(chat.ids.next(), &msg));
+
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin::ADMIN_TOKEN_HEADER;
+    use crate::message::MessageKind;
+    use crate::testing::{as_admin, TestChat, WAIT};
+    use rocket::http::{Header, Method, Status};
+    use rocket::serde::json::Value;
+
+    fn history(chat: &TestChat) -> Value {
+        chat.send(chat.get("/history?room=lobby")).json()["messages"][0].clone()
+    }
+
+    #[test]
+    fn authors_delete_their_messages_leaving_a_tombstone() {
+        let chat = TestChat::with_admin();
+        let mut events = chat.events("room=lobby");
+        let id = chat.post_id("lobby", "alice", "oops");
+
+        let request = chat.request(Method::Delete, format!("/message/{}?username=alice", id));
+        assert_eq!(chat.send(request).status, Status::NoContent);
+        let messages = events.messages(2, WAIT);
+        assert_eq!(messages[1].kind, MessageKind::Delete);
+        assert_eq!(messages[1].target, Some(id));
+
+        let tombstone = history(&chat);
+        assert_eq!(tombstone["id"], id);
+        assert_eq!(tombstone["deleted"], true);
+        assert_ne!(tombstone["message"], "oops");
+    }
+
+    #[test]
+    fn admins_delete_anyones_message() {
+        let chat = TestChat::with_admin();
+        let _events = chat.events("room=lobby");
+        let id = chat.post_id("lobby", "mallory", "spam");
+
+        let request = as_admin(chat.request(Method::Delete, format!("/message/{}", id)));
+        assert_eq!(chat.send(request).status, Status::NoContent);
+        assert_eq!(history(&chat)["deleted"], true);
+
+        // Already deleted
+        let request = as_admin(chat.request(Method::Delete, format!("/message/{}", id)));
+        assert_eq!(chat.send(request).status, Status::NotFound);
+    }
+
+    #[test]
+    fn others_cant_delete_a_message() {
+        let chat = TestChat::with_admin();
+        let _events = chat.events("room=lobby");
+        let id = chat.post_id("lobby", "alice", "mine");
+
+        let request = chat.request(Method::Delete, format!("/message/{}?username=mallory", id));
+        assert_eq!(chat.send(request).status, Status::Forbidden);
+        let request = chat
+            .request(Method::Delete, format!("/message/{}?username=mallory", id))
+            .header(Header::new(ADMIN_TOKEN_HEADER, "guess"));
+        assert_eq!(chat.send(request).status, Status::Forbidden);
+        assert_eq!(history(&chat)["message"], "mine");
+    }
+}
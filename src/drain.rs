@@ -0,0 +1,100 @@
+use crate::config::ChatConfig;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Managed flag raised as soon as the server starts shutting down, by the Drain fairing
+// From then on posts are turned down with a 503, while /events streams keep delivering for shutdown_drain_ms (see events)
+#[derive(Clone, Default)]
+pub struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Fairing raising the Draining flag when shutdown is requested
+// On liftoff, spawns a task waiting for Rocket's Shutdown future
+pub struct Drain;
+
+#[rocket::async_trait]
+impl Fairing for Drain {
+    fn info(&self) -> Info {
+        Info {
+            name: "Shutdown Drain",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (Some(config), Some(draining)) =
+            (rocket.state::<ChatConfig>(), rocket.state::<Draining>())
+        else {
+            return;
+        };
+
+        // Rocket only waits shutdown.grace seconds for connections before cutting them off, a longer drain would be cut short
+        let grace = u64::from(rocket.config().shutdown.grace) * 1000;
+        if config.shutdown_drain_ms >= grace {
+            warn!(
+                "shutdown_drain_ms ({}) isn't shorter than Rocket's shutdown.grace ({}s), streams may be cut off before they drain",
+                config.shutdown_drain_ms,
+                rocket.config().shutdown.grace
+            );
+        }
+
+        let draining = draining.clone();
+        let end = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            end.await;
+            draining.0.store(true, Ordering::Relaxed);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+    use rocket::tokio::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn streams_drain_what_was_posted_before_the_shutdown() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.shutdown_drain_ms", 500)));
+        let mut events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "just in time"), Status::Ok);
+        let started = Instant::now();
+        chat.client().rocket().shutdown().notify();
+
+        // The drain task raises the flag as soon as it sees the shutdown
+        std::thread::sleep(Duration::from_millis(50));
+        let reply = chat.send(chat.post_json(
+            "/message",
+            &json!({ "room": "lobby", "username": "alice", "message": "too late" }),
+        ));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+        assert_eq!(reply.json()["error"], "server is shutting down");
+
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages[0].message, "just in time");
+        assert!(events.find("shutdown", WAIT).is_some());
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(events.ended(WAIT));
+    }
+
+    #[test]
+    fn without_a_drain_streams_end_right_away() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let started = Instant::now();
+        chat.client().rocket().shutdown().notify();
+
+        assert!(events.find("shutdown", WAIT).is_some());
+        assert!(events.ended(WAIT));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
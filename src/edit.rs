@@ -0,0 +1,140 @@
+use crate::auth::SessionToken;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::guards::ClientIp;
+use crate::message::{max_graphemes, now_millis, safe_name, Message, MAX_USERNAME_LEN};
+use crate::rate_limit::RateLimited;
+use rocket::form::{self, Form};
+use rocket::http::Status;
+use rocket::serde::Deserialize;
+
+// Form data accepted by PUT /message/<id>, who is editing and the new body
+#[derive(Debug, FromForm, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EditForm {
+    #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    pub username: String,
+    pub message: String,
+}
+
+// Endpoint to Edit Messages
+// Replaces the body of a stored message and broadcasts a Message of kind Edit, received as an "edit" event on /events
+// -- The new body goes through the same checks and pipeline as a new message (see Chat::prepare), and is rendered again if the original was
+// -- Only the author may edit, a reserved username still needs its session token and banned users get a 403
+// -- 404 when there is no stored message with that id, or it was deleted
+#[put("/message/<id>", data = "<form>")]
+pub async fn edit(
+    id: u64,
+    _limit: RateLimited,
+    form: Result<Form<EditForm>, form::Errors<'_>>,
+    token: SessionToken,
+    ip: ClientIp,
+    chat: Chat<'_>,
+) -> Result<Status, ApiError> {
+    let ClientIp(ip) = ip;
+    let EditForm { username, message } = form.map_err(ApiError::from_form)?.into_inner();
+    let username = username.trim();
+    if message.trim().is_empty() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "message can't be empty",
+        ));
+    }
+    chat.check_ban(None, Some(username), ip)?;
+    if !chat.reservations.authorize(username, token.0.as_deref()) {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            format!("username {} is reserved", username),
+        ));
+    }
+
+    let storage = |e: sqlx::Error| {
+        error!("failed to edit message {}: {}", id, e);
+        ApiError::new(Status::InternalServerError, "failed to edit message")
+    };
+    let mut msg = chat
+        .store
+        .get(id)
+        .await
+        .map_err(storage)?
+        .filter(|msg| !msg.deleted)
+        .ok_or_else(|| ApiError::new(Status::NotFound, format!("no message with id {}", id)))?;
+    if msg.username != username {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            "only the author of a message can edit it",
+        ));
+    }
+    chat.check_ban(Some(&msg.room), Some(username), ip)?;
+
+    let draft = chat.prepare(message, msg.html.is_some())?;
+    msg.message = draft.message;
+    msg.html = draft.html;
+    msg.edited_at = Some(now_millis());
+    msg.signature = None;
+    chat.store.edit(&msg).await.map_err(storage)?;
+
+    // The edit is stored, nobody listening just means nobody to tell right now
+    let _res = chat.queue.send(Message::edit(chat.ids.next(), &msg));
+
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::MessageKind;
+    use crate::testing::{TestChat, WAIT};
+    use rocket::http::{ContentType, Method, Status};
+
+    fn edit(chat: &TestChat, id: u64, form: &str) -> Status {
+        let request = chat
+            .request(Method::Put, format!("/message/{}", id))
+            .header(ContentType::Form)
+            .body(form);
+        chat.send(request).status
+    }
+
+    #[test]
+    fn authors_edit_their_messages() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let id = chat.post_id("lobby", "alice", "helo");
+
+        assert_eq!(
+            edit(&chat, id, "username=alice&message=hello"),
+            Status::NoContent
+        );
+        let messages = events.messages(2, WAIT);
+        assert_eq!(messages[1].kind, MessageKind::Edit);
+        assert_eq!(messages[1].target, Some(id));
+        assert_eq!(messages[1].message, "hello");
+
+        let history = chat.send(chat.get("/history?room=lobby")).json();
+        assert_eq!(history["messages"][0]["message"], "hello");
+        assert!(history["messages"][0]["edited_at"].is_u64());
+    }
+
+    #[test]
+    fn others_cant_edit_a_message() {
+        let chat = TestChat::new();
+        let _events = chat.events("room=lobby");
+        let id = chat.post_id("lobby", "alice", "mine");
+
+        assert_eq!(
+            edit(&chat, id, "username=mallory&message=yours"),
+            Status::Forbidden
+        );
+        let history = chat.send(chat.get("/history?room=lobby")).json();
+        assert_eq!(history["messages"][0]["message"], "mine");
+    }
+
+    #[test]
+    fn editing_a_missing_message_gets_404() {
+        let chat = TestChat::new();
+        assert_eq!(
+            edit(&chat, 42, "username=alice&message=hello"),
+            Status::NotFound
+        );
+    }
+}
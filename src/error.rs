@@ -0,0 +1,171 @@
+use crate::config::ChatConfig;
+use rocket::form;
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{self, Json};
+use rocket::serde::Serialize;
+use rocket::Request;
+use std::io;
+
+// Body of every error response, i.e {"error":"message can't be empty","code":422}
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ErrorBody {
+    error: String,
+    code: u16,
+}
+
+// Error returned by handlers, responds with `status` and the JSON ErrorBody
+// -- retry_after -> Seconds the client should wait before trying again, sent as a Retry-After header
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(status: Status, message: M) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> ApiError {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    // The request body was larger than the max_body_size of `max` bytes
+    pub fn payload_too_large(max: u64) -> ApiError {
+        ApiError::new(
+            Status::PayloadTooLarge,
+            format!("request body must be at most {} bytes", max),
+        )
+    }
+
+    // Form parsing or validation failed, every failing field is listed i.e "room: length must be less than 30"
+    pub fn from_form(errors: form::Errors<'_>) -> ApiError {
+        let message = errors
+            .iter()
+            .map(|e| match &e.name {
+                Some(name) => format!("{}: {}", name, e.kind),
+                None => e.kind.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        ApiError::new(errors.status(), message)
+    }
+
+    // The ErrorBody as JSON, for transports without responses like the frames of /ws
+    pub fn to_json(&self) -> String {
+        let body = ErrorBody {
+            error: self.message.clone(),
+            code: self.status.code,
+        };
+        json::to_string(&body).expect("error bodies serialize to json")
+    }
+
+    // Parsing a JSON body failed
+    // -- 413 when it was cut off at the max_body_size of `max` bytes, 422 otherwise
+    pub fn from_json(error: json::Error<'_>, max: u64) -> ApiError {
+        match error {
+            json::Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                ApiError::payload_too_large(max)
+            }
+            e => ApiError::new(Status::UnprocessableEntity, e.to_string()),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = ErrorBody {
+            error: self.message,
+            code: self.status.code,
+        };
+
+        let mut response = Response::build_from(Json(body).respond_to(req)?);
+        response.status(self.status);
+        if let Some(secs) = self.retry_after {
+            response.header(Header::new("Retry-After", secs.to_string()));
+        }
+
+        response.ok()
+    }
+}
+
+// Catcher for bodies over the max_body_size that a guard rejected before any handler ran
+#[catch(413)]
+pub fn payload_too_large(req: &Request<'_>) -> ApiError {
+    match req.rocket().state::<ChatConfig>() {
+        Some(config) => ApiError::payload_too_large(config.max_body_size),
+        None => ApiError::new(Status::PayloadTooLarge, "request body is too large"),
+    }
+}
+
+// Catcher for every error no handler answered itself (unknown routes, failing guards...)
+// Keeps those in the same JSON shape, using the standard reason as the message
+#[catch(default)]
+pub fn default_catcher(status: Status, _req: &Request<'_>) -> ApiError {
+    ApiError::new(status, status.reason().unwrap_or("unknown error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestChat;
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn validation_failures_get_the_json_error_shape() {
+        let chat = TestChat::new();
+        let _events = chat.events("room=lobby");
+        let reply = chat.send(chat.post_form("/message", "room=lobby&username=alice"));
+
+        assert_eq!(reply.status, Status::UnprocessableEntity);
+        let body = reply.json();
+        assert_eq!(body["code"], 422);
+        assert!(body["error"].as_str().is_some_and(|e| !e.is_empty()));
+        assert_eq!(body.as_object().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn catchers_answer_in_json_too() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.get("/nowhere"));
+        assert_eq!(reply.status, Status::NotFound);
+        assert_eq!(reply.json(), json!({ "error": "Not Found", "code": 404 }));
+    }
+
+    #[test]
+    fn posts_nobody_receives_get_a_json_503() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.post_form("/message", "room=lobby&username=alice&message=hi"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+        assert_eq!(reply.json()["code"], 503);
+    }
+
+    #[test]
+    fn bodies_over_max_body_size_get_413_with_the_limit() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.max_body_size", 100)));
+        let _events = chat.events("room=lobby");
+        let expected = json!({ "error": "request body must be at most 100 bytes", "code": 413 });
+
+        let form = format!("room=lobby&username=alice&message={}", "a".repeat(200));
+        let reply = chat.send(chat.post_form("/message", &form));
+        assert_eq!(reply.status, Status::PayloadTooLarge);
+        assert_eq!(reply.json(), expected);
+
+        let body = json!({ "room": "lobby", "username": "alice", "message": "a".repeat(200) });
+        let reply = chat.send(chat.post_json("/message", &body));
+        assert_eq!(reply.status, Status::PayloadTooLarge);
+        assert_eq!(reply.json(), expected);
+
+        let reply = chat.send(chat.post_form("/message", "room=lobby&username=alice&message=hi"));
+        assert_eq!(reply.status, Status::Ok);
+    }
+}
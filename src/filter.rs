@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+// Censors blacklisted words in message bodies before they are broadcast
+// -- Matching is case-insensitive and only on whole words, so "class" survives a blacklisted "ass"
+// -- A censored word is replaced by one asterisk per character
+pub struct WordFilter {
+    words: HashSet<String>,
+}
+
+impl WordFilter {
+    pub fn new<I: IntoIterator<Item = String>>(words: I) -> WordFilter {
+        WordFilter {
+            words: words
+                .into_iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+
+    // Blacklist from the inline `words` plus, if given, a file with one word per line
+    pub fn load(words: &[String], file: Option<&str>) -> io::Result<WordFilter> {
+        let mut all = words.to_vec();
+        if let Some(file) = file {
+            all.extend(fs::read_to_string(file)?.lines().map(String::from));
+        }
+
+        Ok(WordFilter::new(all))
+    }
+
+    pub fn censor(&self, text: &str) -> String {
+        if self.words.is_empty() {
+            return text.to_string();
+        }
+
+        let mut censored = String::with_capacity(text.len());
+        let mut word = String::new();
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                self.push_word(&mut censored, &word);
+                word.clear();
+                censored.push(c);
+            }
+        }
+        self.push_word(&mut censored, &word);
+
+        censored
+    }
+
+    fn push_word(&self, out: &mut String, word: &str) {
+        if self.words.contains(&word.to_lowercase()) {
+            out.extend(word.chars().map(|_| '*'));
+        } else {
+            out.push_str(word);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> WordFilter {
+        WordFilter::new(["ass".to_string(), " Darn ".to_string()])
+    }
+
+    #[test]
+    fn clean_messages_are_left_alone() {
+        assert_eq!(filter().censor("hello there"), "hello there");
+        assert_eq!(WordFilter::new(Vec::new()).censor("ass"), "ass");
+    }
+
+    #[test]
+    fn blacklisted_words_are_starred_ignoring_case() {
+        assert_eq!(filter().censor("ass"), "***");
+        assert_eq!(filter().censor("what an ASS, darn"), "what an ***, ****");
+        assert_eq!(filter().censor("Darn!"), "****!");
+    }
+
+    #[test]
+    fn words_containing_a_blacklisted_one_are_kept() {
+        assert_eq!(filter().censor("class assessment"), "class assessment");
+        assert_eq!(filter().censor("darned"), "darned");
+    }
+
+    #[test]
+    fn the_blacklist_file_adds_to_the_inline_words() {
+        let path = std::env::temp_dir().join(format!("blacklist-{}.txt", rand::random::<u64>()));
+        fs::write(&path, "heck\n\n  golly \n").unwrap();
+        let filter = WordFilter::load(&["darn".into()], path.to_str()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(filter.censor("heck, golly, darn"), "****, *****, ****");
+        assert!(WordFilter::load(&[], Some("/nonexistent/blacklist")).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::ChatConfig;
+use rocket::request::{FromRequest, Outcome, Request};
+use std::net::IpAddr;
+
+// Request guard reading the `Last-Event-ID` header an EventSource sends when it reconnects
+// Holds the id of the last message the client saw, or None when the header is missing or isn't a number
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = req
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| id.trim().parse().ok());
+
+        Outcome::Success(LastEventId(id))
+    }
+}
+
+// Longest User-Agent, in characters, kept by UserAgent
+pub const MAX_USER_AGENT_LEN: usize = 256;
+
+// Request guard reading the client's `User-Agent` header, for moderators to see what connected users run (see /admin/users)
+// Control characters are stripped and it's cut off at MAX_USER_AGENT_LEN, None when the header is missing or blank
+pub struct UserAgent(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let agent = req.headers().get_one("User-Agent").map(|agent| {
+            agent
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_USER_AGENT_LEN)
+                .collect::<String>()
+        });
+
+        Outcome::Success(UserAgent(agent.filter(|agent| !agent.trim().is_empty())))
+    }
+}
+
+// Request guard for the IP of the client, which rate limits, bans and connection caps go by
+// -- with trust_forwarded, the first address of the X-Forwarded-For header added by a reverse proxy, when it has one
+// -- otherwise Rocket's client IP: the address in its ip_header (X-Real-IP unless configured otherwise), or the socket peer
+// Trusting the header is opt-in since outside of a proxy anybody can send one, and pick an IP that isn't banned or rate limited
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn of(req: &Request<'_>) -> Option<IpAddr> {
+        let trusted = req
+            .rocket()
+            .state::<ChatConfig>()
+            .is_some_and(|config| config.trust_forwarded);
+        let forwarded = req
+            .headers()
+            .get_one("X-Forwarded-For")
+            .and_then(|ips| ips.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+
+        match forwarded {
+            Some(ip) if trusted => Some(ip),
+            _ => req.client_ip(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp(ClientIp::of(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{admin_token, as_admin, TestChat, LOCAL_IP};
+    use rocket::http::{Header, Status};
+    use rocket::serde::json::json;
+
+    const PROXIED: &str = "203.0.113.7";
+
+    fn client_ip(chat: &TestChat, forwarded: &str) -> Option<IpAddr> {
+        let request = chat
+            .get("/")
+            .header(Header::new("X-Forwarded-For", forwarded.to_string()));
+        ClientIp::of(request.inner())
+    }
+
+    #[test]
+    fn forwarded_ips_are_ignored_by_default() {
+        let chat = TestChat::new();
+        assert_eq!(client_ip(&chat, PROXIED), Some(LOCAL_IP));
+    }
+
+    #[test]
+    fn trust_forwarded_takes_the_first_forwarded_ip() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.trust_forwarded", true)));
+        let first = PROXIED.parse().ok();
+        assert_eq!(client_ip(&chat, PROXIED), first);
+        assert_eq!(client_ip(&chat, "203.0.113.7, 10.0.0.1"), first);
+        // A header that isn't an address falls back to the peer
+        assert_eq!(client_ip(&chat, "unknown"), Some(LOCAL_IP));
+    }
+
+    #[test]
+    fn bans_go_by_the_forwarded_ip_when_trusted() {
+        for trusted in [true, false] {
+            let chat = TestChat::configured(|figment| {
+                admin_token(figment).merge(("chat.trust_forwarded", trusted))
+            });
+            let _events = chat.events("room=lobby");
+            let ban = as_admin(chat.post_json("/admin/ban", &json!({ "ip": PROXIED })));
+            assert_eq!(chat.send(ban).status, Status::NoContent);
+
+            let body = json!({ "room": "lobby", "username": "alice", "message": "hi" });
+            let request = chat
+                .post_json("/message", &body)
+                .header(Header::new("X-Forwarded-For", PROXIED));
+            let expected = if trusted {
+                Status::Forbidden
+            } else {
+                Status::Ok
+            };
+            assert_eq!(chat.send(request).status, expected, "{}", trusted);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::channels::Channels;
+use crate::store::Store;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+// Body returned by the probes, i.e {"status":"ok"}
+// A failing probe also says why
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Health {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Health {
+    fn ok() -> Json<Health> {
+        Json(Health {
+            status: "ok",
+            reason: None,
+        })
+    }
+}
+
+// Liveness probe, answers as long as Rocket is serving requests
+#[get("/healthz")]
+pub fn healthz() -> Json<Health> {
+    Health::ok()
+}
+
+// Readiness probe, also checks the broadcast channels and the message store can be used
+// Answers 503 when the store can't be reached
+#[get("/readyz")]
+pub async fn readyz(
+    store: &State<Store>,
+    queue: &State<Channels>,
+) -> Result<Json<Health>, Custom<Json<Health>>> {
+    // Only has to be callable, zero receivers is fine
+    let _subscribers = queue.receiver_count();
+
+    if let Err(e) = store.ping().await {
+        return Err(Custom(
+            Status::ServiceUnavailable,
+            Json(Health {
+                status: "unavailable",
+                reason: Some(format!("message store unreachable: {}", e)),
+            }),
+        ));
+    }
+
+    Ok(Health::ok())
+}
@@ -0,0 +1,31 @@
+use rand::Rng;
+
+// Managed id of this server instance, stamped on every chat message it accepts as `origin_instance`
+// Tells which replica handled a message when several run behind a load balancer or share a Redis relay
+// -- the configured instance_id when set, otherwise a random UUID picked at launch
+pub struct InstanceId(pub String);
+
+impl InstanceId {
+    pub fn new(configured: Option<String>) -> InstanceId {
+        match configured.filter(|id| !id.trim().is_empty()) {
+            Some(id) => InstanceId(id),
+            None => InstanceId(random_uuid()),
+        }
+    }
+}
+
+// A version 4 UUID, i.e "3f1c9a2e-7b4d-4e8a-9c1f-52d6b0e4a7c3"
+fn random_uuid() -> String {
+    // Set the version (4) and variant (10xx) bits, the rest stays random
+    let bits =
+        rand::thread_rng().gen::<u128>() & !(0xf << 76) & !(0x3 << 62) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
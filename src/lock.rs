@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// Managed set of the rooms locked by moderators through /admin/lock
+// Only moderators may post to a locked room (i.e an announcements channel), everyone can still read it
+// Locks only live in memory, every room is unlocked again when the server restarts
+#[derive(Default)]
+pub struct RoomLocks(Mutex<HashSet<String>>);
+
+impl RoomLocks {
+    pub fn set(&self, room: &str, locked: bool) {
+        let mut rooms = self.0.lock().unwrap();
+        if locked {
+            rooms.insert(room.to_string());
+        } else {
+            rooms.remove(room);
+        }
+    }
+
+    pub fn is_locked(&self, room: &str) -> bool {
+        self.0.lock().unwrap().contains(room)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{Reservations, TOKEN_HEADER};
+    use crate::moderators::RoomModerators;
+    use crate::testing::{as_admin, TestChat, WAIT};
+    use rocket::http::{Header, Status};
+    use rocket::serde::json::{json, Value};
+
+    fn lock(chat: &TestChat, room: &str, locked: bool) -> Status {
+        let request =
+            as_admin(chat.post_json("/admin/lock", &json!({ "room": room, "locked": locked })));
+        chat.send(request).status
+    }
+
+    fn body(username: &str, message: &str) -> Value {
+        json!({ "room": "news", "username": username, "message": message })
+    }
+
+    #[test]
+    fn locked_rooms_only_take_posts_from_moderators() {
+        let chat = TestChat::with_admin();
+        let mut events = chat.events("room=news");
+        assert_eq!(lock(&chat, "News", true), Status::NoContent);
+
+        let reply = chat.send(chat.post_json("/message", &body("alice", "hi")));
+        assert_eq!(reply.status, Status::Forbidden);
+        assert_eq!(
+            reply.json()["error"],
+            "news is read-only, only moderators can post to it"
+        );
+        let request = as_admin(chat.post_json("/message", &body("admin", "read this")));
+        assert_eq!(chat.send(request).status, Status::Ok);
+
+        // A moderator of the room posts with its session token
+        let token = chat
+            .client()
+            .rocket()
+            .state::<Reservations>()
+            .unwrap()
+            .reserve("carol")
+            .unwrap();
+        let moderators = chat.client().rocket().state::<RoomModerators>().unwrap();
+        moderators.set("news", "carol", true);
+        let request = chat
+            .post_json("/message", &body("carol", "and this"))
+            .header(Header::new(TOKEN_HEADER, token));
+        assert_eq!(chat.send(request).status, Status::Ok);
+
+        // Subscribers still get what moderators post
+        let bodies: Vec<_> = events
+            .messages(2, WAIT)
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect();
+        assert_eq!(bodies, ["read this", "and this"]);
+
+        assert_eq!(lock(&chat, "news", false), Status::NoContent);
+        assert_eq!(chat.post("news", "alice", "hi"), Status::Ok);
+    }
+
+    #[test]
+    fn locking_takes_a_moderator() {
+        let chat = TestChat::with_admin();
+        let body = json!({ "room": "news", "locked": true });
+        assert_eq!(
+            chat.send(chat.post_json("/admin/lock", &body)).status,
+            Status::Forbidden
+        );
+        assert_eq!(lock(&chat, "a/b", true), Status::UnprocessableEntity);
+    }
+}
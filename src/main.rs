@@ -3,48 +3,266 @@
 #[macro_use]
 extern crate rocket;
 
-use rocket::form::Form;
+mod access_log;
+mod admin;
+mod auth;
+mod bans;
+mod batch;
+mod bridge;
+mod channels;
+mod chat;
+mod cipher;
+mod compression;
+mod config;
+mod connections;
+mod cors;
+mod dedupe;
+mod delete;
+mod drain;
+mod edit;
+mod error;
+mod filter;
+mod guards;
+mod health;
+mod instance;
+mod lock;
+mod markdown;
+mod message;
+mod metrics;
+mod moderators;
+mod motd;
+mod mute;
+mod openapi;
+mod pipeline;
+mod presence;
+mod queries;
+mod rate_limit;
+mod reaction;
+#[cfg(feature = "redis")]
+mod relay;
+mod retention;
+mod rooms;
+mod signature;
+mod slow_mode;
+mod store;
+mod templates;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+mod typing;
+mod version;
+mod ws;
+
+use access_log::{AccessLog, Posted};
+use admin::Moderator;
+use auth::{ApiKey, Reservations, SessionToken, User};
+use bans::{Bans, BAN_CHECK_INTERVAL};
+use channels::Channels;
+use chat::Chat;
+use cipher::BodyCipher;
+use config::ChatConfig;
+use connections::{Connection, ConnectionLimit};
+use cors::Cors;
+use dedupe::RecentPosts;
+use drain::Draining;
+use error::ApiError;
+use filter::WordFilter;
+use guards::{ClientIp, LastEventId, UserAgent};
+use instance::InstanceId;
+use lock::RoomLocks;
+use message::MessageKind;
+use message::{
+    check_name, normalize_room, Message, MessageForm, MessageIds, PostResponse, RoomSequences,
+};
+use metrics::{ExitReason, Metrics};
+use moderators::RoomModerators;
+use motd::Motd;
+use mute::Mutes;
+use presence::{Presence, RoomCaps};
+use queries::{QueryLimit, QuerySlot};
+use rate_limit::{Messages, RateLimited, RateLimiter, Typing, UsernameLimiter};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::form::{self, Form};
 use rocket::fs::{relative, FileServer};
+use rocket::http::Status;
 use rocket::response::stream::{Event, EventStream};
-use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::{self, Json};
+use rocket::serde::Serialize;
 use rocket::tokio::select;
-use rocket::tokio::sync::broadcast::{channel, error::RecvError, Sender};
-use rocket::{Shutdown, State};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::time::{interval_at, sleep, Duration, Instant};
+use rocket::{Build, Rocket, Shutdown, State};
+use rooms::RoomRegistry;
+use signature::Signer;
+use slow_mode::SlowMode;
+use std::path::Path;
+use store::Store;
+
+// Body returned by /world, i.e {"pong":3600}
+// -- pong -> seconds since the server launched
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Pong {
+    pong: u64,
+}
 
 // Defines a /world route and how it handles a get request
-// -- No arguements, just returns a string slice = "Hello World!"
+// -- A lightweight liveness ping, answering with the uptime kept by the Metrics, lighter still than /healthz
 #[get("/world")]
-fn world() -> &'static str {
-    "Hello World!"
-}
-
-// This struct defines the format of our messages which will be passed in our channel
-// 3 fields with some validations
-// Derives a few traits
-// -- Debug -> Can output in debug format
-// -- Clone -> Can duplicate messages
-// -- FromForm -> Can take form data and translate to a message struct
-// -- Serialize
-// -- Deserialize
-#[derive(Debug, Clone, FromForm, Serialize, Deserialize)]
-#[serde(crate = "rocket::serde")] // Serialize and Deserialize via serde (Defined in Rocket)
-struct Message {
-    #[field(validate = len(..30))]
-    pub room: String,
-    #[field(validate = len(..20))]
-    pub username: String,
-    pub message: String,
+fn world(metrics: &State<Metrics>) -> Json<Pong> {
+    Json(Pong {
+        pong: metrics.uptime().as_secs(),
+    })
 }
 
 // Endpoint to Send Messages
 // This endpoint will respond to post requests at /message and accepts form data
-// The handler accepts two arguements, form data contiaining the message and the Sender
+// The handler accepts form data contiaining the message, the signed in User, the session token, the client's IP, the access log record and the Chat state
+// -- The published message is recorded in Posted so the AccessLog fairing can log it
+// -- The RateLimited guard runs first and answers 429 when the client is posting too fast
+// -- The ApiKey guard answers 401 when an api_key is configured and the client didn't present it
+// -- Moderators of the room (see Moderator) may post to it when it is locked
+// -- A browser signed in through /session posts as its User, whatever username the form gives
 // Rocket will automatically convert the response into an HTTP response (response will depend on the Responder trait implementation)
-// -- In this case, Result is a primitive type which implements the Responder trait
-#[post("/message", data = "<form>")]
-fn post(form: Form<Message>, queue: &State<Sender<Message>>) {
-    // Send fails if there are no active subscribers
-    let _res = queue.send(form.into_inner());
+// -- In this case, Result is a type which implements the Responder trait
+// -- Ok carries the assigned id as Json, flagged as deduped when the post repeated the user's last one (see RecentPosts)
+// -- Err is an ApiError, a status with a JSON body giving the reason (see Chat::submit), 422 for invalid fields or 413 for a body over max_body_size
+// Ranked after post_json, which takes the requests sending JSON instead
+#[post("/message", data = "<form>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+async fn post(
+    _limit: RateLimited,
+    key: ApiKey,
+    moderator: Option<Moderator>,
+    form: Result<Form<MessageForm>, form::Errors<'_>>,
+    user: Option<User>,
+    token: SessionToken,
+    ip: ClientIp,
+    posted: &Posted,
+    chat: Chat<'_>,
+) -> Result<Json<PostResponse>, ApiError> {
+    let ClientIp(ip) = ip;
+    let mut form = form
+        .map_err(|errors| {
+            if errors.status() == Status::PayloadTooLarge {
+                ApiError::payload_too_large(chat.config.max_body_size)
+            } else {
+                ApiError::from_form(errors)
+            }
+        })?
+        .into_inner();
+    if let Some(User(username)) = user {
+        form.username = username;
+    }
+    let submitted = chat
+        .submit(form, token.0.as_deref(), key, moderator.as_ref(), ip)
+        .await?;
+    posted.record(&submitted.message);
+
+    Ok(Json(PostResponse::from(submitted)))
+}
+
+// Endpoint to Send Messages as JSON
+// Same as post for clients sending a `Content-Type: application/json` body, i.e {"room":"lobby","username":"alice","message":"hi"}
+// -- JSON skips the form field attributes, so MessageForm::validate runs the same checks
+#[post("/message", format = "json", data = "<json>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn post_json(
+    _limit: RateLimited,
+    key: ApiKey,
+    moderator: Option<Moderator>,
+    json: Result<Json<MessageForm>, json::Error<'_>>,
+    user: Option<User>,
+    token: SessionToken,
+    ip: ClientIp,
+    posted: &Posted,
+    chat: Chat<'_>,
+) -> Result<Json<PostResponse>, ApiError> {
+    let ClientIp(ip) = ip;
+    let form = json.map_err(|e| ApiError::from_json(e, chat.config.max_body_size))?;
+    let mut form = form.into_inner();
+    if let Some(User(username)) = user {
+        form.username = username;
+    }
+    form.validate()
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let submitted = chat
+        .submit(form, token.0.as_deref(), key, moderator.as_ref(), ip)
+        .await?;
+    posted.record(&submitted.message);
+
+    Ok(Json(PostResponse::from(submitted)))
+}
+
+// Query string accepted by /events, every parameter is optional
+// -- room -> only stream messages of this room (i.e /events?room=lobby), when omitted every room is streamed
+// -- username -> marks the user as present in the room for /users (i.e /events?room=lobby&username=alice)
+//    and receives the direct messages sent to or by that user
+// -- iso -> adds an RFC 3339 timestamp_iso to every message (i.e /events?iso=true), only the unix millis are sent otherwise
+// -- format -> what events carry, see Payload (i.e /events?format=text)
+// -- keywords -> comma separated words, only chat messages containing one of them are streamed (i.e /events?keywords=alice,deploy)
+//    compared ignoring case, every other kind of message (announcements, typing...) is still streamed
+// -- batch -> chat messages arriving together are sent as one "messages" event carrying a JSON array (i.e /events?batch=true)
+//    up to max_event_batch of them, only with the json format
+// -- system_only -> only announcements are streamed (joins, leaves, room closures...), no chat, for monitoring (i.e /events?system_only=true)
+//    so the history isn't replayed either, the motd and the stream's own events (ping, stats, shutdown...) are still sent
+#[derive(Debug, FromForm)]
+struct EventsQuery {
+    room: Option<String>,
+    username: Option<String>,
+    iso: bool,
+    keywords: Option<String>,
+    batch: bool,
+    system_only: bool,
+    #[field(default = Payload::Json)]
+    format: Payload,
+}
+
+// What the data of an /events event holds
+// -- Json -> the whole Message as JSON, the default
+// -- Text -> only the message body, for minimal clients without a JSON parser
+//    the event name still tells what kind of message it is (i.e a "reaction" carries the emoji)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, FromFormField)]
+enum Payload {
+    #[default]
+    #[field(value = "json")]
+    Json,
+    #[field(value = "text")]
+    Text,
+}
+
+impl Payload {
+    fn of(self, msg: &Message) -> Event {
+        match self {
+            Payload::Json => Event::json(msg),
+            Payload::Text => Event::data(msg.message.clone()),
+        }
+    }
+}
+
+// Data of the "stats" event /events sends every stats_interval seconds, for clients showing how many are online
+// -- room -> the room the stream listens to, None when it listens to every room
+// -- subscribers -> streams and sockets listening to that room (to every room when None), this one included
+// -- total -> streams and sockets listening to any room on this instance
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct StreamStats<'a> {
+    room: Option<&'a str>,
+    subscribers: usize,
+    total: usize,
+}
+
+impl StreamStats<'_> {
+    // The "stats" event, carrying only the subscriber count for Text payloads
+    fn event(&self, payload: Payload) -> Event {
+        let event = match payload {
+            Payload::Json => Event::json(self),
+            Payload::Text => Event::data(self.subscribers.to_string()),
+        };
+        event.event("stats")
+    }
 }
 
 // Endpoint to Recieve Messages
@@ -52,34 +270,502 @@ fn post(form: Form<Message>, queue: &State<Sender<Message>>) {
 // -- Essentially will be pulling from a stream of events posted to the server by our other route
 // Return Type is of type EventStream which is essentially a Stream that can get opened and listened to by a client
 // -- Similar to WebSockets, except it is uni-directional (client cannot send data back to stream/server)
-// Two arguements, queue and Shutdown
+// Arguements are the connection slot, the query parameters, the signed in User, the Last-Event-ID header, the session token, the client's IP and User-Agent, the Chat state and Shutdown
+// -- the Connection guard runs first and answers 503 once max_sse_connections streams are open, 429 once the IP has max_sse_connections_per_ip of them
+// -- a banned username or IP gets a 403, and the stream ends if the user gets banned while connected
+// -- joining a room that already has its max_users_per_room users present gets a 503, so does a new room once max_rooms are in use
+// -- the stream ends with a "timeout" event after max_stream_duration seconds, when configured
+// -- query holds the optional parameters described on EventsQuery, a room or username that isn't a safe name (or an unknown format) gets a 400 and no stream
+// -- a browser signed in through /session listens as its User, whatever username the query gives
+// -- Last-Event-ID is sent by browsers when they reconnect, so only what they missed gets replayed
 // -- Shutdown is a "future" which resolves when server shutsdown ("Futures" in Rust are Promises in JavaScript)
-#[get("/events")]
-async fn events(queue: &State<Sender<Message>>, mut end: Shutdown) -> EventStream![] {
+// -- once it resolves the stream keeps delivering messages for shutdown_drain_ms, then ends with a "shutdown" event
+#[get("/events?<query..>")]
+#[allow(clippy::too_many_arguments)]
+async fn events<'r>(
+    connection: Connection,
+    query: Result<EventsQuery, form::Errors<'r>>,
+    user: Option<User>,
+    last_event_id: LastEventId,
+    token: SessionToken,
+    ip: ClientIp,
+    agent: UserAgent,
+    chat: Chat<'r>,
+    mut end: Shutdown,
+) -> Result<EventStream![Event + 'r], ApiError> {
+    let ClientIp(ip) = ip;
+    let EventsQuery {
+        room,
+        username,
+        iso,
+        format,
+        keywords,
+        batch,
+        system_only,
+    } = query.map_err(|errors| ApiError {
+        status: Status::BadRequest,
+        ..ApiError::from_form(errors)
+    })?;
+    let username = user.map(|User(username)| username).or(username);
+    ws::check_query(room.as_deref(), username.as_deref())?;
+    let room = room.as_deref().map(normalize_room);
+    let keywords = parse_keywords(keywords.as_deref());
+    chat.check_ban(room.as_deref(), username.as_deref(), ip)?;
+    if let Some(room) = &room {
+        chat.check_room(room)?;
+    }
+    let Chat {
+        config,
+        reservations,
+        bans,
+        ids,
+        presence,
+        room_caps,
+        metrics,
+        queue,
+        access_log,
+        mutes,
+        motd,
+        ..
+    } = chat;
+
+    // Listening as a reserved username (and so reading its direct messages) takes its session token
+    // Without it the client is treated as anonymous
+    let claimed = username.clone();
+    let username = username.filter(|name| reservations.authorize(name, token.0.as_deref()));
+
     // Create new reciever to listen to stream of messages
-    let mut rx = queue.subscribe();
+    // Subscribing before reading history means nothing posted in between is missed
+    // With max_buffered_events it's read ahead into a bounded queue, so a client that stops reading lets go of the channel (see Feed)
+    let max_buffered = config.max_buffered_events;
+    let mut rx = queue
+        .subscribe(room.as_deref(), username.as_deref())
+        .buffered(max_buffered);
+
+    // Load what the client hasn't seen yet, see Chat::backlog
+    let history = chat
+        .backlog(room.as_deref(), username.as_deref(), last_event_id.0)
+        .await;
+
+    // Mark the user as present for as long as the stream lives, announcing the join and later the leave to the room
+    // A room already holding as many users as its cap turns the stream down instead
+    let joined = match (&room, &username) {
+        (Some(room), Some(username)) => {
+            let cap = room_caps.get(room);
+            let joined = presence
+                .join(room, username, agent.0, cap, ids, queue)
+                .map_err(|_| {
+                    ApiError::new(Status::ServiceUnavailable, format!("room {} is full", room))
+                })?;
+            Some(joined)
+        }
+        _ => None,
+    };
+
+    // Greet the client with the message of the day, if there is one
+    // A system message like joins and leaves, but with an id of 0 since only this client gets it and it's never part of the history
+    let motd = motd
+        .get()
+        .map(|motd| Message::system(0, room.as_deref().unwrap_or_default(), motd));
+
+    // Log the stream opening now, and closing with how long it lasted whenever it ends
+    let logged = access_log.stream(room.as_deref(), claimed.as_deref(), ip);
+
+    // Count the subscriber until the stream ends
+    let subscribed = metrics.subscribe();
+    let metrics = metrics.clone();
+
+    // Proxies tend to drop connections that stay idle, so send a ping whenever the stream goes quiet
+    let period = Duration::from_secs(config.heartbeat_interval.max(1));
+    let retry = Duration::from_millis(config.retry_ms);
+    let backoff = Duration::from_millis(config.backoff_retry_ms);
+    let mut heartbeat = interval_at(Instant::now() + period, period);
+
+    // A closed connection is only noticed when writing to it fails, so a quiet stream writes an empty SSE comment now and then
+    // Browsers ignore comments, but the failed write drops the stream, and with it the guards below (the user leaves /users right away)
+    let probe_period = Duration::from_secs(config.disconnect_check_interval.max(1));
+    let probe_on = config.disconnect_check_interval > 0;
+    let mut probe = interval_at(Instant::now() + probe_period, probe_period);
+
+    // Bans are checked by the claimed username, whether or not it was authorized
+    let mut ban_check = interval_at(Instant::now() + BAN_CHECK_INTERVAL, BAN_CHECK_INTERVAL);
+
+    // Busy rooms can gather messages into fewer events, see EventsQuery
+    let max_batch = config.max_event_batch;
+    let batching = batch && format == Payload::Json && max_batch > 1;
+
+    // Streams are closed once they've been open for max_stream_duration seconds, leaving it to the client to reconnect
+    let max_duration = config.max_stream_duration;
+    let mut deadline = Box::pin(sleep(Duration::from_secs(max_duration)));
+
+    // Once shutdown is requested the stream keeps delivering for shutdown_drain_ms before it ends, so what was posted just before isn't lost
+    let drain = Duration::from_millis(config.shutdown_drain_ms);
+    let mut drained = Box::pin(sleep(drain));
+    let mut draining = false;
+
+    // How many are listening is reported every stats_interval seconds, never persisted
+    let stats_period = Duration::from_secs(config.stats_interval.max(1));
+    let stats_on = config.stats_interval > 0;
+    let mut stats = interval_at(Instant::now() + stats_period, stats_period);
 
     // Infinite loop to generate server sent events
-    EventStream! {
+    Ok(EventStream! {
+        // Owned by the stream so they're dropped (the user leaves, stops being counted and frees its slot) however the stream ends
+        // -- that includes Rocket dropping the stream mid-loop when the client went away, no break is needed for them to run
+        let _joined = joined;
+        let mut subscribed = subscribed;
+        let _connection = connection;
+        let _logged = logged;
+
+        // Tell the browser how long to wait before reconnecting if the stream drops
+        yield Event::retry(retry);
+
+        // Replay history first, remembering the newest id so it isn't repeated by the live stream
+        // Every event carries the message id so the browser can report it back as Last-Event-ID
+        let mut last_replayed = last_event_id.0.unwrap_or_default();
+        for msg in history {
+            if system_only
+                || is_muted(mutes, username.as_deref(), &msg)
+                || !mentions(&keywords, &msg)
+            {
+                continue;
+            }
+            let msg = msg.timestamped(iso);
+            last_replayed = msg.id;
+            yield event(&msg, format);
+        }
+
+        if let Some(motd) = motd {
+            let motd = motd.timestamped(iso);
+            yield format.of(&motd).event(motd.kind.event_name());
+        }
+
         // Looping operation
         loop {
             let msg = select! {
                 // Recieve a message from the stream and match it against one of the three possibilities
                 msg = rx.recv() => match msg {
                     Ok(msg) => msg,                         // Proper Message
-                    Err(RecvError::Closed) => break,        // Recieved Error that no more senders exist for stream
-                    Err(RecvError::Lagged(_)) => continue,  // Recieved Error that our reciever lagged too far behind
+                    Err(RecvError::Closed) => {             // Recieved Error that no more senders exist for stream
+                        metrics.closed();
+                        subscribed.exit(ExitReason::ChannelClosed);
+                        break;
+                    }
+                    Err(RecvError::Lagged(n)) => {          // Recieved Error that our reciever lagged too far behind
+                        // The skipped messages are gone from the channel, tell the client how many so it can refetch /history
+                        // The server is struggling to keep up, so this also asks the browser to back off if it has to reconnect
+                        // With max_buffered_events it's the client's queue that overflowed, it's reading too slowly to keep
+                        if max_buffered.is_some() {
+                            yield Event::data("you are reading too slowly").event("slow_consumer").with_retry(backoff);
+                            subscribed.exit(ExitReason::SlowConsumer);
+                            break;
+                        }
+                        metrics.lagged();
+                        yield Event::data(n.to_string()).event("lagged").with_retry(backoff);
+                        continue;
+                    }
+                },
+
+                // Nothing was sent for a whole period, a named event so clients don't mistake it for a chat message
+                _ = heartbeat.tick() => {
+                    yield Event::data("").event("ping");
+                    continue;
+                },
+
+                // Check the client is still there, see disconnect_check_interval
+                _ = probe.tick(), if probe_on => {
+                    yield Event::comment("");
+                    continue;
+                },
+
+                // Time to tell how many are listening, the event carries data so the next ping is a full period away again
+                _ = stats.tick(), if stats_on => {
+                    let stats = StreamStats {
+                        room: room.as_deref(),
+                        subscribers: queue.subscribers(room.as_deref()),
+                        total: queue.receiver_count(),
+                    };
+                    yield stats.event(format);
+                    heartbeat.reset();
+                    continue;
+                },
+
+                // A moderator banned the user since it connected, say why and end the stream
+                _ = ban_check.tick() => {
+                    if bans.is_banned_from(room.as_deref(), claimed.as_deref(), ip) {
+                        yield Event::data("you are banned").event("banned");
+                        subscribed.exit(ExitReason::Banned);
+                        break;
+                    }
+                    continue;
+                },
+
+                // The stream has been open too long, say why and end it, the browser reconnects on its own
+                _ = &mut deadline, if max_duration > 0 => {
+                    yield Event::data("stream timed out, reconnect").event("timeout");
+                    subscribed.exit(ExitReason::IdleTimeout);
+                    break;
                 },
 
                 // Waiting for the Shutdown future to resolve
-                // When it does, break the loop
-                _ = &mut end => break,
+                // When it does, keep streaming what's already been broadcast for shutdown_drain_ms
+                _ = &mut end, if !draining => {
+                    draining = true;
+                    drained.as_mut().reset(Instant::now() + drain);
+                    continue;
+                },
+
+                // Drained, tell the client why the stream is about to end (so it can show it's reconnecting) and break the loop
+                _ = &mut drained, if draining => {
+                    // Every client reconnects at once after a restart, spreading them out a little is the point of the longer retry
+                    yield Event::data("server is shutting down").event("shutdown").with_retry(backoff);
+                    subscribed.exit(ExitReason::Shutdown);
+                    break;
+                },
             };
 
-            // Yield a new event and pass the message we recieved from the Stream
-            yield Event::json(&msg);
+            // With batch, the messages already waiting are taken along with this one, see max_event_batch
+            let mut received = vec![msg];
+            while batching && received.len() < max_batch {
+                match rx.try_recv() {
+                    Some(msg) => received.push(msg),
+                    None => break,
+                }
+            }
+
+            let mut sent = false;
+            let mut chats = Vec::new();
+            for msg in received {
+                // Already sent as part of the replayed history
+                if msg.id <= last_replayed {
+                    continue;
+                }
+
+                // Skip messages meant for other rooms when the client asked for a specific one, and direct messages for someone else
+                if !msg.visible_to(room.as_deref(), username.as_deref()) {
+                    continue;
+                }
+
+                // Skip what users the client muted send, see mute.rs
+                if is_muted(mutes, username.as_deref(), &msg) {
+                    continue;
+                }
+
+                // Skip chat messages without any of the keywords the client asked for
+                if !mentions(&keywords, &msg) {
+                    continue;
+                }
+
+                // Skip everything but announcements (the kinds with priority) when the client only wants those
+                if system_only && !msg.kind.is_priority() {
+                    continue;
+                }
+
+                // Chat messages in a row are gathered into one event, anything else goes out on its own in between
+                let msg = msg.timestamped(iso);
+                if batching && msg.kind == MessageKind::Chat {
+                    chats.push(msg);
+                    continue;
+                }
+                if !chats.is_empty() {
+                    yield batch_event(&std::mem::take(&mut chats), format);
+                }
+
+                // Yield a new event and pass the message we recieved from the Stream
+                yield event(&msg, format);
+                sent = true;
+            }
+            if !chats.is_empty() {
+                yield batch_event(&chats, format);
+                sent = true;
+            }
+
+            // The connection just carried data, so the next ping is a full period away
+            if sent {
+                heartbeat.reset();
+            }
+        }
+    })
+}
+
+// Whether a client listening as `username` muted the author of `msg`
+fn is_muted(mutes: &Mutes, username: Option<&str>, msg: &Message) -> bool {
+    username.is_some_and(|username| mutes.is_muted(username, &msg.username))
+}
+
+// The keywords of /events?keywords=..., lowercased, empty when none were given
+fn parse_keywords(keywords: Option<&str>) -> Vec<String> {
+    keywords
+        .unwrap_or_default()
+        .split(',')
+        .map(|keyword| keyword.trim().to_lowercase())
+        .filter(|keyword| !keyword.is_empty())
+        .collect()
+}
+
+// Whether `msg` should reach a client that asked for `keywords`
+// Only chat messages are filtered, and only when the client gave keywords
+fn mentions(keywords: &[String], msg: &Message) -> bool {
+    if keywords.is_empty() || msg.kind != MessageKind::Chat {
+        return true;
+    }
+    let body = msg.message.to_lowercase();
+    keywords
+        .iter()
+        .any(|keyword| body.contains(keyword.as_str()))
+}
+
+// The SSE event /events sends `msg` as, named after its kind (see MessageKind::event_name) so clients can tell them apart
+// Chat messages carry their id so the browser can report it back as Last-Event-ID, nothing else does
+// -- Only chat messages are stored, so only their ids are known again after a restart: MessageIds then counts on from the stored ones,
+//    and a Last-Event-ID the store never saw (i.e a join's) would hide the next messages, their ids being handed out again
+// -- Replaying system messages, typing notifications, reactions, edits or deletions after a reconnect would be pointless anyway
+fn event(msg: &Message, payload: Payload) -> Event {
+    let event = payload.of(msg).event(msg.kind.event_name());
+    match msg.kind {
+        MessageKind::Chat => event.id(msg.id.to_string()),
+        _ => event,
+    }
+}
+
+// The SSE event /events sends chat messages received together as, with batch set
+// A single message goes out as usual, several as a "messages" event carrying them as a JSON array, with the last one's id
+fn batch_event(msgs: &[Message], payload: Payload) -> Event {
+    match msgs {
+        [msg] => event(msg, payload),
+        [.., last] => Event::json(&msgs).event("messages").id(last.id.to_string()),
+        [] => unreachable!("batches hold at least one message"),
+    }
+}
+
+// A page of /history
+// -- messages -> newest first
+// -- has_more -> whether there are older messages, fetch them by passing `before` = next_before
+// -- next_before -> the smallest id of the page, None when the page is empty
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct HistoryPage {
+    messages: Vec<Message>,
+    has_more: bool,
+    next_before: Option<u64>,
+}
+
+// Endpoint to Fetch History
+// Returns a page of up to `limit` messages in `room` as JSON, newest first
+// -- `before` pages upward, only messages with a smaller id are returned (i.e /history?room=lobby&before=120)
+//    when omitted the page holds the most recent messages
+// -- Only public messages, direct messages are never part of a room's history
+// -- For clients that want to catch up or scroll back without opening an event stream
+// -- `iso=true` adds an RFC 3339 timestamp_iso to every message, like /events
+// -- Answers 503 while max_concurrent_queries history and search queries are already running, see QueryLimit
+#[get("/history?<room>&<before>&<limit>&<iso>")]
+async fn history(
+    room: String,
+    before: Option<u64>,
+    limit: Option<usize>,
+    iso: bool,
+    _slot: QuerySlot,
+    config: &State<ChatConfig>,
+    store: &State<Store>,
+) -> Result<Json<HistoryPage>, ApiError> {
+    check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&room);
+    let limit = limit.unwrap_or(config.history_limit);
+    let (messages, has_more) = store.page(&room, before, limit).await.map_err(|e| {
+        error!("failed to load history for {}: {}", room, e);
+        ApiError::new(Status::InternalServerError, "failed to load history")
+    })?;
+    let next_before = messages.last().map(|msg| msg.id);
+    let messages = messages
+        .into_iter()
+        .map(|msg| msg.timestamped(iso))
+        .collect();
+
+    Ok(Json(HistoryPage {
+        messages,
+        has_more,
+        next_before,
+    }))
+}
+
+// Endpoint to Fetch a User's History
+// Returns up to `limit` of the most recent messages `username` sent as JSON, newest first
+// -- `room` narrows them down to one room, otherwise every room is included (i.e /history/user/alice?room=lobby)
+// -- Like /history, direct messages are left out, and so are deleted messages
+// -- A user who never posted gets an empty array, not a 404
+// -- Like /history, answers 503 when too many queries are running
+#[get("/history/user/<username>?<room>&<limit>&<iso>")]
+async fn user_history(
+    username: &str,
+    room: Option<String>,
+    limit: Option<usize>,
+    iso: bool,
+    _slot: QuerySlot,
+    config: &State<ChatConfig>,
+    store: &State<Store>,
+) -> Result<Json<Vec<Message>>, ApiError> {
+    check_name("username", username).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = match room {
+        Some(room) => {
+            check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+            Some(normalize_room(&room))
         }
+        None => None,
+    };
+
+    let limit = limit.unwrap_or(config.history_limit);
+    let messages = store
+        .by_user(username, room.as_deref(), limit)
+        .await
+        .map_err(|e| {
+            error!("failed to load the history of {}: {}", username, e);
+            ApiError::new(Status::InternalServerError, "failed to load history")
+        })?;
+
+    Ok(Json(
+        messages
+            .into_iter()
+            .map(|msg| msg.timestamped(iso))
+            .collect(),
+    ))
+}
+
+// Endpoint to Search History
+// Returns up to `limit` of the most recent messages in `room` containing `q` (ignoring case) as JSON, newest first
+// -- `username` narrows the results down to what that user sent
+// -- Like /history, direct messages are never searched, and it answers 503 when too many queries are running
+#[get("/search?<room>&<q>&<username>&<limit>")]
+async fn search(
+    room: String,
+    q: String,
+    username: Option<String>,
+    limit: Option<usize>,
+    _slot: QuerySlot,
+    config: &State<ChatConfig>,
+    store: &State<Store>,
+) -> Result<Json<Vec<Message>>, ApiError> {
+    check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let room = normalize_room(&room);
+    if let Some(username) = &username {
+        check_name("username", username)
+            .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    }
+    if q.trim().is_empty() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "q can't be empty",
+        ));
     }
+
+    let limit = limit.unwrap_or(config.history_limit);
+    store
+        .search(&room, &q, username.as_deref(), limit)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("failed to search {} for {}: {}", room, q, e);
+            ApiError::new(Status::InternalServerError, "failed to search history")
+        })
 }
 
 // Launch Attribute
@@ -88,12 +774,203 @@ async fn events(queue: &State<Sender<Message>>, mut end: Shutdown) -> EventStrea
 // -- i.e the below would create a valid route at http://127.0.0.1:8000/hello/world
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
+    build(rocket::Config::figment())
+}
+
+// The app built on `figment`, Rocket's configuration (Rocket.toml and the ROCKET_ environment variables for rocket())
+// Split out of rocket() so tests can run it with settings of their own, see testing.rs
+fn build(figment: Figment) -> Rocket<Build> {
+    let rocket = rocket::custom(figment);
+
+    // Read our own settings out of the "chat" table of Rocket's configuration
+    let config: ChatConfig = rocket
+        .figment()
+        .focus("chat")
+        .extract()
+        .expect("invalid chat configuration");
+
+    // Enforce max_body_size through Rocket's own data limits, see error::payload_too_large for the response
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("limits.form", config.max_body_size))
+        .merge(("limits.json", config.max_body_size));
+    let rocket = rocket.configure(figment);
+
+    // Serve the frontend from the configured directory, or the one shipped in the repository
+    let static_dir = config
+        .static_dir
+        .clone()
+        .unwrap_or_else(|| relative!("static").to_string());
+    assert!(
+        Path::new(&static_dir).is_dir(),
+        "static_dir {} is not a directory",
+        static_dir
+    );
+
+    // Log who posts and who listens, see access_log.rs
+    let access_log = AccessLog::new(config.access_log_level, config.access_log_usernames);
+
+    // Relay messages between instances through Redis when configured, see relay.rs
+    #[cfg(feature = "redis")]
+    let rocket = rocket.attach(relay::RelayFairing);
+    #[cfg(not(feature = "redis"))]
+    assert!(
+        config.redis_url.is_none(),
+        "redis_url is set but this build doesn't have the redis feature"
+    );
+
+    // Sign what's posted with the api_key when configured, see signature.rs
+    let rocket = match &config.signing_secret {
+        Some(secret) => rocket.manage(Signer::new(secret)),
+        None => rocket,
+    };
+
+    rocket
+        // Open the message store once Rocket ignites, continuing the id and seq counters where the stored history left off
+        // Bodies are encrypted at rest when a store_encryption_key is configured, see cipher.rs
+        .attach(AdHoc::try_on_ignite("SQLite Store", |rocket| async {
+            let config = rocket.state::<ChatConfig>().unwrap();
+            let url = config.database_url.clone();
+            let cipher = match config
+                .store_encryption_key
+                .as_deref()
+                .map(BodyCipher::from_base64)
+            {
+                Some(Ok(cipher)) => Some(cipher),
+                Some(Err(e)) => {
+                    error!("{}", e);
+                    return Err(rocket);
+                }
+                None => None,
+            };
+            let store = match Store::connect(&url, cipher).await {
+                Ok(store) => store,
+                Err(e) => {
+                    error!("failed to open message store {}: {}", url, e);
+                    return Err(rocket);
+                }
+            };
+            let last = match (store.last_id().await, store.last_seqs().await) {
+                (Ok(id), Ok(seqs)) => (id, seqs),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("failed to read message store {}: {}", url, e);
+                    return Err(rocket);
+                }
+            };
+
+            Ok(rocket
+                .manage(store)
+                .manage(MessageIds::starting_after(last.0))
+                .manage(RoomSequences::starting_after(last.1)))
+        }))
+        // Load the blacklist once at launch, a missing blacklist file stops the launch
+        .attach(AdHoc::try_on_ignite("Word Filter", |rocket| async {
+            let config = rocket.state::<ChatConfig>().unwrap();
+            match WordFilter::load(&config.blacklist, config.blacklist_file.as_deref()) {
+                Ok(filter) => Ok(rocket.manage(filter)),
+                Err(e) => {
+                    error!("failed to load blacklist: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        // Prune old messages in the background when a retention policy is configured, see retention.rs
+        .attach(retention::Retention)
+        // Open the line oriented TCP bridge when a tcp_bridge_port is configured, see bridge.rs
+        .attach(bridge::TcpBridge)
+        // Turn posts down once shutdown is requested, see drain.rs
+        .attach(drain::Drain)
+        .attach(Cors::new(config.cors_allowed_origins.clone()))
+        .attach(compression::Compression)
+        .attach(access_log)
+        .manage(ConnectionLimit::new(
+            config.max_sse_connections,
+            config.max_sse_connections_per_ip,
+        ))
+        .manage(RateLimiter::<Messages>::new(
+            config.rate_limit_per_second,
+            config.rate_limit_burst,
+        ))
+        .manage(RateLimiter::<Typing>::new(
+            config.typing_rate_limit_per_second,
+            config.typing_rate_limit_per_second,
+        ))
+        .manage(SlowMode::new(config.slow_mode))
+        .manage(UsernameLimiter::new(
+            config.max_usernames_per_ip,
+            config.username_window,
+        ))
+        .manage(RecentPosts::new(config.dedupe_window_ms))
+        .manage(RoomCaps::new(config.max_users_per_room))
+        .manage(QueryLimit::new(config.max_concurrent_queries))
+        .manage(RoomRegistry::new(
+            config.max_rooms,
+            config.room_idle_timeout,
+        ))
         // Use Manage to add state to the rocket instance (all handlers have access to this instance)
-        // The specific state we want to add is the sender end of a channel (to pass messages between async tasks)
-        // We create a channel and then specify the type of struct we want to pass and how much we want the channel to retain
-        // ".0" specifies we only want to retain the sender end of the channel
-        .manage(channel::<Message>(1024).0)
-        .mount("/", routes![world, post, events]) // Uses routes macro to create a list of routes
-        .mount("/", FileServer::from(relative!("static"))) // Specifies where to retrieve static files from
+        // The specific state we want to add is the broadcast channels (to pass messages between async tasks), see channels.rs
+        // Each channel retains up to chat.channel_capacity messages
+        .manage(Channels::new(config.channel_capacity))
+        .manage(Motd::new(config.motd.clone()))
+        .manage(Presence::new(config.templates.clone()))
+        .manage(InstanceId::new(config.instance_id.clone()))
+        .manage(config)
+        .manage(Metrics::default())
+        .manage(Reservations::default())
+        .manage(Bans::default())
+        .manage(Mutes::default())
+        .manage(RoomLocks::default())
+        .manage(RoomModerators::default())
+        .manage(Draining::default())
+        .manage(access_log)
+        // Uses routes macro to create a list of routes
+        .mount(
+            "/",
+            routes![
+                world,
+                post,
+                post_json,
+                events,
+                history,
+                user_history,
+                search,
+                admin::ban,
+                admin::channel,
+                admin::close_room,
+                admin::export,
+                admin::lock,
+                admin::moderators,
+                admin::motd,
+                admin::room_cap,
+                admin::room_config,
+                admin::shutdown,
+                admin::slow_mode,
+                admin::stats,
+                admin::users,
+                auth::register,
+                auth::session,
+                cors::preflight,
+                batch::post_batch,
+                delete::delete,
+                edit::edit,
+                health::healthz,
+                health::readyz,
+                metrics::metrics,
+                mute::mute,
+                mute::unmute,
+                openapi::openapi,
+                presence::rooms,
+                reaction::react,
+                presence::users,
+                typing::typing,
+                version::version,
+                ws::ws
+            ],
+        )
+        .mount("/", FileServer::from(static_dir)) // Specifies where to retrieve static files from
+        .register(
+            "/",
+            catchers![error::payload_too_large, error::default_catcher],
+        ) // Errors nobody handled still get a JSON body
 }
@@ -0,0 +1,37 @@
+use pulldown_cmark::{html, Options, Parser};
+
+// Render a message body written in markdown to HTML that is safe to insert into a page
+// -- pulldown-cmark turns the markdown into HTML, any raw HTML in the body is passed through as is
+// -- ammonia then strips everything that could run code: <script> and <style> tags, event handler attributes, javascript: links...
+pub fn render(body: &str) -> String {
+    let parser = Parser::new_ext(body, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES);
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::clean(&unsafe_html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_is_rendered() {
+        assert_eq!(render("**bold**"), "<p><strong>bold</strong></p>\n");
+        assert_eq!(render("~~gone~~"), "<p><del>gone</del></p>\n");
+    }
+
+    #[test]
+    fn scripts_and_event_handlers_are_stripped() {
+        let html = render("hi <script>alert(1)</script>");
+        assert!(!html.contains("script"), "{}", html);
+        assert!(!html.contains("alert"), "{}", html);
+
+        let html = render(r#"<img src="x.png" onerror="alert(1)">"#);
+        assert!(!html.contains("onerror"), "{}", html);
+        assert!(html.contains("x.png"), "{}", html);
+
+        let html = render("[click](javascript:alert(1))");
+        assert!(!html.contains("javascript"), "{}", html);
+    }
+}
@@ -0,0 +1,891 @@
+use rocket::form::{self, error::ErrorKind};
+use rocket::http::uri::Absolute;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Upper bounds (exclusive) on the length of a room and username, in characters (see graphemes)
+pub const MAX_ROOM_LEN: usize = 30;
+pub const MAX_USERNAME_LEN: usize = 20;
+
+// Upper bound (exclusive) on the length of a client_msg_id
+pub const MAX_CLIENT_MSG_ID_LEN: usize = 64;
+
+// Upper bound (exclusive) on the length of an attachment_url
+pub const MAX_ATTACHMENT_URL_LEN: usize = 2048;
+
+// Body a deleted message is left with
+pub const DELETED_PLACEHOLDER: &str = "[deleted]";
+
+// Username the server sends its own announcements (joins, leaves...) as, clients can't claim it
+pub const SYSTEM_USERNAME: &str = "system";
+
+// Whether `name` is safe to use as a room or username: ASCII letters, digits, '_' and '-', and not empty
+// Keeps newlines, control characters and slashes out of client-side routing and logs
+pub fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Rooms are case-insensitive, "General", "general" and "GENERAL" are the same room
+// Every room name a client gives is lowercased once validated, before it's used for channels, presence, history or anything else
+pub fn normalize_room(room: &str) -> String {
+    room.to_ascii_lowercase()
+}
+
+// The same check for a `field` that didn't go through form validation (JSON bodies, query parameters...)
+pub fn check_name(field: &str, name: &str) -> Result<(), String> {
+    if !is_safe_name(name) {
+        return Err(format!(
+            "{} must be made of letters, digits, '_' and '-' only",
+            field
+        ));
+    }
+
+    Ok(())
+}
+
+// The usernames `body` mentions, i.e "@alice" and "@bob-2", once each in the order they first appear
+// A mention starts with an '@' that doesn't follow a name character, so an email address mentions nobody
+pub fn mentions(body: &str) -> Vec<String> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    let mut mentioned: Vec<String> = Vec::new();
+    let mut previous = None;
+    for (at, c) in body.char_indices() {
+        if c == '@' && !previous.is_some_and(is_name_char) {
+            let name = &body[at + 1..];
+            let end = name.find(|c| !is_name_char(c)).unwrap_or(name.len());
+            let name = &name[..end];
+            if !name.is_empty()
+                && graphemes(name) < MAX_USERNAME_LEN
+                && !mentioned.iter().any(|m| m == name)
+            {
+                mentioned.push(name.to_string());
+            }
+        }
+        previous = Some(c);
+    }
+
+    mentioned
+}
+
+// Length of `text` in characters as people see them: grapheme clusters, not bytes or chars
+// An emoji made of several code points ("👍🏽", a flag) or a letter with a combining accent ("e\u{301}") counts as one
+// Every limit on the length of rooms, usernames and message bodies is in these
+pub fn graphemes(text: &str) -> usize {
+    text.graphemes(true).count()
+}
+
+// Form validator bounding the length of a field in graphemes, like Rocket's `len` does in bytes
+// i.e #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))], failing with the same error `len` would
+pub fn max_graphemes<'v>(text: &str, range: impl RangeBounds<usize>) -> form::Result<'v, ()> {
+    let len = graphemes(text);
+    if !range.contains(&len) {
+        let min = match range.start_bound() {
+            Bound::Included(min) => Some(*min as u64),
+            Bound::Excluded(min) => Some(*min as u64 + 1),
+            Bound::Unbounded => None,
+        };
+        let max = match range.end_bound() {
+            Bound::Included(max) => Some(*max as u64),
+            Bound::Excluded(max) => Some((*max as u64).saturating_sub(1)),
+            Bound::Unbounded => None,
+        };
+        Err(form::Error::from(ErrorKind::InvalidLength { min, max }))?;
+    }
+
+    Ok(())
+}
+
+// Form validator for rooms and usernames, see is_safe_name
+pub fn safe_name<'v>(name: &str) -> form::Result<'v, ()> {
+    if !is_safe_name(name) {
+        Err(form::Error::validation(
+            "must be made of letters, digits, '_' and '-' only",
+        ))?;
+    }
+
+    Ok(())
+}
+
+// Check an attachment_url is an absolute http or https URL naming a host, i.e "https://example.com/cat.png"
+pub fn check_attachment_url(url: &str) -> Result<(), String> {
+    let uri = Absolute::parse(url)
+        .map_err(|_| "attachment_url must be a valid absolute URL".to_string())?;
+    if !uri.scheme().eq_ignore_ascii_case("http") && !uri.scheme().eq_ignore_ascii_case("https") {
+        return Err("attachment_url must be an http or https URL".into());
+    }
+    if uri
+        .authority()
+        .is_none_or(|authority| authority.host().is_empty())
+    {
+        return Err("attachment_url must name a host".into());
+    }
+
+    Ok(())
+}
+
+// Form validator rejecting the reserved system username
+pub fn not_reserved<'v>(username: &str) -> form::Result<'v, ()> {
+    if username == SYSTEM_USERNAME {
+        Err(form::Error::validation("username is reserved"))?;
+    }
+
+    Ok(())
+}
+
+// This struct defines the format of the form data a client submits to /message
+// 3 fields with some validations (rooms and usernames are limited to safe characters, see is_safe_name), plus an optional recipient turning it into a direct message
+// and an optional flag asking for the body to be rendered from markdown to HTML
+// A client may also pick a `client_msg_id` of its own, echoed back in the response and the broadcast so it can recognize its message on the stream
+// and reply to an earlier message of the room by giving its id as `reply_to`
+// An `attachment_url` shares a file hosted elsewhere (there are no uploads), only http(s) URLs are accepted
+// Derives a few traits
+// -- Debug -> Can output in debug format
+// -- Clone -> Can duplicate messages
+// -- FromForm -> Can take form data and translate to a message struct
+// -- Serialize
+// -- Deserialize
+#[derive(Debug, Clone, FromForm, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")] // Serialize and Deserialize via serde (Defined in Rocket)
+pub struct MessageForm {
+    #[field(validate = max_graphemes(..MAX_ROOM_LEN))]
+    #[field(validate = safe_name())]
+    pub room: String,
+    #[field(validate = max_graphemes(..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    #[field(validate = not_reserved())]
+    pub username: String,
+    pub message: String,
+    #[serde(default)]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub markdown: bool,
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+    #[serde(default)]
+    pub attachment_url: Option<String>,
+}
+
+impl MessageForm {
+    // The same checks the field attributes run for form data
+    // For messages arriving some other way (i.e JSON over a WebSocket) which skip form validation
+    pub fn validate(&self) -> Result<(), String> {
+        if graphemes(&self.room) >= MAX_ROOM_LEN {
+            return Err(format!(
+                "room must be shorter than {} characters",
+                MAX_ROOM_LEN
+            ));
+        }
+        if graphemes(&self.username) >= MAX_USERNAME_LEN {
+            return Err(format!(
+                "username must be shorter than {} characters",
+                MAX_USERNAME_LEN
+            ));
+        }
+        check_name("room", &self.room)?;
+        check_name("username", &self.username)?;
+        if self.username == SYSTEM_USERNAME {
+            return Err("username is reserved".into());
+        }
+
+        Ok(())
+    }
+
+    // Strip leading/trailing whitespace from the username, recipient, client_msg_id and attachment_url, keeping whatever is in between
+    // The room is normalized, see normalize_room, and the body is left to the pipeline (see Step::Trim)
+    // A blank recipient means the message isn't direct, a blank client_msg_id or attachment_url is dropped
+    pub fn trimmed(self) -> MessageForm {
+        MessageForm {
+            room: normalize_room(&self.room),
+            username: self.username.trim().to_string(),
+            message: self.message,
+            to: self
+                .to
+                .map(|to| to.trim().to_string())
+                .filter(|to| !to.is_empty()),
+            markdown: self.markdown,
+            client_msg_id: self
+                .client_msg_id
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty()),
+            reply_to: self.reply_to,
+            attachment_url: self
+                .attachment_url
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty()),
+        }
+    }
+
+    // Checks on the (trimmed) content that field attributes can't express
+    // -- username and body can't be blank, how long the body may be is checked by the pipeline (see Step::Length)
+    // -- an attachment_url must be an absolute http or https URL with a host, so no javascript: or file: links reach other clients
+    pub fn validate_content(&self) -> Result<(), String> {
+        if self.username.is_empty() {
+            return Err("username can't be empty".into());
+        }
+        if self.message.trim().is_empty() {
+            return Err("message can't be empty".into());
+        }
+        if let Some(to) = &self.to {
+            if graphemes(to) >= MAX_USERNAME_LEN {
+                return Err(format!(
+                    "to must be shorter than {} characters",
+                    MAX_USERNAME_LEN
+                ));
+            }
+            check_name("to", to)?;
+        }
+        if let Some(id) = &self.client_msg_id {
+            if id.len() >= MAX_CLIENT_MSG_ID_LEN {
+                return Err(format!(
+                    "client_msg_id must be shorter than {} bytes",
+                    MAX_CLIENT_MSG_ID_LEN
+                ));
+            }
+        }
+
+        if let Some(url) = &self.attachment_url {
+            if url.len() >= MAX_ATTACHMENT_URL_LEN {
+                return Err(format!(
+                    "attachment_url must be shorter than {} bytes",
+                    MAX_ATTACHMENT_URL_LEN
+                ));
+            }
+            check_attachment_url(url)?;
+        }
+
+        Ok(())
+    }
+}
+
+// This struct defines the format of our messages which will be passed in our channel
+// It wraps the submitted form with fields assigned by the server
+// -- id -> Monotonically increasing, lets clients dedupe and order messages, 0 for the message of the day a stream opens with
+// -- timestamp -> Unix time in milliseconds when the server accepted the message
+// -- to -> Recipient of a direct message, only the sender and recipient get to see it
+// -- system -> Set on announcements made by the server itself so clients can style them differently
+// -- kind -> What the message is, see MessageKind
+// -- target -> For a Reaction, an Edit or a Delete, the id of the message it is about
+// -- html -> Sanitized HTML rendering of the body, only when markdown rendering was asked for (see render_markdown)
+// -- deleted -> The message was deleted, its body is only a placeholder
+// -- edited_at -> Unix time in milliseconds of the last edit, None if the message was never edited
+// -- reactions -> How many users reacted to this message with each emoji, filled in when read back from the store
+// -- client_msg_id -> The id the sender picked for its message, only on the live broadcast, it isn't stored
+// -- timestamp_iso -> The timestamp as an RFC 3339 string in UTC, only filled in for clients asking for it (i.e /events?iso=true)
+// -- reply_to -> The id of the message of the same room this one replies to
+// -- origin_instance -> The id of the server instance that accepted a chat message, see InstanceId
+// -- attachment_url -> An http(s) URL of a file shared along with the message
+// -- seq -> For a public chat message, its number in its room: 1 for the room's first message, then one more for every message, see RoomSequences
+// -- signature -> For a message posted with the api_key when a signing_secret is configured, its HMAC, see Signer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Message {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub timestamp: u64,
+    pub room: String,
+    pub username: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub system: bool,
+    #[serde(default)]
+    pub kind: MessageKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_iso: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+// The kinds of Message sharing the broadcast channel
+// -- Chat -> Something a user said, persisted to history
+// -- System -> An announcement from the server itself (joins, leaves...), `system` is also set for older clients
+// -- Typing -> A user is typing in a room, transient and never persisted
+// -- Reaction -> A user reacted to a stored message with the emoji in `message`, the reaction is persisted but this event isn't
+// -- Edit -> The author of a stored message changed its body to `message`, the store is updated in place
+// -- Delete -> A stored message was deleted, it stays in the store as a tombstone
+// -- Mention -> A chat message mentioned the user in `to` as @username, only sent to that user and never persisted
+// -- RoomClosed -> A moderator closed the room through /admin/close-room, the last message its streams get before they end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum MessageKind {
+    #[default]
+    Chat,
+    System,
+    Typing,
+    Reaction,
+    Edit,
+    Delete,
+    Mention,
+    #[serde(rename = "room_closed")]
+    RoomClosed,
+}
+
+impl MessageKind {
+    // Name of the SSE event /events sends messages of this kind as
+    // Chat messages use "message", the name browsers give events without one, so listeners of the default event keep working
+    pub fn event_name(self) -> &'static str {
+        match self {
+            MessageKind::Chat => "message",
+            MessageKind::System => "system",
+            MessageKind::Typing => "typing",
+            MessageKind::Reaction => "reaction",
+            MessageKind::Edit => "edit",
+            MessageKind::Delete => "delete",
+            MessageKind::Mention => "mention",
+            MessageKind::RoomClosed => "room_closed",
+        }
+    }
+
+    // Whether messages of this kind go through the priority channels, see Channels
+    // Announcements and moderation notices have to reach everyone, however much chat is going on
+    pub fn is_priority(self) -> bool {
+        matches!(self, MessageKind::System | MessageKind::RoomClosed)
+    }
+}
+
+impl Message {
+    // What every message the server makes up starts from, with the given kind and nothing else set
+    // The constructors below fill in the rest with struct update syntax
+    fn base(
+        id: u64,
+        kind: MessageKind,
+        room: String,
+        username: String,
+        message: String,
+    ) -> Message {
+        Message {
+            id,
+            seq: None,
+            timestamp: now_millis(),
+            room,
+            username,
+            message,
+            to: None,
+            system: false,
+            kind,
+            target: None,
+            html: None,
+            deleted: false,
+            edited_at: None,
+            reactions: BTreeMap::new(),
+            client_msg_id: None,
+            timestamp_iso: None,
+            reply_to: None,
+            origin_instance: None,
+            attachment_url: None,
+            signature: None,
+        }
+    }
+
+    // An announcement from the server to everyone in `room`, i.e "alice joined lobby"
+    pub fn system(id: u64, room: &str, message: String) -> Message {
+        Message {
+            system: true,
+            ..Message::base(
+                id,
+                MessageKind::System,
+                room.to_string(),
+                SYSTEM_USERNAME.to_string(),
+                message,
+            )
+        }
+    }
+
+    // Tells the subscribers of `room` it was closed, from the server like other announcements
+    pub fn room_closed(id: u64, room: &str) -> Message {
+        Message {
+            kind: MessageKind::RoomClosed,
+            ..Message::system(id, room, format!("{} was closed by a moderator", room))
+        }
+    }
+
+    // `username` is typing in `room`
+    pub fn typing(id: u64, room: String, username: String) -> Message {
+        Message::base(id, MessageKind::Typing, room, username, String::new())
+    }
+
+    // `username` reacted to `target` with `emoji`
+    // Sent to the same audience as `target`, so a reaction to a direct message only reaches its two participants
+    // -- `username` must be one of them, see Message::visible_to
+    pub fn reaction(id: u64, target: &Message, username: String, emoji: String) -> Message {
+        let to = target.to.as_ref().map(|to| {
+            if *to == username {
+                target.username.clone()
+            } else {
+                to.clone()
+            }
+        });
+
+        Message {
+            to,
+            target: Some(target.id),
+            ..Message::base(
+                id,
+                MessageKind::Reaction,
+                target.room.clone(),
+                username,
+                emoji,
+            )
+        }
+    }
+
+    // Announces that `edited`, as now stored, was edited
+    // Sent to the same audience as the message itself
+    pub fn edit(id: u64, edited: &Message) -> Message {
+        Message {
+            to: edited.to.clone(),
+            target: Some(edited.id),
+            html: edited.html.clone(),
+            edited_at: edited.edited_at,
+            ..Message::base(
+                id,
+                MessageKind::Edit,
+                edited.room.clone(),
+                edited.username.clone(),
+                edited.message.clone(),
+            )
+        }
+    }
+
+    // Announces that `deleted` was deleted, by its author or a moderator
+    // Sent to the same audience as the message itself
+    pub fn delete(id: u64, deleted: &Message) -> Message {
+        Message {
+            to: deleted.to.clone(),
+            target: Some(deleted.id),
+            deleted: true,
+            ..Message::base(
+                id,
+                MessageKind::Delete,
+                deleted.room.clone(),
+                deleted.username.clone(),
+                DELETED_PLACEHOLDER.to_string(),
+            )
+        }
+    }
+
+    // Tells `username` that `mentioning` mentioned them, carrying its body and its id as the target
+    // A direct message to that user alone, Channels::send doesn't copy it to the author
+    pub fn mention(id: u64, mentioning: &Message, username: &str) -> Message {
+        Message {
+            to: Some(username.to_string()),
+            target: Some(mentioning.id),
+            ..Message::base(
+                id,
+                MessageKind::Mention,
+                mentioning.room.clone(),
+                mentioning.username.clone(),
+                mentioning.message.clone(),
+            )
+        }
+    }
+
+    // Fill in timestamp_iso when `iso` is set, for clients that asked for it
+    pub fn timestamped(mut self, iso: bool) -> Message {
+        if iso {
+            self.timestamp_iso = Some(format_millis(self.timestamp));
+        }
+        self
+    }
+
+    // Whether a subscriber listening to `room` (every room when None) as `username` should receive this message
+    // -- a direct message reaches its sender and recipient whatever room they listen to, and nobody else
+    // -- anything else reaches everyone listening to its room
+    pub fn visible_to(&self, room: Option<&str>, username: Option<&str>) -> bool {
+        match &self.to {
+            Some(to) => username.is_some_and(|name| name == to || name == self.username),
+            None => room.is_none_or(|room| room == self.room),
+        }
+    }
+}
+
+// Managed counter handing out message ids
+// Lives in Rocket state so it survives across requests, clones share the same counter
+#[derive(Clone)]
+pub struct MessageIds(Arc<AtomicU64>);
+
+impl MessageIds {
+    // Start counting after `last`, the highest id handed out so far (i.e restored from the store)
+    pub fn starting_after(last: u64) -> MessageIds {
+        MessageIds(Arc::new(AtomicU64::new(last)))
+    }
+
+    // Ids start at 1 and increase by one for every accepted message
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+// Managed counters handing out the seq of public messages, one per room
+// Unlike ids a room's messages get consecutive numbers, for clients showing one room at a time
+// Counts are per instance, instances sharing messages through a relay number them on their own
+pub struct RoomSequences(Mutex<HashMap<String, u64>>);
+
+impl RoomSequences {
+    // Continue every room's count from `last`, the highest seq of each room so far (i.e restored from the store)
+    pub fn starting_after(last: HashMap<String, u64>) -> RoomSequences {
+        RoomSequences(Mutex::new(last))
+    }
+
+    // The id (from `ids`) and seq of the next message of `room`, seqs start at 1
+    // Both are taken under the lock, so two posts to the same room can't get the same seq, nor seqs in another order than their ids
+    pub fn next(&self, room: &str, ids: &MessageIds) -> (u64, u64) {
+        let mut rooms = self.0.lock().unwrap();
+        let seq = rooms.entry(room.to_string()).or_default();
+        *seq += 1;
+        (ids.next(), *seq)
+    }
+}
+
+// Body returned from /message so the sender can correlate its post with the broadcast
+// Only sent once the message is stored, its id and timestamp are those of the stored message
+// -- client_msg_id -> Echoed back when the post had one
+// -- deduped -> Set when the post duplicated the one the user just made, `id` is that earlier message's and nothing was broadcast again
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PostResponse {
+    pub id: u64,
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub deduped: bool,
+}
+
+// A unix time in milliseconds as an RFC 3339 string in UTC, i.e "2024-05-01T12:30:00.25Z"
+pub fn format_millis(millis: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000)
+        .ok()
+        .and_then(|at| at.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+// Current unix time in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{unlimited, TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn safe_names_are_letters_digits_underscores_and_dashes() {
+        for name in ["general", "Room_2", "bob-the-builder", "_", "x"] {
+            assert!(is_safe_name(name), "{}", name);
+        }
+        for name in [
+            "",
+            "a/b",
+            "../etc",
+            "two words",
+            "line\nbreak",
+            "tab\t",
+            "café",
+            "日本",
+            "🎉",
+        ] {
+            assert!(!is_safe_name(name), "{:?}", name);
+        }
+        assert!(check_name("room", "general").is_ok());
+        assert_eq!(
+            check_name("room", "a/b").unwrap_err(),
+            "room must be made of letters, digits, '_' and '-' only"
+        );
+    }
+
+    #[test]
+    fn unsafe_rooms_and_usernames_get_422() {
+        let chat = TestChat::configured(unlimited);
+        for (room, username) in [
+            ("a/b", "alice"),
+            ("café", "alice"),
+            ("lobby", "bob/../root"),
+            ("lobby", "zoë"),
+        ] {
+            assert_eq!(
+                chat.post(room, username, "hello"),
+                Status::UnprocessableEntity,
+                "{} {}",
+                room,
+                username
+            );
+            let form = format!("room={}&username={}&message=hello", room, username);
+            assert_eq!(
+                chat.send(chat.post_form("/message", &form)).status,
+                Status::UnprocessableEntity
+            );
+        }
+
+        assert_eq!(
+            chat.send(chat.get("/history?room=a%2Fb")).status,
+            Status::UnprocessableEntity
+        );
+        assert_eq!(
+            chat.send(chat.get("/search?room=caf%C3%A9&q=hi")).status,
+            Status::UnprocessableEntity
+        );
+    }
+
+    #[test]
+    fn mentions_are_at_signs_before_a_name() {
+        assert_eq!(
+            mentions("@alice and @bob-2, @alice again"),
+            ["alice", "bob-2"]
+        );
+        assert_eq!(mentions("mail alice@example.com"), Vec::<String>::new());
+        assert_eq!(mentions("@ nobody, @"), Vec::<String>::new());
+        assert_eq!(mentions("(@carol)"), ["carol"]);
+    }
+
+    #[test]
+    fn rooms_are_numbered_on_their_own() {
+        let ids = MessageIds::starting_after(0);
+        let seqs = RoomSequences::starting_after(HashMap::from([("games".to_string(), 41)]));
+        assert_eq!(seqs.next("lobby", &ids), (1, 1));
+        assert_eq!(seqs.next("games", &ids), (2, 42));
+        assert_eq!(seqs.next("lobby", &ids), (3, 2));
+    }
+
+    #[test]
+    fn concurrent_posts_to_a_room_get_distinct_seqs_in_id_order() {
+        let ids = MessageIds::starting_after(0);
+        let seqs = RoomSequences::starting_after(HashMap::new());
+        let mut numbered: Vec<(u64, u64)> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..100)
+                            .map(|_| seqs.next("lobby", &ids))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect()
+        });
+
+        numbered.sort();
+        let in_order: Vec<_> = (1..=800).map(|n| (n, n)).collect();
+        assert_eq!(numbered, in_order);
+    }
+
+    #[test]
+    fn lengths_count_what_people_see() {
+        assert_eq!(graphemes("alice"), 5);
+        assert_eq!(graphemes(""), 0);
+        // A thumbs up with a skin tone, a flag and a family are several code points each
+        assert_eq!(graphemes("👍🏽🇫🇷👨‍👩‍👧"), 3);
+        // "é" as an "e" and a combining accent
+        assert_eq!(graphemes("cafe\u{301}"), 4);
+        assert_eq!(graphemes("日本語"), 3);
+
+        assert!(max_graphemes("👍🏽👍🏽", ..3).is_ok());
+        assert!(max_graphemes("👍🏽👍🏽👍🏽", ..3).is_err());
+        assert!(max_graphemes("", 1..3).is_err());
+    }
+
+    #[test]
+    fn message_length_is_counted_in_graphemes() {
+        let chat =
+            TestChat::configured(|figment| unlimited(figment).merge(("chat.max_message_len", 5)));
+        let _events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "👍🏽👍🏽👍🏽👍🏽👍🏽"), Status::Ok);
+        assert_eq!(
+            chat.post("lobby", "alice", &"e\u{301}".repeat(5)),
+            Status::Ok
+        );
+        assert_eq!(chat.post("lobby", "alice", "hello"), Status::Ok);
+
+        let body = json!({ "room": "lobby", "username": "alice", "message": "👍🏽👍🏽👍🏽👍🏽👍🏽👍🏽" });
+        let reply = chat.send(chat.post_json("/message", &body));
+        assert_eq!(reply.status, Status::UnprocessableEntity);
+        assert_eq!(
+            reply.json()["error"],
+            "message must be at most 5 characters, got 6"
+        );
+    }
+
+    #[test]
+    fn recipients_and_mentions_are_capped_in_characters() {
+        let form = |to: &str| -> MessageForm {
+            rocket::serde::json::from_value(json!({
+                "room": "lobby",
+                "username": "alice",
+                "message": "hi",
+                "to": to,
+            }))
+            .unwrap()
+        };
+        assert!(form(&"b".repeat(MAX_USERNAME_LEN - 1))
+            .validate_content()
+            .is_ok());
+        assert_eq!(
+            form(&"b".repeat(MAX_USERNAME_LEN))
+                .validate_content()
+                .unwrap_err(),
+            format!("to must be shorter than {} characters", MAX_USERNAME_LEN)
+        );
+
+        let longest = "b".repeat(MAX_USERNAME_LEN - 1);
+        assert_eq!(mentions(&format!("@{}", longest)), [longest]);
+        assert!(mentions(&format!("@{}", "b".repeat(MAX_USERNAME_LEN))).is_empty());
+    }
+
+    #[test]
+    fn millis_are_formatted_as_rfc_3339_in_utc() {
+        assert_eq!(format_millis(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_millis(1_714_566_600_250), "2024-05-01T12:30:00.25Z");
+    }
+
+    fn direct(id: u64, username: &str, to: &str, body: &str) -> Message {
+        Message {
+            username: username.to_string(),
+            to: Some(to.to_string()),
+            system: false,
+            kind: MessageKind::Chat,
+            ..Message::system(id, "lobby", body.to_string())
+        }
+    }
+
+    #[test]
+    fn constructors_fill_in_their_kind_and_target() {
+        let dm = direct(1, "alice", "bob", "hi");
+
+        let typing = Message::typing(2, "lobby".into(), "alice".into());
+        assert_eq!((typing.kind, typing.system), (MessageKind::Typing, false));
+        assert_eq!(typing.target, None);
+
+        // A reaction goes to whichever of the two participants didn't react
+        let reaction = Message::reaction(3, &dm, "bob".into(), "👍".into());
+        assert_eq!(reaction.kind, MessageKind::Reaction);
+        assert_eq!(
+            (reaction.target, reaction.to.as_deref()),
+            (Some(1), Some("alice"))
+        );
+        assert_eq!(reaction.message, "👍");
+
+        let edited = Message {
+            edited_at: Some(42),
+            html: Some("<p>hi</p>".into()),
+            ..dm.clone()
+        };
+        let edit = Message::edit(4, &edited);
+        assert_eq!(
+            (edit.kind, edit.target, edit.edited_at),
+            (MessageKind::Edit, Some(1), Some(42))
+        );
+        assert_eq!(
+            (edit.to.as_deref(), edit.html.as_deref()),
+            (Some("bob"), Some("<p>hi</p>"))
+        );
+
+        let delete = Message::delete(5, &dm);
+        assert_eq!((delete.kind, delete.deleted), (MessageKind::Delete, true));
+        assert_eq!(delete.message, DELETED_PLACEHOLDER);
+        assert_eq!(delete.to.as_deref(), Some("bob"));
+
+        let mention = Message::mention(6, &dm, "carol");
+        assert_eq!(
+            (mention.kind, mention.target),
+            (MessageKind::Mention, Some(1))
+        );
+        assert_eq!(
+            (mention.username.as_str(), mention.to.as_deref()),
+            ("alice", Some("carol"))
+        );
+
+        let closed = Message::room_closed(7, "lobby");
+        assert_eq!(
+            (closed.kind, closed.system),
+            (MessageKind::RoomClosed, true)
+        );
+        assert_eq!(closed.username, SYSTEM_USERNAME);
+    }
+
+    #[test]
+    fn attachment_urls_must_be_http_or_https() {
+        assert!(check_attachment_url("https://example.com/cat.png").is_ok());
+        assert!(check_attachment_url("HTTP://example.com").is_ok());
+        for url in [
+            "javascript:alert(1)",
+            "file:///etc/passwd",
+            "ftp://example.com/a",
+            "https://",
+            "/cat.png",
+        ] {
+            assert!(check_attachment_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn attachment_urls_reach_streams_and_history() {
+        let chat = TestChat::configured(unlimited);
+        let mut events = chat.events("room=lobby");
+        let post = |url: &str| {
+            let body = json!({
+                "room": "lobby",
+                "username": "alice",
+                "message": "look",
+                "attachment_url": url,
+            });
+            chat.send(chat.post_json("/message", &body))
+        };
+
+        let url = "https://example.com/cat.png";
+        assert_eq!(post(url).status, Status::Ok);
+        let sent = events.messages(1, WAIT);
+        assert_eq!(sent[0].attachment_url.as_deref(), Some(url));
+        let history = chat.send(chat.get("/history?room=lobby")).json();
+        assert_eq!(history["messages"][0]["attachment_url"], url);
+
+        let reply = post("javascript:alert(1)");
+        assert_eq!(reply.status, Status::UnprocessableEntity);
+        assert_eq!(
+            reply.json()["error"],
+            "attachment_url must be an http or https URL"
+        );
+        let long = format!("https://example.com/{}", "a".repeat(MAX_ATTACHMENT_URL_LEN));
+        assert_eq!(post(&long).status, Status::UnprocessableEntity);
+    }
+}
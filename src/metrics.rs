@@ -0,0 +1,296 @@
+use rocket::http::ContentType;
+use rocket::State;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Shortest time between two logged samples of messages dropped for lack of subscribers
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Counters {
+    started: Instant,
+    messages_posted: AtomicU64,
+    dropped_no_subscribers: AtomicU64,
+    // Milliseconds after `started` a dropped message was last logged
+    drop_logged_at: AtomicU64,
+    active_subscribers: AtomicU64,
+    lagged: AtomicU64,
+    closed: AtomicU64,
+    exits: [AtomicU64; ExitReason::ALL.len()],
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters {
+            started: Instant::now(),
+            messages_posted: AtomicU64::default(),
+            dropped_no_subscribers: AtomicU64::default(),
+            drop_logged_at: AtomicU64::default(),
+            active_subscribers: AtomicU64::default(),
+            lagged: AtomicU64::default(),
+            closed: AtomicU64::default(),
+            exits: Default::default(),
+        }
+    }
+}
+
+// Counters and gauges scraped through /metrics
+// Cloning hands out another handle to the same counters, so streams can hold on to it after the request ends
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+impl Metrics {
+    pub fn message_posted(&self) {
+        self.0.messages_posted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Count a chat message nobody was subscribed to receive
+    // Returns how many were dropped so far for the first one and then at most once every DROP_LOG_INTERVAL, for the caller to log
+    pub fn dropped_no_subscribers(&self) -> Option<u64> {
+        let counters = &self.0;
+        let dropped = counters
+            .dropped_no_subscribers
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        let now = counters.started.elapsed().as_millis() as u64;
+        let logged_at = counters.drop_logged_at.load(Ordering::Relaxed);
+        let due = dropped == 1 || now - logged_at >= DROP_LOG_INTERVAL.as_millis() as u64;
+        // Only one of the posts racing past the interval gets to log
+        let claimed = due
+            && counters
+                .drop_logged_at
+                .compare_exchange(logged_at, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok();
+        claimed.then_some(dropped)
+    }
+
+    pub fn lagged(&self) {
+        self.0.lagged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn closed(&self) {
+        self.0.closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn exited(&self, reason: ExitReason) {
+        self.0.exits[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Chat messages accepted since the server started
+    pub fn posted(&self) -> u64 {
+        self.0.messages_posted.load(Ordering::Relaxed)
+    }
+
+    // Time since the server started (since the Metrics were created, at launch)
+    pub fn uptime(&self) -> Duration {
+        self.0.started.elapsed()
+    }
+
+    // Count an /events subscriber until the returned guard is dropped
+    pub fn subscribe(&self) -> SubscriberGuard {
+        self.0.active_subscribers.fetch_add(1, Ordering::Relaxed);
+        SubscriberGuard {
+            metrics: self.clone(),
+            exit: None,
+        }
+    }
+
+    fn unsubscribe(&self) {
+        self.0.active_subscribers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Everything in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+
+        let counters = &self.0;
+        metric(
+            "chat_messages_posted_total",
+            "counter",
+            "Chat messages accepted for broadcast.",
+            &counters.messages_posted,
+        );
+        metric(
+            "chat_messages_dropped_no_subscribers_total",
+            "counter",
+            "Chat messages stored but received by no subscriber.",
+            &counters.dropped_no_subscribers,
+        );
+        metric(
+            "chat_active_subscribers",
+            "gauge",
+            "Currently open /events streams.",
+            &counters.active_subscribers,
+        );
+        metric(
+            "chat_events_lagged_total",
+            "counter",
+            "Times an /events subscriber fell behind and skipped messages.",
+            &counters.lagged,
+        );
+        metric(
+            "chat_events_closed_total",
+            "counter",
+            "Times an /events stream ended because the channel closed.",
+            &counters.closed,
+        );
+
+        // One labeled series per reason, so they're all there (at 0) before the first stream ends
+        let name = "chat_events_exits_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} /events streams ended, by why they ended.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for reason in ExitReason::ALL {
+            let value = counters.exits[reason as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", name, reason.label(), value);
+        }
+
+        out
+    }
+}
+
+// Why an /events stream ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    // The server is shutting down
+    Shutdown,
+    // The broadcast channel closed, no one can send to it anymore
+    ChannelClosed,
+    // The client went away, Rocket dropped the stream without it ever breaking its loop
+    ClientDisconnect,
+    // The stream was open for max_stream_duration
+    IdleTimeout,
+    // A moderator banned the user
+    Banned,
+    // The client read too slowly and fell max_buffered_events behind
+    SlowConsumer,
+}
+
+impl ExitReason {
+    const ALL: [ExitReason; 6] = [
+        ExitReason::Shutdown,
+        ExitReason::ChannelClosed,
+        ExitReason::ClientDisconnect,
+        ExitReason::IdleTimeout,
+        ExitReason::Banned,
+        ExitReason::SlowConsumer,
+    ];
+
+    // Value of the `reason` label
+    pub fn label(self) -> &'static str {
+        match self {
+            ExitReason::Shutdown => "shutdown",
+            ExitReason::ChannelClosed => "channel_closed",
+            ExitReason::ClientDisconnect => "client_disconnect",
+            ExitReason::IdleTimeout => "idle_timeout",
+            ExitReason::Banned => "banned",
+            ExitReason::SlowConsumer => "slow_consumer",
+        }
+    }
+}
+
+// Keeps an /events subscriber counted while alive
+// Moved into the event stream like the PresenceGuard, so it drops on every way the stream can end
+// -- the loop records why it breaks with `exit`, a guard dropped without a reason means the client disconnected
+pub struct SubscriberGuard {
+    metrics: Metrics,
+    exit: Option<ExitReason>,
+}
+
+impl SubscriberGuard {
+    pub fn exit(&mut self, reason: ExitReason) {
+        self.exit = Some(reason);
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        let reason = self.exit.unwrap_or(ExitReason::ClientDisconnect);
+        debug!("/events stream ended: {}", reason.label());
+        self.metrics.exited(reason);
+        self.metrics.unsubscribe();
+    }
+}
+
+// Endpoint for Prometheus to scrape
+#[get("/metrics")]
+pub fn metrics(metrics: &State<Metrics>) -> (ContentType, String) {
+    let prometheus = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (prometheus, metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestChat, WAIT};
+    use rocket::http::Status;
+
+    fn exits(chat: &TestChat, reason: ExitReason) -> u64 {
+        let metrics = chat.client().rocket().state::<Metrics>().unwrap();
+        metrics.0.exits[reason as usize].load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn a_shutdown_counts_as_a_shutdown_exit() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        chat.client().rocket().shutdown().notify();
+
+        assert!(events.ended(WAIT));
+        assert_eq!(exits(&chat, ExitReason::Shutdown), 1);
+        assert_eq!(exits(&chat, ExitReason::ClientDisconnect), 0);
+    }
+
+    #[test]
+    fn streams_dropped_by_the_client_and_timed_out_are_told_apart() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.max_stream_duration", 1)));
+        let events = chat.events("room=lobby");
+        chat.block_on(async { drop(events) });
+        assert_eq!(exits(&chat, ExitReason::ClientDisconnect), 1);
+
+        let mut events = chat.events("room=lobby");
+        assert!(events.ended(WAIT));
+        assert_eq!(exits(&chat, ExitReason::IdleTimeout), 1);
+
+        let rendered = chat.send(chat.get("/metrics")).body;
+        assert!(rendered.contains("chat_events_exits_total{reason=\"client_disconnect\"} 1"));
+        assert!(rendered.contains("chat_events_exits_total{reason=\"idle_timeout\"} 1"));
+        assert!(rendered.contains("chat_events_exits_total{reason=\"shutdown\"} 0"));
+    }
+
+    #[test]
+    fn posts_nobody_receives_are_counted() {
+        let chat = TestChat::new();
+        assert_eq!(
+            chat.post("lobby", "alice", "anyone?"),
+            Status::ServiceUnavailable
+        );
+        assert_eq!(
+            chat.post("games", "alice", "hello?"),
+            Status::ServiceUnavailable
+        );
+        let _events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "hi"), Status::Ok);
+
+        let rendered = chat.send(chat.get("/metrics")).body;
+        assert!(rendered.contains("chat_messages_dropped_no_subscribers_total 2\n"));
+        assert!(rendered.contains("chat_messages_posted_total 3\n"));
+    }
+
+    #[test]
+    fn dropped_messages_are_logged_once_per_interval() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.dropped_no_subscribers(), Some(1));
+        assert_eq!(metrics.dropped_no_subscribers(), None);
+        assert_eq!(metrics.dropped_no_subscribers(), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+// Managed room -> usernames moderating it, granted by whoever holds the admin token through /admin/moderators
+// A room moderator may lock the room, put it in slow mode, close it, ban users from it and delete its messages, signing in with its session token (see Moderator)
+// Only registered usernames can moderate, and assignments only live in memory like the reservations they rely on
+#[derive(Default)]
+pub struct RoomModerators(Mutex<HashMap<String, HashSet<String>>>);
+
+impl RoomModerators {
+    pub fn set(&self, room: &str, username: &str, moderator: bool) {
+        let mut rooms = self.0.lock().unwrap();
+        if moderator {
+            rooms
+                .entry(room.to_string())
+                .or_default()
+                .insert(username.to_string());
+        } else if let Some(moderators) = rooms.get_mut(room) {
+            moderators.remove(username);
+            if moderators.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+
+    pub fn is_moderator(&self, room: &str, username: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(room)
+            .is_some_and(|moderators| moderators.contains(username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::TOKEN_HEADER;
+    use crate::testing::{as_admin, Reply, TestChat, WAIT};
+    use rocket::http::{Header, Status};
+    use rocket::serde::json::{json, Value};
+    use rocket::tokio::time::Duration;
+
+    // A chat where carol, registered with the returned session token, moderates lobby
+    fn chat() -> (TestChat, String) {
+        let chat = TestChat::with_admin();
+        let registered = chat
+            .send(chat.post_form("/register", "username=carol"))
+            .json();
+        let token = registered["token"].as_str().unwrap().to_string();
+        let request = as_admin(chat.post_json(
+            "/admin/moderators",
+            &json!({ "room": "lobby", "username": "carol", "moderator": true }),
+        ));
+        assert_eq!(chat.send(request).status, Status::NoContent);
+        (chat, token)
+    }
+
+    fn moderate(chat: &TestChat, token: &str, uri: &str, body: Value) -> Reply {
+        let request = chat
+            .post_json(uri, &body)
+            .header(Header::new(TOKEN_HEADER, token.to_string()));
+        chat.send(request)
+    }
+
+    #[test]
+    fn room_moderators_moderate_their_room_only() {
+        let (chat, token) = chat();
+        for (uri, body) in [
+            ("/admin/lock", json!({ "room": "Lobby", "locked": true })),
+            ("/admin/slowmode", json!({ "room": "lobby", "seconds": 10 })),
+        ] {
+            assert_eq!(moderate(&chat, &token, uri, body).status, Status::NoContent);
+        }
+        for (uri, body) in [
+            ("/admin/lock", json!({ "room": "games", "locked": true })),
+            ("/admin/slowmode", json!({ "room": "games", "seconds": 10 })),
+            ("/admin/close-room", json!({ "room": "games" })),
+        ] {
+            let reply = moderate(&chat, &token, uri, body);
+            assert_eq!(reply.status, Status::Forbidden, "{}", uri);
+            assert_eq!(reply.json()["error"], "you don't moderate games");
+        }
+
+        // Admin-only endpoints stay that way
+        let body = json!({ "room": "lobby", "username": "dave", "moderator": true });
+        let reply = moderate(&chat, &token, "/admin/moderators", body);
+        assert_eq!(reply.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn room_moderators_ban_from_their_room_only() {
+        let (chat, token) = chat();
+        let mut lobby = chat.events("room=lobby&username=mallory");
+        let mut games = chat.events("room=games&username=mallory");
+        let ban = json!({ "username": "mallory", "room": "lobby" });
+        assert_eq!(
+            moderate(&chat, &token, "/admin/ban", ban).status,
+            Status::NoContent
+        );
+
+        assert!(lobby.find("banned", WAIT).is_some());
+        assert!(lobby.ended(WAIT));
+        assert!(!games.ended(Duration::from_millis(1500)));
+        assert_eq!(chat.post("lobby", "mallory", "hi"), Status::Forbidden);
+        assert_eq!(chat.post("games", "mallory", "hi"), Status::Ok);
+        let reply = chat.send(chat.get("/events?room=lobby&username=mallory"));
+        assert_eq!(reply.status, Status::Forbidden);
+
+        for ban in [
+            json!({ "username": "mallory", "room": "games" }),
+            json!({ "username": "mallory" }),
+        ] {
+            let reply = moderate(&chat, &token, "/admin/ban", ban);
+            assert_eq!(reply.status, Status::Forbidden);
+        }
+        assert_eq!(chat.post("games", "mallory", "still here"), Status::Ok);
+    }
+}
@@ -0,0 +1,20 @@
+use std::sync::RwLock;
+
+// Managed message of the day, sent to every /events stream as it opens
+// Starts out as the configured motd, moderators can change or clear it through /admin/motd
+pub struct Motd(RwLock<Option<String>>);
+
+impl Motd {
+    pub fn new(motd: Option<String>) -> Motd {
+        Motd(RwLock::new(motd.filter(|motd| !motd.trim().is_empty())))
+    }
+
+    // Replace the message, a blank one clears it
+    pub fn set(&self, motd: Option<String>) {
+        *self.0.write().unwrap() = motd.filter(|motd| !motd.trim().is_empty());
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0.read().unwrap().clone()
+    }
+}
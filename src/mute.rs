@@ -0,0 +1,173 @@
+use crate::auth::SessionToken;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::message::{max_graphemes, not_reserved, safe_name, MAX_USERNAME_LEN};
+use rocket::form::{self, Form};
+use rocket::http::Status;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+// Who each user muted, username -> muted usernames
+// /events skips whatever a muted user sends for the user who muted them, everyone else still gets it
+// Mutes only live in memory, they are forgotten when the server restarts
+#[derive(Default)]
+pub struct Mutes(Mutex<HashMap<String, HashSet<String>>>);
+
+impl Mutes {
+    pub fn mute(&self, username: &str, muted: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_default()
+            .insert(muted.to_string());
+    }
+
+    pub fn unmute(&self, username: &str, muted: &str) {
+        let mut mutes = self.0.lock().unwrap();
+        if let Some(muted_by_user) = mutes.get_mut(username) {
+            muted_by_user.remove(muted);
+            if muted_by_user.is_empty() {
+                mutes.remove(username);
+            }
+        }
+    }
+
+    // Whether `username` muted `author`
+    pub fn is_muted(&self, username: &str, author: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(username)
+            .is_some_and(|muted| muted.contains(author))
+    }
+}
+
+// Form data accepted by /mute and /unmute
+// -- username -> who mutes, a reserved username needs its session token
+// -- muted -> who gets muted
+#[derive(Debug, FromForm)]
+pub struct MuteForm {
+    #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    #[field(validate = not_reserved())]
+    pub username: String,
+    #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    pub muted: String,
+}
+
+impl MuteForm {
+    // Parse the form and check the client may act as its username
+    fn authorize(
+        form: Result<Form<MuteForm>, form::Errors<'_>>,
+        token: &SessionToken,
+        chat: &Chat<'_>,
+    ) -> Result<MuteForm, ApiError> {
+        let form = form.map_err(ApiError::from_form)?.into_inner();
+        if !chat
+            .reservations
+            .authorize(&form.username, token.0.as_deref())
+        {
+            return Err(ApiError::new(
+                Status::Forbidden,
+                format!("username {} is reserved", form.username),
+            ));
+        }
+
+        Ok(form)
+    }
+}
+
+// Endpoint to Mute a User
+// From now on the /events streams of `username` skip the messages, typing notifications and reactions `muted` sends
+// Applies to streams already open, and to the history replayed when a stream (re)connects
+#[post("/mute", data = "<form>")]
+pub fn mute(
+    form: Result<Form<MuteForm>, form::Errors<'_>>,
+    token: SessionToken,
+    chat: Chat<'_>,
+) -> Result<Status, ApiError> {
+    let MuteForm { username, muted } = MuteForm::authorize(form, &token, &chat)?;
+    if username == muted {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "you can't mute yourself",
+        ));
+    }
+
+    chat.mutes.mute(&username, &muted);
+    Ok(Status::NoContent)
+}
+
+// Endpoint to Unmute a User
+// Answers 204 whether or not `muted` was muted
+#[post("/unmute", data = "<form>")]
+pub fn unmute(
+    form: Result<Form<MuteForm>, form::Errors<'_>>,
+    token: SessionToken,
+    chat: Chat<'_>,
+) -> Result<Status, ApiError> {
+    let MuteForm { username, muted } = MuteForm::authorize(form, &token, &chat)?;
+
+    chat.mutes.unmute(&username, &muted);
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageKind;
+    use crate::testing::{unlimited, Events, TestChat, WAIT};
+
+    // The senders of the next `n` chat messages a stream got, joins and leaves left out
+    fn senders(events: &mut Events<'_>, n: usize) -> Vec<String> {
+        let mut senders = Vec::new();
+        while senders.len() < n {
+            let Some(msg) = events.messages(1, WAIT).pop() else {
+                break;
+            };
+            if msg.kind == MessageKind::Chat {
+                senders.push(msg.username);
+            }
+        }
+        senders
+    }
+
+    #[test]
+    fn muted_users_are_skipped_only_by_who_muted_them() {
+        let chat = TestChat::configured(unlimited);
+        let mute = chat.post_form("/mute", "username=alice&muted=bob");
+        assert_eq!(chat.send(mute).status, Status::NoContent);
+        let mut alice = chat.events("room=lobby&username=alice");
+        let mut carol = chat.events("room=lobby&username=carol");
+
+        chat.post_id("lobby", "bob", "hi from bob");
+        chat.post_id("lobby", "dave", "hi from dave");
+        assert_eq!(senders(&mut alice, 1), ["dave"]);
+        assert_eq!(senders(&mut carol, 2), ["bob", "dave"]);
+
+        let unmute = chat.post_form("/unmute", "username=alice&muted=bob");
+        assert_eq!(chat.send(unmute).status, Status::NoContent);
+        chat.post_id("lobby", "bob", "back again");
+        assert_eq!(senders(&mut alice, 1), ["bob"]);
+    }
+
+    #[test]
+    fn muting_yourself_gets_422() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.post_form("/mute", "username=alice&muted=alice"));
+        assert_eq!(reply.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn unmuting_forgets_users_with_nobody_muted() {
+        let mutes = Mutes::default();
+        mutes.mute("alice", "bob");
+        assert!(mutes.is_muted("alice", "bob"));
+        assert!(!mutes.is_muted("bob", "alice"));
+        mutes.unmute("alice", "bob");
+        assert!(!mutes.is_muted("alice", "bob"));
+        assert!(mutes.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+use rocket::serde::json::{json, Value};
+
+// Endpoint to describe the API to integrators
+// A hand maintained OpenAPI 3 document of the main public routes, keep it in step with the handlers and the Message, MessageForm and PostResponse structs
+// -- Moderation (/admin, /debug) and operational routes (/healthz, /metrics...) are left out, see the README for those
+#[get("/openapi.json")]
+pub fn openapi() -> Value {
+    let error = json!({ "$ref": "#/components/responses/Error" });
+    let room = json!({
+        "name": "room", "in": "query", "required": true,
+        "schema": { "$ref": "#/components/schemas/Name" }
+    });
+    let limit = json!({
+        "name": "limit", "in": "query",
+        "description": "Most messages returned, history_limit when omitted",
+        "schema": { "type": "integer", "minimum": 0 }
+    });
+    let iso = json!({
+        "name": "iso", "in": "query",
+        "description": "Add an RFC 3339 timestamp_iso to every message",
+        "schema": { "type": "boolean", "default": false }
+    });
+    let messages = json!({
+        "description": "Messages, newest first",
+        "content": { "application/json": { "schema": {
+            "type": "array", "items": { "$ref": "#/components/schemas/Message" }
+        } } }
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "rust-realtime-chat",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/message": {
+                "post": {
+                    "summary": "Post a message to a room, or a direct message to a user",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/x-www-form-urlencoded": { "schema": { "$ref": "#/components/schemas/MessageForm" } },
+                            "application/json": { "schema": { "$ref": "#/components/schemas/MessageForm" } },
+                        }
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The message was accepted",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/PostResponse" } } }
+                        },
+                        "401": error, "403": error, "404": error, "413": error,
+                        "422": error, "429": error, "500": error, "503": error,
+                    }
+                }
+            },
+            "/messages": {
+                "post": {
+                    "summary": "Post several messages at once, each one is checked and published on its own",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": {
+                            "type": "array", "items": { "$ref": "#/components/schemas/MessageForm" }
+                        } } }
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "One result per message, in the same order",
+                            "content": { "application/json": { "schema": {
+                                "type": "array", "items": { "$ref": "#/components/schemas/BatchResult" }
+                            } } }
+                        },
+                        "401": error, "413": error, "422": error, "429": error,
+                    }
+                }
+            },
+            "/events": {
+                "get": {
+                    "summary": "Stream messages as server-sent events, replaying the recent history first",
+                    "description": "Chat messages are sent as \"message\" events carrying a Message, with its id as the event id. \
+                        Other events are \"system\", \"typing\", \"reaction\", \"edit\", \"delete\", \"stats\", \"ping\", \
+                        \"messages\" (several chat messages at once, with batch), \"mention\", \"room_closed\", \"lagged\", \"slow_consumer\", \"banned\", \"timeout\" and \"shutdown\".",
+                    "parameters": [
+                        {
+                            "name": "room", "in": "query",
+                            "description": "Only stream this room, every room when omitted",
+                            "schema": { "$ref": "#/components/schemas/Name" }
+                        },
+                        {
+                            "name": "username", "in": "query",
+                            "description": "Mark the user present in the room and receive their direct messages",
+                            "schema": { "$ref": "#/components/schemas/Name" }
+                        },
+                        iso,
+                        {
+                            "name": "format", "in": "query",
+                            "description": "What events carry: the whole Message as JSON, or only its body",
+                            "schema": { "type": "string", "enum": ["json", "text"], "default": "json" }
+                        },
+                        {
+                            "name": "keywords", "in": "query",
+                            "description": "Comma separated words, only chat messages containing one of them (ignoring case) are streamed",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "batch", "in": "query",
+                            "description": "Send chat messages arriving together as one \"messages\" event carrying an array of them, json format only",
+                            "schema": { "type": "boolean", "default": false }
+                        },
+                        {
+                            "name": "system_only", "in": "query",
+                            "description": "Only stream announcements (\"system\" and \"room_closed\" events), no chat and no replayed history",
+                            "schema": { "type": "boolean", "default": false }
+                        },
+                        {
+                            "name": "Last-Event-ID", "in": "header",
+                            "description": "Only replay the messages after this id",
+                            "schema": { "type": "integer" }
+                        },
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The event stream",
+                            "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                        },
+                        "400": error, "403": error, "503": error,
+                    }
+                }
+            },
+            "/history": {
+                "get": {
+                    "summary": "A page of the public messages of a room",
+                    "parameters": [
+                        room,
+                        {
+                            "name": "before", "in": "query",
+                            "description": "Only messages with a smaller id, the most recent ones when omitted",
+                            "schema": { "type": "integer" }
+                        },
+                        limit,
+                        iso,
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The page, newest first",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/HistoryPage" } } }
+                        },
+                        "422": error, "503": error,
+                    }
+                }
+            },
+            "/history/user/{username}": {
+                "get": {
+                    "summary": "The most recent public messages a user sent",
+                    "parameters": [
+                        {
+                            "name": "username", "in": "path", "required": true,
+                            "schema": { "$ref": "#/components/schemas/Name" }
+                        },
+                        {
+                            "name": "room", "in": "query",
+                            "description": "Only messages of this room, every room when omitted",
+                            "schema": { "$ref": "#/components/schemas/Name" }
+                        },
+                        limit,
+                        iso,
+                    ],
+                    "responses": { "200": messages, "422": error, "503": error }
+                }
+            },
+            "/search": {
+                "get": {
+                    "summary": "The most recent public messages of a room containing some text, ignoring case",
+                    "parameters": [
+                        room,
+                        { "name": "q", "in": "query", "required": true, "schema": { "type": "string" } },
+                        {
+                            "name": "username", "in": "query",
+                            "description": "Only messages this user sent",
+                            "schema": { "$ref": "#/components/schemas/Name" }
+                        },
+                        limit,
+                    ],
+                    "responses": { "200": messages, "422": error, "503": error }
+                }
+            },
+            "/rooms": {
+                "get": {
+                    "summary": "Every room with someone connected or with stored history, busiest first",
+                    "responses": {
+                        "200": {
+                            "description": "The rooms",
+                            "content": { "application/json": { "schema": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "required": ["room", "subscribers"],
+                                    "properties": {
+                                        "room": { "type": "string" },
+                                        "subscribers": { "type": "integer" },
+                                    }
+                                }
+                            } } }
+                        }
+                    }
+                }
+            },
+            "/users": {
+                "get": {
+                    "summary": "The users connected to a room",
+                    "parameters": [room],
+                    "responses": {
+                        "200": {
+                            "description": "Their usernames",
+                            "content": { "application/json": { "schema": {
+                                "type": "array", "items": { "type": "string" }
+                            } } }
+                        },
+                        "422": error,
+                    }
+                }
+            },
+        },
+        "components": {
+            "schemas": {
+                "Name": {
+                    "type": "string",
+                    "description": "A room or username: letters, digits, '_' and '-' only. Rooms ignore case",
+                    "pattern": "^[A-Za-z0-9_-]+$",
+                },
+                "MessageForm": {
+                    "type": "object",
+                    "required": ["room", "username", "message"],
+                    "properties": {
+                        "room": { "$ref": "#/components/schemas/Name" },
+                        "username": { "$ref": "#/components/schemas/Name" },
+                        "message": { "type": "string" },
+                        "to": { "type": "string", "description": "Recipient, turns the message into a direct message" },
+                        "markdown": { "type": "boolean", "description": "Render the body from markdown to HTML" },
+                        "client_msg_id": { "type": "string", "description": "Echoed back in the response and the broadcast" },
+                        "reply_to": { "type": "integer", "description": "Id of the message of the same room this one replies to" },
+                        "attachment_url": { "type": "string", "format": "uri", "maxLength": 2047, "description": "An http or https URL of a file to share" },
+                    }
+                },
+                "Message": {
+                    "type": "object",
+                    "required": ["id", "timestamp", "room", "username", "message", "system", "kind"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "seq": { "type": "integer", "description": "Number of a public chat message within its room, starting at 1" },
+                        "timestamp": { "type": "integer", "description": "Unix time in milliseconds" },
+                        "room": { "type": "string" },
+                        "username": { "type": "string" },
+                        "message": { "type": "string" },
+                        "to": { "type": "string" },
+                        "system": { "type": "boolean" },
+                        "kind": { "type": "string", "enum": ["chat", "system", "typing", "reaction", "edit", "delete", "mention", "room_closed"] },
+                        "target": { "type": "integer", "description": "For a reaction, edit, delete or mention, the id of the message it is about" },
+                        "html": { "type": "string" },
+                        "deleted": { "type": "boolean" },
+                        "edited_at": { "type": "integer" },
+                        "reactions": { "type": "object", "additionalProperties": { "type": "integer" } },
+                        "client_msg_id": { "type": "string" },
+                        "timestamp_iso": { "type": "string", "format": "date-time" },
+                        "reply_to": { "type": "integer" },
+                        "origin_instance": { "type": "string" },
+                        "attachment_url": { "type": "string", "format": "uri" },
+                        "signature": { "type": "string", "description": "HMAC of a message posted with the API key, see the README" },
+                    }
+                },
+                "PostResponse": {
+                    "type": "object",
+                    "required": ["id", "timestamp"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "timestamp": { "type": "integer", "description": "Unix time in milliseconds" },
+                        "client_msg_id": { "type": "string" },
+                        "deduped": { "type": "boolean" },
+                    }
+                },
+                "BatchResult": {
+                    "type": "object",
+                    "required": ["status"],
+                    "properties": {
+                        "status": { "type": "string", "enum": ["posted", "failed"] },
+                        "id": { "type": "integer" },
+                        "timestamp": { "type": "integer" },
+                        "client_msg_id": { "type": "string" },
+                        "deduped": { "type": "boolean" },
+                        "error": { "type": "string" },
+                        "code": { "type": "integer" },
+                    }
+                },
+                "HistoryPage": {
+                    "type": "object",
+                    "required": ["messages", "has_more"],
+                    "properties": {
+                        "messages": { "type": "array", "items": { "$ref": "#/components/schemas/Message" } },
+                        "has_more": { "type": "boolean" },
+                        "next_before": { "type": "integer", "nullable": true },
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error", "code"],
+                    "properties": {
+                        "error": { "type": "string" },
+                        "code": { "type": "integer" },
+                    }
+                },
+            },
+            "responses": {
+                "Error": {
+                    "description": "The request was refused, `error` says why",
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestChat;
+    use rocket::http::Status;
+
+    #[test]
+    fn the_document_lists_the_main_routes() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.get("/openapi.json"));
+        assert_eq!(reply.status, Status::Ok);
+        let document = reply.json();
+        assert_eq!(document["openapi"], "3.0.3");
+        assert_eq!(document["info"]["version"], env!("CARGO_PKG_VERSION"));
+
+        let paths = document["paths"].as_object().unwrap();
+        for path in ["/message", "/events", "/history"] {
+            assert!(paths.contains_key(path), "{} is missing", path);
+        }
+
+        // Every documented path is one of the mounted routes, i.e /history/user/{username} is /history/user/<username>
+        let routes: Vec<String> = chat
+            .client()
+            .rocket()
+            .routes()
+            .map(|route| route.uri.path().to_string())
+            .collect();
+        for path in paths.keys() {
+            let path = path.replace('{', "<").replace('}', ">");
+            assert!(routes.contains(&path), "{} isn't a route", path);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::filter::WordFilter;
+use crate::markdown;
+use crate::message::graphemes;
+use rocket::serde::Deserialize;
+
+// The steps message bodies go through before they're published, in this order unless `pipeline` is configured
+pub const DEFAULT_PIPELINE: [Step; 4] = [Step::Trim, Step::Length, Step::Filter, Step::Markdown];
+
+// One step of the pipeline a message body goes through on /message, /messages, /ws and edits, see `pipeline`
+// -- Trim -> strip the leading and trailing whitespace
+// -- Length -> reject a body longer than max_message_len characters, counted as graphemes
+// -- Filter -> censor the blacklisted words, see WordFilter
+// -- Markdown -> render the body to sanitized HTML, when the post or render_markdown asks for it
+// Left out of the pipeline, a step doesn't happen at all (i.e without "length" a body is only limited by max_body_size)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Step {
+    Trim,
+    Length,
+    Filter,
+    Markdown,
+}
+
+// A body going through the pipeline
+// -- message -> the body as the steps so far left it
+// -- markdown -> whether it's to be rendered
+// -- html -> its rendering, once the Markdown step ran
+#[derive(Debug, Clone)]
+pub struct Draft {
+    pub message: String,
+    pub markdown: bool,
+    pub html: Option<String>,
+}
+
+// What the steps go by besides the draft itself
+pub struct Context<'a> {
+    pub filter: &'a WordFilter,
+    pub max_len: usize,
+}
+
+impl Step {
+    // Run this step on `draft`, failing with why the message is rejected
+    pub fn apply(self, mut draft: Draft, context: &Context) -> Result<Draft, String> {
+        match self {
+            Step::Trim => draft.message = draft.message.trim().to_string(),
+            Step::Length => {
+                let len = graphemes(&draft.message);
+                if len > context.max_len {
+                    return Err(format!(
+                        "message must be at most {} characters, got {}",
+                        context.max_len, len
+                    ));
+                }
+            }
+            Step::Filter => draft.message = context.filter.censor(&draft.message),
+            Step::Markdown => {
+                draft.html = draft.markdown.then(|| markdown::render(&draft.message));
+            }
+        }
+
+        Ok(draft)
+    }
+}
+
+// Run `draft` through `steps` in order, stopping at the first one rejecting it
+pub fn run(steps: &[Step], draft: Draft, context: &Context) -> Result<Draft, String> {
+    steps
+        .iter()
+        .try_fold(draft, |draft, step| step.apply(draft, context))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{unlimited, TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+
+    fn draft(message: &str) -> Draft {
+        Draft {
+            message: message.to_string(),
+            markdown: true,
+            html: None,
+        }
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let filter = WordFilter::new(["darn".to_string()]);
+        let context = Context {
+            filter: &filter,
+            max_len: 10,
+        };
+        let steps = [Step::Trim, Step::Filter, Step::Length];
+
+        let done = run(&steps, draft("   Darn it   "), &context).unwrap();
+        assert_eq!(done.message, "**** it");
+        assert_eq!(done.html, None);
+
+        // Measured before trimming, the padding counts
+        let padded = run(
+            &[Step::Length, Step::Trim],
+            draft("   Darn it   "),
+            &context,
+        );
+        assert_eq!(
+            padded.unwrap_err(),
+            "message must be at most 10 characters, got 13"
+        );
+    }
+
+    #[test]
+    fn the_first_rejection_stops_the_pipeline() {
+        let filter = WordFilter::new(Vec::new());
+        let context = Context {
+            filter: &filter,
+            max_len: 3,
+        };
+        let rejected = run(&DEFAULT_PIPELINE, draft("**too long**"), &context);
+        assert!(rejected.is_err());
+
+        let context = Context {
+            max_len: 100,
+            ..context
+        };
+        let rendered = run(&DEFAULT_PIPELINE, draft(" **bold** "), &context).unwrap();
+        assert_eq!(rendered.message, "**bold**");
+        assert_eq!(
+            rendered.html.as_deref(),
+            Some("<p><strong>bold</strong></p>\n")
+        );
+    }
+
+    #[test]
+    fn posts_go_through_the_configured_pipeline() {
+        let chat = TestChat::configured(|figment| {
+            unlimited(figment)
+                .merge(("chat.pipeline", ["filter", "length"]))
+                .merge(("chat.blacklist", ["darn"]))
+                .merge(("chat.max_message_len", 10))
+        });
+        let mut events = chat.events("room=lobby");
+        let body =
+            json!({ "room": "lobby", "username": "alice", "message": "darn it", "markdown": true });
+        assert_eq!(
+            chat.send(chat.post_json("/message", &body)).status,
+            Status::Ok
+        );
+
+        let msg = events.messages(1, WAIT).remove(0);
+        assert_eq!(msg.message, "**** it");
+        assert_eq!(msg.html, None);
+        assert_eq!(
+            chat.post("lobby", "alice", "much too long"),
+            Status::UnprocessableEntity
+        );
+    }
+}
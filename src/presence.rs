@@ -0,0 +1,340 @@
+use crate::channels::Channels;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::message::{check_name, normalize_room, now_millis, Message, MessageIds};
+use crate::templates::{render, Templates};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Who is currently connected to /events, per room
+// -- room -> username -> the user's open streams (the same user may have several tabs open)
+// -- templates -> how joins and leaves are announced
+// Cloning hands out another handle to the same map, so streams can hold on to it after the request ends
+#[derive(Clone)]
+pub struct Presence {
+    rooms: Arc<Mutex<HashMap<String, Users>>>,
+    streams: Arc<AtomicU64>,
+    templates: Arc<Templates>,
+}
+
+// The users present in a room, username -> their open streams
+type Users = BTreeMap<String, Vec<Stream>>;
+
+// One open stream of a present user
+// -- id -> tells the user's streams apart, so the right one is removed when it ends
+// -- connected_at -> unix time in milliseconds the stream opened at
+// -- user_agent -> the client's User-Agent header, see UserAgent
+struct Stream {
+    id: u64,
+    connected_at: u64,
+    user_agent: Option<String>,
+}
+
+// A stream open in a room, as listed by /admin/users
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PresentStream {
+    pub username: String,
+    pub connected_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+// Error returned by Presence::join when the room already has as many streams open as its cap allows
+#[derive(Debug)]
+pub struct RoomFull;
+
+impl Presence {
+    pub fn new(templates: Templates) -> Presence {
+        Presence {
+            rooms: Arc::default(),
+            streams: Arc::default(),
+            templates: Arc::new(templates),
+        }
+    }
+
+    // The announcement `template` makes for `username` in `room`
+    fn announcement(template: &str, room: &str, username: &str) -> String {
+        render(template, &[("username", username), ("room", room)])
+    }
+
+    // Record `username` as connected to `room`, through a client sending `user_agent`, until the returned guard is dropped
+    // The room is told through `queue`, once now that the user joined and again when the guard drops
+    // Fails with RoomFull, without joining, when `room` already has `cap` streams open
+    pub fn join(
+        &self,
+        room: &str,
+        username: &str,
+        user_agent: Option<String>,
+        cap: Option<usize>,
+        ids: &MessageIds,
+        queue: &Channels,
+    ) -> Result<PresenceGuard, RoomFull> {
+        let id = self.streams.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut rooms = self.rooms.lock().unwrap();
+            let users = rooms.entry(room.to_string()).or_default();
+            if cap.is_some_and(|cap| users.values().map(Vec::len).sum::<usize>() >= cap) {
+                if users.is_empty() {
+                    rooms.remove(room);
+                }
+                return Err(RoomFull);
+            }
+            users.entry(username.to_string()).or_default().push(Stream {
+                id,
+                connected_at: now_millis(),
+                user_agent,
+            });
+        }
+
+        let joined = Presence::announcement(&self.templates.joined, room, username);
+        let _res = queue.send(Message::system(ids.next(), room, joined));
+
+        Ok(PresenceGuard {
+            presence: self.clone(),
+            room: room.to_string(),
+            username: username.to_string(),
+            stream: id,
+            ids: ids.clone(),
+            queue: queue.clone(),
+        })
+    }
+
+    fn leave(&self, room: &str, username: &str, stream: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(users) = rooms.get_mut(room) {
+            if let Some(streams) = users.get_mut(username) {
+                streams.retain(|open| open.id != stream);
+                if streams.is_empty() {
+                    users.remove(username);
+                }
+            }
+            if users.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+
+    // Number of open streams in every room someone is connected to
+    pub fn rooms(&self) -> HashMap<String, usize> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .map(|(room, users)| (room.clone(), users.values().map(Vec::len).sum()))
+            .collect()
+    }
+
+    // Whether `username` has a stream open in `room`
+    pub fn is_present(&self, room: &str, username: &str) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(room)
+            .is_some_and(|users| users.contains_key(username))
+    }
+
+    // Usernames connected to `room`, sorted alphabetically
+    pub fn users(&self, room: &str) -> Vec<String> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(room)
+            .map(|users| users.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Every stream open in `room`, by username and then oldest first
+    pub fn streams(&self, room: &str) -> Vec<PresentStream> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(users) = rooms.get(room) else {
+            return Vec::new();
+        };
+        users
+            .iter()
+            .flat_map(|(username, streams)| {
+                streams.iter().map(|stream| PresentStream {
+                    username: username.clone(),
+                    connected_at: stream.connected_at,
+                    user_agent: stream.user_agent.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Managed state capping how many streams may be present in a room at once
+// -- every room gets the max_users_per_room from the config, moderators can override it per room through /admin/roomcap
+// -- None means there is no cap
+pub struct RoomCaps {
+    default: Option<usize>,
+    rooms: Mutex<HashMap<String, Option<usize>>>,
+}
+
+impl RoomCaps {
+    pub fn new(default: Option<usize>) -> RoomCaps {
+        RoomCaps {
+            default,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Set the cap of `room`, overriding the configured one
+    pub fn set(&self, room: &str, cap: Option<usize>) {
+        self.rooms.lock().unwrap().insert(room.to_string(), cap);
+    }
+
+    // The cap of `room`
+    pub fn get(&self, room: &str) -> Option<usize> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+// Keeps a user marked as present while alive and announces the user left once dropped
+// It is moved into the event stream, so it drops on every way the stream can end
+// -- the loop breaking on shutdown or a closed channel, or Rocket dropping the stream when the client disconnects
+pub struct PresenceGuard {
+    presence: Presence,
+    room: String,
+    username: String,
+    stream: u64,
+    ids: MessageIds,
+    queue: Channels,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        self.presence.leave(&self.room, &self.username, self.stream);
+
+        let left =
+            Presence::announcement(&self.presence.templates.left, &self.room, &self.username);
+        let _res = self
+            .queue
+            .send(Message::system(self.ids.next(), &self.room, left));
+    }
+}
+
+// Endpoint to list the users currently connected to `room`
+#[get("/users?<room>")]
+pub fn users(room: String, presence: &State<Presence>) -> Result<Json<Vec<String>>, ApiError> {
+    check_name("room", &room).map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+
+    Ok(Json(presence.users(&normalize_room(&room))))
+}
+
+// A room listed by /rooms
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RoomInfo {
+    pub room: String,
+    pub subscribers: usize,
+}
+
+// Endpoint to discover rooms
+// Every room with someone connected or with stored history, busiest first
+#[get("/rooms")]
+pub async fn rooms(chat: Chat<'_>) -> Result<Json<Vec<RoomInfo>>, ApiError> {
+    let mut counts = chat.presence.rooms();
+    let stored = chat.store.rooms().await.map_err(|e| {
+        error!("failed to list stored rooms: {}", e);
+        ApiError::new(Status::InternalServerError, "failed to list rooms")
+    })?;
+    for room in stored {
+        counts.entry(room).or_default();
+    }
+
+    let mut rooms: Vec<RoomInfo> = counts
+        .into_iter()
+        .map(|(room, subscribers)| RoomInfo { room, subscribers })
+        .collect();
+    rooms.sort_by(|a, b| {
+        b.subscribers
+            .cmp(&a.subscribers)
+            .then_with(|| a.room.cmp(&b.room))
+    });
+
+    Ok(Json(rooms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{as_admin, TestChat, WAIT};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn a_dropped_client_leaves_the_room() {
+        let chat = TestChat::new();
+        let mut bob = chat.events("room=lobby&username=bob");
+        let alice = chat.events("room=lobby&username=alice");
+        assert_eq!(
+            chat.send(chat.get("/users?room=lobby")).json(),
+            json!(["alice", "bob"])
+        );
+
+        // The tab closing drops the stream, which takes its PresenceGuard with it
+        chat.block_on(async { drop(alice) });
+        assert_eq!(
+            chat.send(chat.get("/users?room=lobby")).json(),
+            json!(["bob"])
+        );
+        let presence = chat.client().rocket().state::<Presence>().unwrap();
+        assert_eq!(presence.rooms().get("lobby"), Some(&1));
+        let left = bob
+            .messages(3, WAIT)
+            .into_iter()
+            .map(|msg| msg.message)
+            .find(|body| body.contains("left"));
+        assert_eq!(left.as_deref(), Some("alice left lobby"));
+
+        chat.block_on(async { drop(bob) });
+        assert_eq!(chat.send(chat.get("/users?room=lobby")).json(), json!([]));
+        assert_eq!(presence.rooms().get("lobby"), None);
+    }
+
+    #[test]
+    fn a_full_room_turns_the_next_stream_down() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.max_users_per_room", 2)));
+        let _alice = chat.events("room=lobby&username=alice");
+        let _bob = chat.events("room=lobby&username=bob");
+
+        let reply = chat.send(chat.get("/events?room=lobby&username=carol"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+        assert_eq!(
+            reply.json(),
+            json!({ "error": "room lobby is full", "code": 503 })
+        );
+
+        // Other rooms, and streams that don't say who they are, aren't counted
+        let _carol = chat.events("room=games&username=carol");
+        let _anonymous = chat.events("room=lobby");
+    }
+
+    #[test]
+    fn room_caps_can_be_changed_per_room() {
+        let chat = TestChat::with_admin();
+        let cap = |max_users: Option<usize>| {
+            let request = as_admin(chat.post_json(
+                "/admin/roomcap",
+                &json!({ "room": "lobby", "max_users": max_users }),
+            ));
+            assert_eq!(chat.send(request).status, Status::NoContent);
+        };
+
+        cap(Some(1));
+        let _alice = chat.events("room=lobby&username=alice");
+        let reply = chat.send(chat.get("/events?room=lobby&username=bob"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+
+        cap(None);
+        let _bob = chat.events("room=lobby&username=bob");
+    }
+}
@@ -0,0 +1,95 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use rocket::State;
+use std::sync::Arc;
+
+// Managed state capping how many read-heavy store queries (/history, /search, /admin/export...) run at once
+// Past the cap requests are turned down right away instead of queuing, so a burst of reads can't take every pooled connection from posting
+// -- None means there is no cap
+// -- posting and the live broadcast never take a slot
+pub struct QueryLimit(Option<Arc<Semaphore>>);
+
+impl QueryLimit {
+    pub fn new(max: Option<usize>) -> QueryLimit {
+        QueryLimit(max.map(|max| Arc::new(Semaphore::new(max.min(Semaphore::MAX_PERMITS)))))
+    }
+}
+
+// Request guard holding one of the QueryLimit's slots
+// -- fails with 503 Service Unavailable when all the slots are taken
+// The slot is freed when the guard drops, move it into a response stream to hold it until the stream ends
+pub struct QuerySlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for QuerySlot {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limit = match req.guard::<&State<QueryLimit>>().await {
+            Outcome::Success(limit) => limit,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        match &limit.0 {
+            None => Outcome::Success(QuerySlot { _permit: None }),
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Outcome::Success(QuerySlot {
+                    _permit: Some(permit),
+                }),
+                Err(_) => Outcome::Error((Status::ServiceUnavailable, ())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestChat;
+    use rocket::futures::future::join_all;
+
+    #[test]
+    fn queries_past_max_concurrent_queries_get_503() {
+        let chat =
+            TestChat::configured(|figment| figment.merge(("chat.max_concurrent_queries", 2)));
+        let _events = chat.events("room=lobby");
+        chat.post_id("lobby", "alice", "hi");
+
+        // Two slow queries hold every slot while a burst comes in
+        let request = chat.get("/history?room=lobby");
+        let held = chat.block_on(async {
+            let first = QuerySlot::from_request(request.inner()).await.succeeded();
+            let second = QuerySlot::from_request(request.inner()).await.succeeded();
+            (first.unwrap(), second.unwrap())
+        });
+        let burst = chat.block_on(join_all((0..10).map(|i| {
+            let uri = match i % 2 {
+                0 => "/history?room=lobby",
+                _ => "/search?room=lobby&q=hi",
+            };
+            chat.get(uri).dispatch()
+        })));
+        assert!(burst
+            .iter()
+            .all(|reply| reply.status() == Status::ServiceUnavailable));
+
+        // Posting never waits on a slot
+        assert_eq!(chat.post("lobby", "alice", "still posting"), Status::Ok);
+
+        drop(held);
+        let reply = chat.send(chat.get("/history?room=lobby"));
+        assert_eq!(reply.status, Status::Ok);
+    }
+
+    #[test]
+    fn queries_are_uncapped_by_default() {
+        let chat = TestChat::new();
+        let burst = chat.block_on(join_all(
+            (0..20).map(|_| chat.get("/history?room=lobby").dispatch()),
+        ));
+        assert!(burst.iter().all(|reply| reply.status() == Status::Ok));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::guards::ClientIp;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A token bucket per client
+// -- tokens refill continuously at `rate` per second up to `burst`
+// -- every accepted request takes one token, a request finding the bucket empty is rejected
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+// What a RateLimiter limits, so each kind of request gets its own limits and buckets in managed state
+// -- Messages -> posts to /message
+// -- Typing -> typing notifications to /typing, sent far more often than messages
+pub struct Messages;
+pub struct Typing;
+
+// How often a RateLimiter forgets the buckets that refilled, see RateLimiter::sweep
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Managed state tracking one bucket for every client IP that has sent requests of kind `K`
+pub struct RateLimiter<K = Messages> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    last_sweep: Mutex<Instant>,
+    kind: PhantomData<K>,
+}
+
+impl<K> RateLimiter<K> {
+    pub fn new(per_second: u32, burst: u32) -> RateLimiter<K> {
+        RateLimiter {
+            rate: per_second as f64,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+            kind: PhantomData,
+        }
+    }
+
+    // Take a token from the bucket of `ip`, returns false when it is exhausted
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.sweep(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Once per SWEEP_INTERVAL, forget the buckets that refilled since they were last used
+    // A full bucket is what a new client gets anyway, so dropping it changes nothing but keeps the map from growing with every client ever seen
+    fn sweep(&self, now: Instant) {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if now.duration_since(*last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        *last_sweep = now;
+
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+}
+
+// Request guard enforcing the RateLimiter of kind `K`, fails with 429 Too Many Requests once a client is over its limit
+// Requests without a known remote address aren't limited
+pub struct RateLimited<K = Messages>(PhantomData<K>);
+
+#[rocket::async_trait]
+impl<'r, K: Send + Sync + 'static> FromRequest<'r> for RateLimited<K> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limiter = match req.guard::<&State<RateLimiter<K>>>().await {
+            Outcome::Success(limiter) => limiter,
+            _ => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        match ClientIp::of(req) {
+            Some(ip) if !limiter.check(ip) => Outcome::Error((Status::TooManyRequests, ())),
+            _ => Outcome::Success(RateLimited(PhantomData)),
+        }
+    }
+}
+
+// Managed state capping how many distinct usernames a single client IP may post as within a window
+// Without accounts nothing stops a client from switching usernames on every post, to spam or impersonate
+// -- remembers when each IP last posted as each of its usernames, a username not used for `window` no longer counts
+// -- `max` of None turns the cap off
+pub struct UsernameLimiter {
+    max: Option<usize>,
+    window: Duration,
+    seen: Mutex<HashMap<IpAddr, HashMap<String, Instant>>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl UsernameLimiter {
+    pub fn new(max: Option<usize>, window_secs: u64) -> UsernameLimiter {
+        UsernameLimiter {
+            max: max.map(|max| max.max(1)),
+            window: Duration::from_secs(window_secs.max(1)),
+            seen: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Record a post by `ip` as `username`
+    // Returns how long until the IP may use another username instead when it already used `max` others within the window,
+    // the username isn't recorded then
+    pub fn check(&self, ip: IpAddr, username: &str) -> Result<(), Duration> {
+        let Some(max) = self.max else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        self.sweep(now);
+        let mut seen = self.seen.lock().unwrap();
+        let usernames = seen.entry(ip).or_default();
+        usernames.retain(|_, at| now.duration_since(*at) < self.window);
+
+        if !usernames.contains_key(username) && usernames.len() >= max {
+            let oldest = usernames.values().min().copied().unwrap_or(now);
+            return Err(self.window.saturating_sub(now.duration_since(oldest)));
+        }
+        usernames.insert(username.to_string(), now);
+
+        Ok(())
+    }
+
+    // Once per window, forget the IPs that haven't posted within it, keeps the map from growing with every client ever seen
+    fn sweep(&self, now: Instant) {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if now.duration_since(*last_sweep) < self.window {
+            return;
+        }
+        *last_sweep = now;
+
+        self.seen.lock().unwrap().retain(|_, usernames| {
+            usernames.retain(|_, at| now.duration_since(*at) < self.window);
+            !usernames.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{unlimited, TestChat};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+    use std::net::Ipv4Addr;
+
+    const ALICE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn a_burst_empties_the_bucket_of_that_ip_only() {
+        let limiter = RateLimiter::<Messages>::new(1, 3);
+        assert!((0..3).all(|_| limiter.check(ALICE)));
+        assert!(!limiter.check(ALICE));
+        assert!(limiter.check(BOB));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::<Messages>::new(20, 1);
+        assert!(limiter.check(ALICE));
+        assert!(!limiter.check(ALICE));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(limiter.check(ALICE));
+    }
+
+    #[test]
+    fn refilled_buckets_are_swept() {
+        let limiter = RateLimiter::<Messages>::new(1, 100);
+        limiter.check(ALICE);
+        (0..100).for_each(|_| {
+            limiter.check(BOB);
+        });
+
+        // Alice's bucket refills in a second, Bob's takes a hundred
+        let now = Instant::now() + SWEEP_INTERVAL;
+        limiter.sweep(now);
+        let ips: Vec<_> = limiter.buckets.lock().unwrap().keys().copied().collect();
+        assert_eq!(ips, [BOB]);
+
+        limiter.sweep(now + SWEEP_INTERVAL);
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn username_limiter_caps_usernames_per_ip() {
+        let limiter = UsernameLimiter::new(Some(2), 60);
+        assert!(limiter.check(ALICE, "alice").is_ok());
+        assert!(limiter.check(ALICE, "alice2").is_ok());
+        assert!(limiter.check(ALICE, "alice").is_ok());
+        assert!(limiter.check(ALICE, "alice3").is_err());
+        assert!(limiter.check(BOB, "alice3").is_ok());
+    }
+
+    #[test]
+    fn stale_usernames_are_swept() {
+        let limiter = UsernameLimiter::new(Some(1), 60);
+        assert!(limiter.check(ALICE, "alice").is_ok());
+        limiter.sweep(Instant::now() + Duration::from_secs(61));
+        assert!(limiter.seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cycling_usernames_past_the_cap_gets_429() {
+        let chat = TestChat::configured(|figment| {
+            unlimited(figment)
+                .merge(("chat.max_usernames_per_ip", 3))
+                .merge(("chat.username_window", 60))
+        });
+        let _events = chat.events("room=lobby");
+        for username in ["one", "two", "three"] {
+            assert_eq!(chat.post("lobby", username, "hi"), Status::Ok);
+        }
+
+        let body = json!({ "room": "lobby", "username": "four", "message": "hi" });
+        let reply = chat.send(chat.post_json("/message", &body));
+        assert_eq!(reply.status, Status::TooManyRequests);
+        assert_eq!(reply.header("Retry-After"), Some("60"));
+        assert_eq!(chat.post("lobby", "one", "still me"), Status::Ok);
+    }
+
+    #[test]
+    fn posts_with_the_api_key_can_use_any_number_of_usernames() {
+        let chat = TestChat::configured(|figment| {
+            unlimited(figment)
+                .merge(("chat.max_usernames_per_ip", 1))
+                .merge(("chat.api_key", "bot-key"))
+        });
+        let _events = chat.events("room=lobby");
+        for username in ["one", "two"] {
+            let body = json!({ "room": "lobby", "username": username, "message": "hi" });
+            let request = chat
+                .post_json("/message", &body)
+                .header(rocket::http::Header::new("X-API-Key", "bot-key"));
+            assert_eq!(chat.send(request).status, Status::Ok);
+        }
+    }
+}
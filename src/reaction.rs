@@ -0,0 +1,108 @@
+use crate::auth::SessionToken;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::guards::ClientIp;
+use crate::message::{check_name, graphemes, Message, MAX_USERNAME_LEN, SYSTEM_USERNAME};
+use crate::rate_limit::RateLimited;
+use rocket::http::Status;
+use rocket::serde::json::{self, Json};
+use rocket::serde::Deserialize;
+
+// Upper bound (exclusive) on the length of an emoji, in bytes
+// Generous enough for multi codepoint emoji such as flags and skin tones
+pub const MAX_EMOJI_LEN: usize = 32;
+
+// JSON body accepted by /react, i.e {"message_id":12,"username":"alice","emoji":"👍"}
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReactionForm {
+    pub message_id: u64,
+    pub username: String,
+    pub emoji: String,
+}
+
+impl ReactionForm {
+    // Same username rules as a MessageForm, and an emoji that is a single non blank token
+    fn validate(&self) -> Result<(), String> {
+        if self.username.is_empty() || graphemes(&self.username) >= MAX_USERNAME_LEN {
+            return Err(format!(
+                "username must be between 1 and {} characters",
+                MAX_USERNAME_LEN - 1
+            ));
+        }
+        check_name("username", &self.username)?;
+        if self.username == SYSTEM_USERNAME {
+            return Err("username is reserved".into());
+        }
+        if self.emoji.is_empty() || self.emoji.len() >= MAX_EMOJI_LEN {
+            return Err(format!(
+                "emoji must be between 1 and {} bytes",
+                MAX_EMOJI_LEN - 1
+            ));
+        }
+        if self.emoji.chars().any(char::is_whitespace) {
+            return Err("emoji can't contain whitespace".into());
+        }
+
+        Ok(())
+    }
+}
+
+// Endpoint to React to a Message
+// Records the reaction in the store and broadcasts a Message of kind Reaction, received as a "reaction" event on /events
+// -- Shares the /message rate limit, a reserved username still needs its session token and banned users get a 403
+// -- 404 when there is no stored message with that id, it was deleted or it is a direct message the user isn't part of
+// -- Reacting again with the same emoji is accepted but only counted once in /history
+#[post("/react", format = "json", data = "<json>")]
+pub async fn react(
+    _limit: RateLimited,
+    json: Result<Json<ReactionForm>, json::Error<'_>>,
+    token: SessionToken,
+    ip: ClientIp,
+    chat: Chat<'_>,
+) -> Result<Status, ApiError> {
+    let ClientIp(ip) = ip;
+    let form = json
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e.to_string()))?
+        .into_inner();
+    form.validate()
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    chat.check_ban(None, Some(&form.username), ip)?;
+    if !chat
+        .reservations
+        .authorize(&form.username, token.0.as_deref())
+    {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            format!("username {} is reserved", form.username),
+        ));
+    }
+
+    let storage = |e: sqlx::Error| {
+        error!("failed to store reaction to {}: {}", form.message_id, e);
+        ApiError::new(Status::InternalServerError, "failed to store reaction")
+    };
+    let target = chat
+        .store
+        .get(form.message_id)
+        .await
+        .map_err(storage)?
+        .filter(|msg| !msg.deleted && msg.visible_to(None, Some(&form.username)))
+        .ok_or_else(|| {
+            ApiError::new(
+                Status::NotFound,
+                format!("no message with id {}", form.message_id),
+            )
+        })?;
+    chat.check_ban(Some(&target.room), Some(&form.username), ip)?;
+    chat.store
+        .react(target.id, &form.username, &form.emoji)
+        .await
+        .map_err(storage)?;
+
+    // The reaction is stored, nobody listening just means nobody to tell right now
+    let msg = Message::reaction(chat.ids.next(), &target, form.username, form.emoji);
+    let _res = chat.queue.send(msg);
+
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,126 @@
+use crate::channels::Channels;
+use crate::config::ChatConfig;
+use crate::message::Message;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client, RedisResult};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::futures::StreamExt;
+use rocket::serde::json;
+use rocket::tokio::select;
+use rocket::tokio::time::{sleep, Duration};
+use rocket::{Build, Orbit, Rocket, Shutdown};
+
+// Relays chat messages between instances through a Redis pub/sub channel
+// -- Chat::publish sends every accepted message to Redis instead of straight to the broadcast channels
+// -- each instance runs `forward`, which receives them all back (its own included) and sends them to its local channels
+// Only managed when built with the `redis` feature and `redis_url` is configured
+pub struct Relay {
+    client: Client,
+    conn: MultiplexedConnection,
+    channel: String,
+}
+
+impl Relay {
+    // Connect to the Redis server at `url`, messages are relayed through `channel`
+    pub async fn connect(url: &str, channel: &str) -> RedisResult<Relay> {
+        let client = Client::open(url)?;
+        let conn = client.get_multiplexed_async_connection().await?;
+
+        Ok(Relay {
+            client,
+            conn,
+            channel: channel.to_string(),
+        })
+    }
+
+    // Send `msg` to every instance subscribed to the channel
+    pub async fn publish(&self, msg: &Message) -> RedisResult<()> {
+        let payload = json::to_string(msg).expect("a Message always serializes");
+        let _receivers: i64 = self.conn.clone().publish(&self.channel, payload).await?;
+
+        Ok(())
+    }
+
+    // Spawn the task moving messages from the Redis channel into `queue`, until the server shuts down
+    // The subscription is retried every second while Redis is unreachable
+    pub fn forward(&self, queue: Channels, mut end: Shutdown) {
+        let client = self.client.clone();
+        let channel = self.channel.clone();
+
+        rocket::tokio::spawn(async move {
+            loop {
+                select! {
+                    res = subscribe(&client, &channel, &queue) => {
+                        if let Err(e) = res {
+                            error!("redis subscription to {} failed: {}", channel, e);
+                        }
+                    },
+                    _ = &mut end => break,
+                }
+
+                select! {
+                    _ = sleep(Duration::from_secs(1)) => {},
+                    _ = &mut end => break,
+                }
+            }
+        });
+    }
+}
+
+// Forward everything published on `channel` to `queue` until the subscription ends
+// Payloads that aren't a Message are logged and skipped
+async fn subscribe(client: &Client, channel: &str, queue: &Channels) -> RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    info!("relaying messages through redis channel {}", channel);
+
+    let mut messages = pubsub.on_message();
+    while let Some(payload) = messages.next().await {
+        let payload: String = payload.get_payload()?;
+        match json::from_str::<Message>(&payload) {
+            // Nobody listening on this instance just means nobody to tell
+            Ok(msg) => {
+                let _res = queue.send(msg);
+            }
+            Err(e) => warn!("ignoring malformed message from redis: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+// Fairing setting up the Relay when `redis_url` is configured
+// -- on ignite, connects and manages the Relay, failing the launch when Redis is unreachable
+// -- on liftoff, starts forwarding into the broadcast channels
+pub struct RelayFairing;
+
+#[rocket::async_trait]
+impl Fairing for RelayFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Redis Relay",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = rocket.state::<ChatConfig>().unwrap();
+        let Some(url) = config.redis_url.clone() else {
+            return Ok(rocket);
+        };
+
+        match Relay::connect(&url, &config.redis_channel).await {
+            Ok(relay) => Ok(rocket.manage(relay)),
+            Err(e) => {
+                error!("failed to connect to redis {}: {}", url, e);
+                Err(rocket)
+            }
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let (Some(relay), Some(queue)) = (rocket.state::<Relay>(), rocket.state::<Channels>()) {
+            relay.forward(queue.clone(), rocket.shutdown());
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use crate::config::ChatConfig;
+use crate::message::now_millis;
+use crate::store::Store;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::select;
+use rocket::tokio::time::{interval, Duration};
+use rocket::{Orbit, Rocket};
+
+// Fairing keeping the message store from growing forever
+// On liftoff, when `retention_max_age` or `retention_max_messages` is configured, spawns a task
+// pruning the store every `retention_interval` seconds (and once right away) until the server shuts down
+pub struct Retention;
+
+#[rocket::async_trait]
+impl Fairing for Retention {
+    fn info(&self) -> Info {
+        Info {
+            name: "Message Retention",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (Some(config), Some(store)) = (rocket.state::<ChatConfig>(), rocket.state::<Store>())
+        else {
+            return;
+        };
+        let max_age = config.retention_max_age.map(Duration::from_secs);
+        let max_messages = config.retention_max_messages;
+        if max_age.is_none() && max_messages.is_none() {
+            return;
+        }
+
+        let store = store.clone();
+        let mut end = rocket.shutdown();
+        let mut ticks = interval(Duration::from_secs(config.retention_interval.max(1)));
+        rocket::tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = ticks.tick() => {
+                        let cutoff = max_age.map(|age| now_millis().saturating_sub(age.as_millis() as u64));
+                        match store.prune(cutoff, max_messages).await {
+                            Ok(0) => {}
+                            Ok(pruned) => info!("pruned {} old messages", pruned),
+                            Err(e) => error!("failed to prune old messages: {}", e),
+                        }
+                    },
+                    _ = &mut end => break,
+                }
+            }
+        });
+    }
+}
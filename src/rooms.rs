@@ -0,0 +1,119 @@
+use crate::channels::Channels;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Error returned by RoomRegistry::admit when a new room would go over max_rooms
+#[derive(Debug)]
+pub struct TooManyRooms;
+
+// Managed registry of the rooms in use, so clients can't make the server keep track of an unbounded number of them
+// A room is created by the first post or stream to it and stays registered while it's used
+// -- once max_rooms are registered, a new room is only admitted if an idle one can be evicted to make space:
+//    nobody subscribed to it and nothing posted to it for room_idle_timeout seconds
+// -- rooms that already exist keep working whatever happens to new ones
+// Only lives in memory, rooms with stored history count again once someone uses them after a restart
+pub struct RoomRegistry {
+    max: Option<usize>,
+    idle: Option<Duration>,
+    rooms: Mutex<HashMap<String, Instant>>,
+}
+
+impl RoomRegistry {
+    // No `max` means no limit, an `idle_secs` of 0 never evicts rooms
+    pub fn new(max: Option<usize>, idle_secs: u64) -> RoomRegistry {
+        RoomRegistry {
+            max,
+            idle: (idle_secs > 0).then(|| Duration::from_secs(idle_secs)),
+            rooms: Mutex::default(),
+        }
+    }
+
+    // Record activity in `room`, registering it first if it's new
+    // Fails when it's new and there's no space for it, even after evicting idle rooms
+    pub fn admit(&self, room: &str, queue: &Channels) -> Result<(), TooManyRooms> {
+        let Some(max) = self.max else {
+            return Ok(());
+        };
+        let mut rooms = self.rooms.lock().unwrap();
+        let now = Instant::now();
+        if let Some(active) = rooms.get_mut(room) {
+            *active = now;
+            return Ok(());
+        }
+
+        if rooms.len() >= max {
+            if let Some(idle) = self.idle {
+                rooms.retain(|room, active| {
+                    now.duration_since(*active) < idle || queue.subscribers(Some(room)) > 0
+                });
+            }
+        }
+        if rooms.len() >= max {
+            return Err(TooManyRooms);
+        }
+
+        rooms.insert(room.to_string(), now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{unlimited, TestChat};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn new_rooms_past_max_rooms_get_503() {
+        let chat = TestChat::configured(|figment| {
+            unlimited(figment)
+                .merge(("chat.max_rooms", 2))
+                .merge(("chat.room_idle_timeout", 0))
+        });
+        let _a = chat.events("room=a");
+        let _b = chat.events("room=b");
+
+        let reply = chat.send(chat.get("/events?room=c"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+        assert_eq!(
+            reply.json()["error"],
+            "too many rooms in use, c can't be created"
+        );
+        let body = json!({ "room": "c", "username": "alice", "message": "hi" });
+        let reply = chat.send(chat.post_json("/message", &body));
+        assert_eq!(
+            reply.json()["error"],
+            "too many rooms in use, c can't be created"
+        );
+
+        // The rooms in use keep working
+        assert_eq!(chat.post("a", "alice", "hi"), Status::Ok);
+        let _more = chat.events("room=b");
+    }
+
+    #[test]
+    fn idle_rooms_are_evicted_to_make_space() {
+        let chat = TestChat::configured(|figment| {
+            unlimited(figment)
+                .merge(("chat.max_rooms", 2))
+                .merge(("chat.room_idle_timeout", 1))
+        });
+        let _a = chat.events("room=a");
+        let b = chat.events("room=b");
+        chat.block_on(async { drop(b) });
+
+        // b has no subscribers left but was just used
+        let reply = chat.send(chat.get("/events?room=c"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+
+        // a is idle too by now, but still has a subscriber, so b makes space
+        sleep(Duration::from_millis(1100));
+        let _c = chat.events("room=c");
+        let reply = chat.send(chat.get("/events?room=b"));
+        assert_eq!(reply.status, Status::ServiceUnavailable);
+        assert_eq!(chat.post("a", "alice", "still here"), Status::Ok);
+    }
+}
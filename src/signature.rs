@@ -0,0 +1,123 @@
+use crate::message::Message;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Signs the messages posted with the api_key, so consumers holding the signing_secret can tell they came through an authenticated path
+// The signature is the base64 HMAC-SHA256, keyed with the secret, of these fields joined by "\n", in this order:
+// -- id, timestamp, room, username, to, reply_to, attachment_url, message
+// -- missing optional fields are empty strings, the body comes last so its own line breaks can't be confused with separators
+// Editing a message drops its signature, the new body wasn't posted with the key
+pub struct Signer(Hmac<Sha256>);
+
+impl Signer {
+    pub fn new(secret: &str) -> Signer {
+        Signer(Hmac::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length"))
+    }
+
+    pub fn sign(&self, msg: &Message) -> String {
+        let mut mac = self.0.clone();
+        mac.update(signed_fields(msg).as_bytes());
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+}
+
+// What Signer::sign covers, see above
+fn signed_fields(msg: &Message) -> String {
+    let optional = |field: Option<String>| field.unwrap_or_default();
+    [
+        msg.id.to_string(),
+        msg.timestamp.to_string(),
+        msg.room.clone(),
+        msg.username.clone(),
+        optional(msg.to.clone()),
+        optional(msg.reply_to.map(|id| id.to_string())),
+        optional(msg.attachment_url.clone()),
+        msg.message.clone(),
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::API_KEY_HEADER;
+    use crate::testing::{TestChat, WAIT};
+    use rocket::http::Header;
+    use rocket::serde::json::json;
+
+    const SECRET: &str = "signing-secret";
+
+    // What a consumer holding the secret does: rebuild the fields and check the MAC over them
+    fn verifies(msg: &Message, secret: &str) -> bool {
+        let fields = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            msg.id,
+            msg.timestamp,
+            msg.room,
+            msg.username,
+            msg.to.as_deref().unwrap_or_default(),
+            msg.reply_to.map(|id| id.to_string()).unwrap_or_default(),
+            msg.attachment_url.as_deref().unwrap_or_default(),
+            msg.message
+        );
+        let Some(signature) = msg.signature.as_deref() else {
+            return false;
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(fields.as_bytes());
+        mac.verify_slice(&STANDARD.decode(signature).unwrap())
+            .is_ok()
+    }
+
+    #[test]
+    fn signatures_cover_every_signed_field() {
+        let signer = Signer::new(SECRET);
+        let mut msg = Message {
+            username: "bot".into(),
+            to: Some("alice".into()),
+            attachment_url: Some("https://example.com/graph.png".into()),
+            ..Message::system(7, "alerts", "disk full\non db-1".into())
+        };
+        msg.signature = Some(signer.sign(&msg));
+        assert!(verifies(&msg, SECRET));
+        assert!(!verifies(&msg, "another-secret"));
+
+        msg.to = None;
+        assert!(!verifies(&msg, SECRET));
+    }
+
+    #[test]
+    fn posts_with_the_api_key_are_signed() {
+        let chat = TestChat::configured(|figment| {
+            figment
+                .merge(("chat.api_key", "bot-key"))
+                .merge(("chat.signing_secret", SECRET))
+        });
+        let mut events = chat.events("room=alerts");
+        let body = json!({ "room": "alerts", "username": "bot", "message": "disk full" });
+        let request = chat
+            .post_json("/message", &body)
+            .header(Header::new(API_KEY_HEADER, "bot-key"));
+        let reply = chat.send(request).json();
+
+        let msg = events.messages(1, WAIT).remove(0);
+        assert_eq!(msg.id, reply["id"].as_u64().unwrap());
+        assert!(verifies(&msg, SECRET));
+    }
+
+    #[test]
+    fn nothing_is_signed_without_a_signing_secret() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.api_key", "bot-key")));
+        let mut events = chat.events("room=alerts");
+        let body = json!({ "room": "alerts", "username": "bot", "message": "disk full" });
+        let request = chat
+            .post_json("/message", &body)
+            .header(Header::new(API_KEY_HEADER, "bot-key"));
+        chat.send(request);
+
+        let msg = events.messages(1, WAIT).remove(0);
+        assert_eq!(msg.signature, None);
+    }
+}
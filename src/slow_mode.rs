@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Managed state throttling how often a single user may post to a room
+// -- every room gets the slow_mode interval from the config, moderators can override it per room through /admin/slowmode
+// -- an interval of 0 turns slow mode off
+// -- remembers until when each (room, username) has to wait, only for rooms in slow mode
+pub struct SlowMode {
+    default: Duration,
+    rooms: Mutex<HashMap<String, Duration>>,
+    waits: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl SlowMode {
+    pub fn new(default_secs: u64) -> SlowMode {
+        SlowMode {
+            default: Duration::from_secs(default_secs),
+            rooms: Mutex::new(HashMap::new()),
+            waits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Set the interval of `room`, overriding the configured one
+    pub fn set(&self, room: &str, secs: u64) {
+        let interval = Duration::from_secs(secs);
+        self.rooms
+            .lock()
+            .unwrap()
+            .insert(room.to_string(), interval);
+        if interval.is_zero() {
+            self.waits.lock().unwrap().retain(|(r, _), _| r != room);
+        }
+    }
+
+    fn interval(&self, room: &str) -> Duration {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    // Record a post by `username` to `room`
+    // Returns how long the user still has to wait instead when they posted there too recently, the post isn't recorded then
+    pub fn check(&self, room: &str, username: &str) -> Result<(), Duration> {
+        let interval = self.interval(room);
+        if interval.is_zero() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut waits = self.waits.lock().unwrap();
+        // Forget waits that are over, keeps the map from growing with every user ever seen
+        waits.retain(|_, until| *until > now);
+
+        let key = (room.to_string(), username.to_string());
+        if let Some(until) = waits.get(&key) {
+            return Err(*until - now);
+        }
+        waits.insert(key, now + interval);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{as_admin, unlimited, TestChat};
+    use rocket::http::Status;
+    use rocket::serde::json::json;
+
+    #[test]
+    fn a_user_waits_between_posts_to_a_slowed_room() {
+        let slow_mode = SlowMode::new(0);
+        assert!(slow_mode.check("lobby", "alice").is_ok());
+        assert!(slow_mode.check("lobby", "alice").is_ok());
+
+        slow_mode.set("lobby", 10);
+        assert!(slow_mode.check("lobby", "alice").is_ok());
+        let wait = slow_mode.check("lobby", "alice").unwrap_err();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+        assert!(slow_mode.check("lobby", "bob").is_ok());
+        assert!(slow_mode.check("games", "alice").is_ok());
+
+        slow_mode.set("lobby", 0);
+        assert!(slow_mode.check("lobby", "alice").is_ok());
+    }
+
+    #[test]
+    fn posting_too_soon_gets_429_with_retry_after() {
+        let chat = TestChat::with_admin();
+        let _events = chat.events("room=lobby");
+        let request = as_admin(chat.post_json(
+            "/admin/slowmode",
+            &json!({ "room": "lobby", "seconds": 10 }),
+        ));
+        assert_eq!(chat.send(request).status, Status::NoContent);
+
+        assert_eq!(chat.post("lobby", "alice", "first"), Status::Ok);
+        let body = json!({ "room": "lobby", "username": "alice", "message": "second" });
+        let reply = chat.send(chat.post_json("/message", &body));
+        assert_eq!(reply.status, Status::TooManyRequests);
+        assert_eq!(reply.header("Retry-After"), Some("10"));
+        assert_eq!(
+            reply.json()["error"],
+            "lobby is in slow mode, wait 10s before posting again"
+        );
+        assert_eq!(chat.post("lobby", "bob", "hello"), Status::Ok);
+    }
+
+    #[test]
+    fn slow_mode_comes_from_the_configuration() {
+        let chat = TestChat::configured(|figment| unlimited(figment).merge(("chat.slow_mode", 5)));
+        let _events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "first"), Status::Ok);
+        assert_eq!(
+            chat.post("lobby", "alice", "second"),
+            Status::TooManyRequests
+        );
+    }
+}
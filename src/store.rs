@@ -0,0 +1,736 @@
+use crate::cipher::BodyCipher;
+use crate::message::{Message, MessageKind, DELETED_PLACEHOLDER};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+// Columns selected for a MessageRow
+const COLUMNS: &str =
+    "id, timestamp, room, username, message, recipient, html, deleted, edited_at, reply_to,
+    origin_instance, encrypted, attachment_url, signature, seq";
+
+// Which messages a query may return
+// -- public messages, of the requested room (?1) or of every room when it's NULL
+// -- direct messages, whatever their room, only when the viewer (?2) sent or received them
+const VISIBLE: &str = "((recipient IS NULL AND (?1 IS NULL OR room = ?1))
+    OR (recipient IS NOT NULL AND (recipient = ?2 OR username = ?2)))";
+
+// A row of the messages table
+// Only chat messages are stored, server announcements are live only
+// SQLite only knows signed integers so ids and timestamps are stored as i64
+// `encrypted` rows hold their body and HTML sealed by a BodyCipher, see Store::open
+#[derive(sqlx::FromRow)]
+struct MessageRow {
+    id: i64,
+    timestamp: i64,
+    room: String,
+    username: String,
+    message: String,
+    recipient: Option<String>,
+    html: Option<String>,
+    deleted: bool,
+    edited_at: Option<i64>,
+    reply_to: Option<i64>,
+    origin_instance: Option<String>,
+    encrypted: bool,
+    attachment_url: Option<String>,
+    signature: Option<String>,
+    seq: Option<i64>,
+}
+
+impl From<MessageRow> for Message {
+    fn from(row: MessageRow) -> Message {
+        Message {
+            id: row.id as u64,
+            seq: row.seq.map(|seq| seq as u64),
+            timestamp: row.timestamp as u64,
+            room: row.room,
+            username: row.username,
+            message: row.message,
+            to: row.recipient,
+            system: false,
+            kind: MessageKind::Chat,
+            target: None,
+            html: row.html,
+            deleted: row.deleted,
+            edited_at: row.edited_at.map(|at| at as u64),
+            reactions: BTreeMap::new(),
+            client_msg_id: None,
+            timestamp_iso: None,
+            reply_to: row.reply_to.map(|id| id as u64),
+            origin_instance: row.origin_instance,
+            attachment_url: row.attachment_url,
+            signature: row.signature,
+        }
+    }
+}
+
+// SQLite backed message history
+// Every message accepted by /message is inserted here so late joiners can catch up
+// Cloning hands out another handle to the same pool, i.e for background tasks
+// -- with a `cipher` message bodies are encrypted on insert and edit and decrypted on read, see store_encryption_key
+#[derive(Clone)]
+pub struct Store {
+    pool: SqlitePool,
+    cipher: Option<BodyCipher>,
+}
+
+impl Store {
+    // Open (or create) the database at `url` and make sure the schema exists
+    // Bodies are stored encrypted with `cipher` when given, rows stored before stay readable as they are
+    pub async fn connect(url: &str, cipher: Option<BodyCipher>) -> Result<Store, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                room TEXT NOT NULL,
+                username TEXT NOT NULL,
+                message TEXT NOT NULL,
+                recipient TEXT,
+                html TEXT,
+                deleted INTEGER NOT NULL DEFAULT 0,
+                edited_at INTEGER,
+                reply_to INTEGER,
+                origin_instance TEXT,
+                encrypted INTEGER NOT NULL DEFAULT 0,
+                attachment_url TEXT,
+                signature TEXT,
+                seq INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Columns added after the table was first created, for databases made by an older version
+        add_column(&pool, "recipient", "TEXT").await?;
+        add_column(&pool, "html", "TEXT").await?;
+        add_column(&pool, "deleted", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column(&pool, "edited_at", "INTEGER").await?;
+        add_column(&pool, "reply_to", "INTEGER").await?;
+        add_column(&pool, "origin_instance", "TEXT").await?;
+        add_column(&pool, "encrypted", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column(&pool, "attachment_url", "TEXT").await?;
+        add_column(&pool, "signature", "TEXT").await?;
+        add_column(&pool, "seq", "INTEGER").await?;
+
+        // Rooms became case-insensitive (see normalize_room), fold the rooms of older messages into their lowercase room
+        sqlx::query("UPDATE messages SET room = lower(room) WHERE room != lower(room)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id)")
+            .execute(&pool)
+            .await?;
+
+        // One row per user and emoji they reacted to a message with, reacting twice with the same emoji counts once
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS reactions (
+                message_id INTEGER NOT NULL REFERENCES messages (id),
+                username TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                PRIMARY KEY (message_id, username, emoji)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Store { pool, cipher })
+    }
+
+    // Cheap round trip to check the database is reachable
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    // Highest message id stored so far, 0 for an empty database
+    pub async fn last_id(&self) -> Result<u64, sqlx::Error> {
+        let (id,): (Option<i64>,) = sqlx::query_as("SELECT MAX(id) FROM messages")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(id.unwrap_or_default() as u64)
+    }
+
+    // The highest seq stored in every room, see RoomSequences
+    // Messages stored before seqs existed have none, so their rooms start again from 1
+    pub async fn last_seqs(&self) -> Result<HashMap<String, u64>, sqlx::Error> {
+        let rooms: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT room, MAX(seq) FROM messages WHERE seq IS NOT NULL GROUP BY room",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rooms
+            .into_iter()
+            .map(|(room, seq)| (room, seq as u64))
+            .collect())
+    }
+
+    // Every room with at least one public message stored
+    pub async fn rooms(&self) -> Result<Vec<String>, sqlx::Error> {
+        let rooms: Vec<(String,)> =
+            sqlx::query_as("SELECT DISTINCT room FROM messages WHERE recipient IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rooms.into_iter().map(|(room,)| room).collect())
+    }
+
+    // The stored message with this id, None when there is no such message
+    pub async fn get(&self, id: u64) -> Result<Option<Message>, sqlx::Error> {
+        let sql = format!("SELECT {} FROM messages WHERE id = ?", COLUMNS);
+        let row: Option<MessageRow> = sqlx::query_as(&sql)
+            .bind(id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        row.map(|row| self.open(row)).transpose()
+    }
+
+    // Record that `username` reacted to message `id` with `emoji`
+    pub async fn react(&self, id: u64, username: &str, emoji: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO reactions (message_id, username, emoji) VALUES (?, ?, ?)",
+        )
+        .bind(id as i64)
+        .bind(username)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Overwrite the stored body, HTML rendering and edit time of message `msg.id` with those of `msg`
+    pub async fn edit(&self, msg: &Message) -> Result<(), sqlx::Error> {
+        let (message, html) = self.seal(msg);
+        sqlx::query(
+            "UPDATE messages SET message = ?, html = ?, edited_at = ?, encrypted = ?, signature = ? WHERE id = ?",
+        )
+        .bind(message)
+        .bind(html)
+        .bind(msg.edited_at.map(|at| at as i64))
+        .bind(self.cipher.is_some())
+        .bind(&msg.signature)
+        .bind(msg.id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Turn message `id` into a tombstone, its row stays so ids and history keep their order but the body is gone
+    pub async fn delete(&self, id: u64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE messages SET message = ?, html = NULL, deleted = 1, encrypted = 0, signature = NULL WHERE id = ?",
+        )
+        .bind(DELETED_PLACEHOLDER)
+        .bind(id as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn insert(&self, msg: &Message) -> Result<(), sqlx::Error> {
+        let (message, html) = self.seal(msg);
+        sqlx::query(
+            "INSERT INTO messages (id, timestamp, room, username, message, recipient, html, reply_to, origin_instance, encrypted, attachment_url, signature, seq)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(msg.id as i64)
+        .bind(msg.timestamp as i64)
+        .bind(&msg.room)
+        .bind(&msg.username)
+        .bind(message)
+        .bind(&msg.to)
+        .bind(html)
+        .bind(msg.reply_to.map(|id| id as i64))
+        .bind(&msg.origin_instance)
+        .bind(self.cipher.is_some())
+        .bind(&msg.attachment_url)
+        .bind(&msg.signature)
+        .bind(msg.seq.map(|seq| seq as i64))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // The last `limit` messages of `room` (or of every room when `None`)
+    // Direct messages are only included when `viewer` sent or received them
+    // Returned oldest first, in the order they were inserted
+    pub async fn recent(
+        &self,
+        room: Option<&str>,
+        viewer: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {columns} FROM (
+                SELECT {columns} FROM messages
+                WHERE {visible}
+                ORDER BY id DESC
+                LIMIT ?3
+            ) ORDER BY id ASC",
+            columns = COLUMNS,
+            visible = VISIBLE,
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(room)
+            .bind(viewer)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        self.with_reactions(rows).await
+    }
+
+    // A page of up to `limit` public messages of `room` with an id below `before` (the most recent ones when None)
+    // Returned newest first, along with whether there are older messages left
+    pub async fn page(
+        &self,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<(Vec<Message>, bool), sqlx::Error> {
+        // One row more than asked for tells whether there is another page
+        let sql = format!(
+            "SELECT {} FROM messages
+            WHERE {} AND (?3 IS NULL OR id < ?3)
+            ORDER BY id DESC
+            LIMIT ?4",
+            COLUMNS, VISIBLE,
+        );
+        let mut rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(room)
+            .bind(None::<&str>)
+            .bind(before.map(|id| id as i64))
+            .bind(limit as i64 + 1)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+        rows.reverse();
+        let mut messages = self.with_reactions(rows).await?;
+        messages.reverse();
+
+        Ok((messages, has_more))
+    }
+
+    // Every message of `room` (or of every room when `None`) with an id greater than `after`
+    // Direct messages are only included when `viewer` sent or received them
+    // Used to fill the gap when a client reconnects, returned oldest first
+    pub async fn since(
+        &self,
+        room: Option<&str>,
+        viewer: Option<&str>,
+        after: u64,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {} FROM messages WHERE {} AND id > ?3 ORDER BY id ASC",
+            COLUMNS, VISIBLE,
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(room)
+            .bind(viewer)
+            .bind(after as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        self.with_reactions(rows).await
+    }
+
+    // Up to `limit` public messages of `room` with an id greater than `after`, deleted ones included
+    // Returned oldest first, read through page by page to go over a whole room
+    pub async fn chunk(
+        &self,
+        room: &str,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {} FROM messages WHERE {} AND id > ?3 ORDER BY id ASC LIMIT ?4",
+            COLUMNS, VISIBLE,
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(room)
+            .bind(None::<&str>)
+            .bind(after as i64)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        self.with_reactions(rows).await
+    }
+
+    // The last `limit` public messages of `room` whose body contains `query`, ignoring (ASCII) case
+    // -- `username` only keeps the messages that user sent
+    // -- SQLite can't look into encrypted bodies, with a cipher every message of the room is read and matched here instead
+    // Returned newest first
+    pub async fn search(
+        &self,
+        room: &str,
+        query: &str,
+        username: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        // LIKE treats % and _ as wildcards, escape them so they match literally
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let sql = format!(
+            "SELECT {columns} FROM (
+                SELECT {columns} FROM messages
+                WHERE {visible} AND deleted = 0 AND (?3 IS NULL OR message LIKE ?3 ESCAPE '\\') AND (?4 IS NULL OR username = ?4)
+                ORDER BY id DESC
+                LIMIT ?5
+            ) ORDER BY id ASC",
+            columns = COLUMNS,
+            visible = VISIBLE,
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(room)
+            .bind(None::<&str>)
+            .bind(self.cipher.is_none().then_some(pattern))
+            .bind(username)
+            .bind(if self.cipher.is_some() {
+                -1
+            } else {
+                limit as i64
+            })
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut messages = self.with_reactions(rows).await?;
+        messages.reverse();
+        if self.cipher.is_some() {
+            let query = query.to_ascii_lowercase();
+            messages.retain(|msg| msg.message.to_ascii_lowercase().contains(&query));
+            messages.truncate(limit);
+        }
+        Ok(messages)
+    }
+
+    // The last `limit` public messages `username` sent, to `room` or to any room when `None`
+    // Deleted messages are left out, returned newest first
+    pub async fn by_user(
+        &self,
+        username: &str,
+        room: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {columns} FROM (
+                SELECT {columns} FROM messages
+                WHERE {visible} AND deleted = 0 AND username = ?3
+                ORDER BY id DESC
+                LIMIT ?4
+            ) ORDER BY id ASC",
+            columns = COLUMNS,
+            visible = VISIBLE,
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(room)
+            .bind(None::<&str>)
+            .bind(username)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut messages = self.with_reactions(rows).await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    // Delete messages (and their reactions) sent before `cutoff` (unix time in milliseconds)
+    // and all but the newest `max_per_room` of every room, returning how many messages were deleted
+    // The newest message is always kept, the id counter restarts after it on launch and ids must never be reused
+    pub async fn prune(
+        &self,
+        cutoff: Option<u64>,
+        max_per_room: Option<usize>,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query(
+            "DELETE FROM messages WHERE id < (SELECT MAX(id) FROM messages) AND (
+                timestamp < ?1
+                OR id IN (
+                    SELECT id FROM (
+                        SELECT id, ROW_NUMBER() OVER (PARTITION BY room ORDER BY id DESC) AS newer
+                        FROM messages
+                    ) WHERE newer > ?2
+                )
+            )",
+        )
+        .bind(cutoff.map(|at| at as i64))
+        .bind(max_per_room.map(|max| max as i64))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM reactions WHERE message_id NOT IN (SELECT id FROM messages)")
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    // The body and HTML to store for `msg`, encrypted when there is a cipher
+    // Goes along with the `encrypted` flag of the row, see open
+    fn seal(&self, msg: &Message) -> (String, Option<String>) {
+        match &self.cipher {
+            Some(cipher) => (
+                cipher.encrypt(&msg.message),
+                msg.html.as_deref().map(|html| cipher.encrypt(html)),
+            ),
+            None => (msg.message.clone(), msg.html.clone()),
+        }
+    }
+
+    // The Message stored in `row`, decrypting its body and HTML if they were stored encrypted
+    // Fails when they were and there is no cipher, or not the one they were encrypted with
+    fn open(&self, mut row: MessageRow) -> Result<Message, sqlx::Error> {
+        if row.encrypted {
+            let Some(cipher) = &self.cipher else {
+                return Err(sqlx::Error::Decode(
+                    format!(
+                        "message {} is encrypted but no store_encryption_key is configured",
+                        row.id
+                    )
+                    .into(),
+                ));
+            };
+            let decrypt = |sealed: &str| {
+                cipher
+                    .decrypt(sealed)
+                    .map_err(|e| sqlx::Error::Decode(format!("message {}: {}", row.id, e).into()))
+            };
+            row.message = decrypt(&row.message)?;
+            row.html = row.html.as_deref().map(decrypt).transpose()?;
+        }
+
+        Ok(Message::from(row))
+    }
+
+    // Turn rows (ordered by id) into Messages with their reaction counts filled in
+    // A single query over the id range the rows span, counts for ids not among the rows are ignored
+    async fn with_reactions(&self, rows: Vec<MessageRow>) -> Result<Vec<Message>, sqlx::Error> {
+        let mut messages = rows
+            .into_iter()
+            .map(|row| self.open(row))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (Some(first), Some(last)) = (messages.first(), messages.last()) else {
+            return Ok(messages);
+        };
+
+        let counts: Vec<(i64, String, i64)> = sqlx::query_as(
+            "SELECT message_id, emoji, COUNT(*) FROM reactions
+            WHERE message_id BETWEEN ? AND ?
+            GROUP BY message_id, emoji",
+        )
+        .bind(first.id as i64)
+        .bind(last.id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (id, emoji, count) in counts {
+            if let Ok(i) = messages.binary_search_by_key(&(id as u64), |msg| msg.id) {
+                messages[i].reactions.insert(emoji, count as u64);
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+// Add `column` to the messages table unless it's already there
+async fn add_column(pool: &SqlitePool, column: &str, decl: &str) -> Result<(), sqlx::Error> {
+    let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info('messages')")
+        .fetch_all(pool)
+        .await?;
+
+    if !columns.iter().any(|(name,)| name == column) {
+        sqlx::query(&format!(
+            "ALTER TABLE messages ADD COLUMN {} {}",
+            column, decl
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::now_millis;
+    use std::path::PathBuf;
+
+    // A store on a fresh database in the temp directory, removed when dropped
+    struct TestStore {
+        store: Store,
+        path: PathBuf,
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.path.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    async fn store(cipher: Option<BodyCipher>) -> TestStore {
+        let path =
+            std::env::temp_dir().join(format!("store-test-{:016x}.db", rand::random::<u64>()));
+        let url = format!("sqlite://{}", path.display());
+        let store = Store::connect(&url, cipher).await.unwrap();
+        TestStore { store, path }
+    }
+
+    fn chat(id: u64, room: &str, username: &str, body: &str, timestamp: u64) -> Message {
+        Message {
+            username: username.to_string(),
+            system: false,
+            kind: MessageKind::Chat,
+            timestamp,
+            ..Message::system(id, room, body.to_string())
+        }
+    }
+
+    fn ids(messages: &[Message]) -> Vec<u64> {
+        messages.iter().map(|msg| msg.id).collect()
+    }
+
+    #[rocket::async_test]
+    async fn pruning_by_age_keeps_recent_messages() {
+        let test = store(None).await;
+        let now = now_millis();
+        let day = 24 * 3600 * 1000;
+        test.store
+            .insert(&chat(1, "lobby", "alice", "old", now - 2 * day))
+            .await
+            .unwrap();
+        test.store
+            .insert(&chat(2, "other", "alice", "old", now - 2 * day))
+            .await
+            .unwrap();
+        test.store
+            .insert(&chat(3, "lobby", "alice", "new", now))
+            .await
+            .unwrap();
+
+        assert_eq!(test.store.prune(Some(now - day), None).await.unwrap(), 2);
+        let left = test.store.recent(None, None, 10).await.unwrap();
+        assert_eq!(ids(&left), [3]);
+    }
+
+    #[rocket::async_test]
+    async fn pruning_by_count_trims_every_room() {
+        let test = store(None).await;
+        let now = now_millis();
+        for id in 1..=4 {
+            test.store
+                .insert(&chat(id, "lobby", "alice", "hi", now))
+                .await
+                .unwrap();
+        }
+        test.store
+            .insert(&chat(5, "other", "alice", "hi", now))
+            .await
+            .unwrap();
+
+        assert_eq!(test.store.prune(None, Some(2)).await.unwrap(), 2);
+        let left = test.store.recent(None, None, 10).await.unwrap();
+        assert_eq!(ids(&left), [3, 4, 5]);
+    }
+
+    #[rocket::async_test]
+    async fn pruning_never_removes_the_newest_message() {
+        let test = store(None).await;
+        test.store
+            .insert(&chat(1, "lobby", "alice", "old", 0))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            test.store.prune(Some(now_millis()), Some(0)).await.unwrap(),
+            0
+        );
+        assert_eq!(test.store.last_id().await.unwrap(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn chunks_page_through_a_room_oldest_first() {
+        let test = store(None).await;
+        let now = now_millis();
+        for id in 1..=5 {
+            let room = if id == 3 { "games" } else { "lobby" };
+            test.store
+                .insert(&chat(id, room, "alice", "hi", now))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(ids(&test.store.chunk("lobby", 0, 2).await.unwrap()), [1, 2]);
+        assert_eq!(ids(&test.store.chunk("lobby", 2, 2).await.unwrap()), [4, 5]);
+        assert!(test.store.chunk("lobby", 5, 2).await.unwrap().is_empty());
+    }
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    async fn stored_body(test: &TestStore, id: u64) -> String {
+        let (body,): (String,) = sqlx::query_as("SELECT message FROM messages WHERE id = ?")
+            .bind(id as i64)
+            .fetch_one(&test.store.pool)
+            .await
+            .unwrap();
+        body
+    }
+
+    #[rocket::async_test]
+    async fn encrypted_bodies_round_trip() {
+        let cipher = BodyCipher::from_base64(KEY).unwrap();
+        let test = store(Some(cipher)).await;
+        let msg = Message {
+            html: Some("<p>top secret</p>".into()),
+            ..chat(1, "lobby", "alice", "top secret", now_millis())
+        };
+        test.store.insert(&msg).await.unwrap();
+
+        assert!(!stored_body(&test, 1).await.contains("top secret"));
+        let read = test.store.get(1).await.unwrap().unwrap();
+        assert_eq!(read.message, "top secret");
+        assert_eq!(read.html.as_deref(), Some("<p>top secret</p>"));
+        assert_eq!(read.room, "lobby");
+
+        // Without the key the body can't be read back
+        let url = format!("sqlite://{}", test.path.display());
+        let keyless = Store::connect(&url, None).await.unwrap();
+        assert!(keyless.get(1).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn bodies_are_plaintext_without_a_key() {
+        let test = store(None).await;
+        test.store
+            .insert(&chat(1, "lobby", "alice", "hello", now_millis()))
+            .await
+            .unwrap();
+        assert_eq!(stored_body(&test, 1).await, "hello");
+        assert_eq!(test.store.get(1).await.unwrap().unwrap().message, "hello");
+    }
+}
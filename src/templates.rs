@@ -0,0 +1,100 @@
+use rocket::serde::Deserialize;
+
+// Wording of the announcements the server makes in a room, read from the `chat.templates` table
+// so deployments can translate or reword them, i.e in Rocket.toml
+//    [default.chat.templates]
+//    joined = "{username} a rejoint {room}"
+// -- joined -> a user's first stream to a room opened, placeholders {username} and {room}
+// -- left -> one of a user's streams to a room closed, placeholders {username} and {room}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Templates {
+    pub joined: String,
+    pub left: String,
+}
+
+impl Default for Templates {
+    fn default() -> Templates {
+        Templates {
+            joined: "{username} joined {room}".into(),
+            left: "{username} left {room}".into(),
+        }
+    }
+}
+
+// Fill the {name} placeholders of `template` in with the value `vars` gives that name
+// -- placeholders without a value, and braces that aren't placeholders, are left as they are
+// -- values are inserted verbatim, a value that looks like a placeholder isn't filled in again
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let filled = tail.find('}').and_then(|end| {
+            let name = &tail[1..end];
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| (end, *value))
+        });
+        match filled {
+            Some((end, value)) => {
+                out.push_str(value);
+                rest = &tail[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestChat, WAIT};
+    use rocket::serde::json::json;
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let vars = [("username", "alice"), ("room", "lobby")];
+        assert_eq!(
+            render("{username} joined {room}", &vars),
+            "alice joined lobby"
+        );
+        assert_eq!(render("{room}{room}", &vars), "lobbylobby");
+        assert_eq!(
+            render("{username} said {nothing} {", &vars),
+            "alice said {nothing} {"
+        );
+        assert_eq!(render("{username}", &[("username", "{room}")]), "{room}");
+    }
+
+    #[test]
+    fn announcements_use_the_configured_templates() {
+        let chat = TestChat::configured(|figment| {
+            figment.merge((
+                "chat.templates",
+                json!({ "joined": "{username} a rejoint {room} {oops}" }),
+            ))
+        });
+        let mut events = chat.events("room=lobby&username=alice");
+        let joined = events.messages(1, WAIT);
+        assert_eq!(joined[0].message, "alice a rejoint lobby {oops}");
+        assert!(joined[0].system);
+
+        // Templates the configuration leaves out keep their default
+        let bob = chat.events("room=lobby&username=bob");
+        chat.block_on(async { drop(bob) });
+        let left = events
+            .messages(2, WAIT)
+            .into_iter()
+            .map(|msg| msg.message)
+            .collect::<Vec<_>>();
+        assert_eq!(left, ["bob a rejoint lobby {oops}", "bob left lobby"]);
+    }
+}
@@ -0,0 +1,589 @@
+use crate::admin::ADMIN_TOKEN_HEADER;
+use crate::build;
+use crate::message::Message;
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::local::asynchronous::{Client, LocalRequest, LocalResponse};
+use rocket::serde::json::{self, Value};
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use rocket::tokio::net::TcpStream;
+use rocket::tokio::runtime::{self, Runtime};
+use rocket::tokio::task::JoinHandle;
+use rocket::tokio::time::{sleep, timeout, Duration};
+use rocket::{Ignite, Rocket, Shutdown};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+
+// Synchronous harness for the tests, runs the whole app on Rocket's local client, without a socket
+// Posts and reads /events like a browser would
+// -- i.e
+//    let chat = TestChat::new();
+//    let mut events = chat.events("room=lobby");
+//    assert_eq!(chat.post("lobby", "alice", "hi"), Status::Ok);
+//    let messages = events.messages(1, Duration::from_secs(1));
+// Each TestChat gets its own SQLite database in the temp directory, removed once it's dropped
+pub struct TestChat {
+    runtime: Runtime,
+    client: Option<Client>,
+    database: PathBuf,
+}
+
+// How long tests wait for what they expect to arrive (i.e events.messages(1, WAIT)) before giving up on it
+pub const WAIT: Duration = Duration::from_secs(5);
+
+// The admin_token TestChat::with_admin configures, sent by requests passed through as_admin
+pub const ADMIN_TOKEN: &str = "admin-secret";
+
+// What a request got back, read in full
+// -- body -> the body as text, `bytes` holds it as sent (i.e compressed)
+pub struct Reply {
+    pub status: Status,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Reply {
+    pub fn json(&self) -> Value {
+        json::from_str(&self.body).unwrap_or_else(|e| panic!("{} isn't JSON: {}", self.body, e))
+    }
+
+    // The first value of header `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl TestChat {
+    // The app with its default settings, see ChatConfig
+    pub fn new() -> TestChat {
+        TestChat::configured(|figment| figment)
+    }
+
+    // The app with settings of the test's own, merged over the defaults
+    // -- i.e TestChat::configured(|figment| figment.merge(("chat.rate_limit_burst", 100)))
+    pub fn configured(configure: impl FnOnce(Figment) -> Figment) -> TestChat {
+        TestChat::on_database(temp_database(), configure)
+    }
+
+    // The app with an admin_token (ADMIN_TOKEN) and unlimited settings, for tests of what moderators do
+    pub fn with_admin() -> TestChat {
+        TestChat::configured(admin_token)
+    }
+
+    // The app on `database`, created if missing
+    pub fn on_database(database: PathBuf, configure: impl FnOnce(Figment) -> Figment) -> TestChat {
+        // The local client is async, so the harness brings its own runtime and blocks on it, like Rocket's blocking client does
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start the test runtime");
+        let client = launch(&runtime, &database, configure);
+
+        TestChat {
+            runtime,
+            client: Some(client),
+            database,
+        }
+    }
+
+    // Shut the app down and launch it again on the same database, like restarting the server would
+    // Whatever was only kept in memory (ids handed out, bans, presence...) is gone, the stored messages are still there
+    pub fn restart(&mut self, configure: impl FnOnce(Figment) -> Figment) {
+        if let Some(client) = self.client.take() {
+            self.runtime.block_on(async { drop(client) });
+        }
+        self.client = Some(launch(&self.runtime, &self.database, configure));
+    }
+
+    // The SQLite database the app runs on, i.e to tamper with it behind the app's back
+    pub fn database(&self) -> &Path {
+        &self.database
+    }
+
+    // The local client, to send requests the helpers below don't cover
+    // Its requests are async, run them with send or block_on
+    pub fn client(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+
+    pub fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    // A request to `uri`, coming from 127.0.0.1
+    pub fn request(&self, method: Method, uri: impl std::fmt::Display) -> LocalRequest<'_> {
+        self.client()
+            .req(method, uri.to_string())
+            .remote(local_ip())
+    }
+
+    pub fn get(&self, uri: impl std::fmt::Display) -> LocalRequest<'_> {
+        self.request(Method::Get, uri)
+    }
+
+    // A POST request to `uri` carrying `body` as JSON
+    pub fn post_json(&self, uri: impl std::fmt::Display, body: &Value) -> LocalRequest<'_> {
+        self.request(Method::Post, uri)
+            .header(ContentType::JSON)
+            .body(body.to_string())
+    }
+
+    // A POST request to `uri` carrying `body` as a url-encoded form (i.e "room=lobby&username=alice")
+    pub fn post_form<'c>(&'c self, uri: impl std::fmt::Display, body: &'c str) -> LocalRequest<'c> {
+        self.request(Method::Post, uri)
+            .header(ContentType::Form)
+            .body(body)
+    }
+
+    // Dispatch `request` and read the whole reply
+    pub fn send(&self, request: LocalRequest<'_>) -> Reply {
+        self.block_on(async {
+            let response = request.dispatch().await;
+            let status = response.status();
+            let headers = response
+                .headers()
+                .iter()
+                .map(|header| (header.name().to_string(), header.value().to_string()))
+                .collect();
+            let bytes = response.into_bytes().await.unwrap_or_default();
+            Reply {
+                status,
+                headers,
+                body: String::from_utf8_lossy(&bytes).into_owned(),
+                bytes,
+            }
+        })
+    }
+
+    // Post a message to /message as `username`, answering the status it got
+    // Posts need someone listening to the room, open events() first
+    pub fn post(&self, room: &str, username: &str, message: &str) -> Status {
+        let body = json::json!({ "room": room, "username": username, "message": message });
+        self.send(self.post_json("/message", &body)).status
+    }
+
+    // Like post, for a post that must succeed, answering the id it was given
+    pub fn post_id(&self, room: &str, username: &str, message: &str) -> u64 {
+        let body = json::json!({ "room": room, "username": username, "message": message });
+        let reply = self.send(self.post_json("/message", &body));
+        assert_eq!(reply.status, Status::Ok, "{}", reply.body);
+        reply.json()["id"].as_u64().unwrap()
+    }
+
+    // Open an /events stream with the given query (i.e "room=lobby&username=alice"), subscribed as soon as this returns
+    // Panics when the stream is turned down, the status tells why
+    pub fn events(&self, query: &str) -> Events<'_> {
+        self.stream(self.get(format!("/events?{}", query)))
+    }
+
+    // Open the event stream `request` asks for, when it needs headers of its own (i.e Last-Event-ID)
+    pub fn stream<'c>(&'c self, request: LocalRequest<'c>) -> Events<'c> {
+        let response = self.block_on(request.dispatch());
+        assert_eq!(response.status(), Status::Ok, "the stream was turned down");
+
+        Events {
+            chat: self,
+            body: BufReader::new(response),
+            line: Vec::new(),
+            event: SseEvent::default(),
+        }
+    }
+}
+
+impl Default for TestChat {
+    fn default() -> TestChat {
+        TestChat::new()
+    }
+}
+
+// Shutting the app down takes its runtime, then the database can go
+impl Drop for TestChat {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.runtime.block_on(async { drop(client) });
+        }
+        remove_database(&self.database);
+    }
+}
+
+// Launch the app on `database` with the given settings
+fn launch(
+    runtime: &Runtime,
+    database: &Path,
+    configure: impl FnOnce(Figment) -> Figment,
+) -> Client {
+    runtime
+        .block_on(Client::tracked(build(configure(settings(database)))))
+        .expect("failed to launch the app")
+}
+
+// The settings every test starts from, the app on `database` and quiet
+// Rocket.toml and the ROCKET_ environment variables are ignored so the machine's settings can't change the outcome
+fn settings(database: &Path) -> Figment {
+    Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge((
+            "chat.database_url",
+            format!("sqlite://{}", database.display()),
+        ))
+}
+
+// The app launched on real sockets, for clients Rocket's local client can't stand in for (the TCP bridge, WebSockets)
+// Run from a #[rocket::async_test], unlike TestChat it has no runtime of its own
+// -- i.e
+//    let server = TestServer::launch(|figment| figment).await;
+//    let mut stream = server.connect().await;
+//    ...
+//    server.stop().await;
+pub struct TestServer {
+    pub port: u16,
+    shutdown: Shutdown,
+    server: JoinHandle<Result<Rocket<Ignite>, rocket::Error>>,
+    database: PathBuf,
+}
+
+impl TestServer {
+    // The app with settings of the test's own, like TestChat::configured, listening on a free port of 127.0.0.1
+    pub async fn launch(configure: impl FnOnce(Figment) -> Figment) -> TestServer {
+        let database = temp_database();
+        let port = free_port();
+        let figment = settings(&database)
+            .merge(("address", "127.0.0.1"))
+            .merge(("port", port));
+        let rocket = build(configure(figment))
+            .ignite()
+            .await
+            .expect("failed to launch the app");
+        let shutdown = rocket.shutdown();
+        let server = rocket::tokio::spawn(rocket.launch());
+
+        TestServer {
+            port,
+            shutdown,
+            server,
+            database,
+        }
+    }
+
+    // A connection to the HTTP server
+    pub async fn connect(&self) -> TcpStream {
+        connect(self.port).await
+    }
+
+    // Ask the server to shut down, as /admin/shutdown would, without waiting for it
+    pub fn shutdown(&self) {
+        self.shutdown.clone().notify();
+    }
+
+    // Shut the server down, waiting for it to stop, then remove its database
+    pub async fn stop(self) {
+        self.shutdown.notify();
+        let _ = timeout(WAIT, self.server).await;
+        remove_database(&self.database);
+    }
+}
+
+// A port of 127.0.0.1 nothing listens on right now
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Connect to `port` of 127.0.0.1, waiting for the server to open it
+pub async fn connect(port: u16) -> TcpStream {
+    timeout(WAIT, async {
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => return stream,
+                Err(_) => sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .expect("the server didn't open the port")
+}
+
+// Settings lifting the rate limit and the dedupe window, for tests posting a lot or posting the same body twice
+// -- i.e TestChat::configured(unlimited)
+pub fn unlimited(figment: Figment) -> Figment {
+    figment
+        .merge(("chat.rate_limit_burst", 10_000))
+        .merge(("chat.dedupe_window_ms", 0))
+}
+
+// Settings of TestChat::with_admin, for tests that need more of their own
+// -- i.e TestChat::configured(|figment| admin_token(figment).merge(("chat.channel_capacity", 2)))
+pub fn admin_token(figment: Figment) -> Figment {
+    unlimited(figment).merge(("chat.admin_token", ADMIN_TOKEN))
+}
+
+// `request` with the X-Admin-Token header holding ADMIN_TOKEN
+// -- i.e chat.send(as_admin(chat.post_json("/admin/ban", &body)))
+pub fn as_admin(request: LocalRequest<'_>) -> LocalRequest<'_> {
+    request.header(Header::new(ADMIN_TOKEN_HEADER, ADMIN_TOKEN))
+}
+
+// A path in the temp directory for a database no other test uses
+pub fn temp_database() -> PathBuf {
+    std::env::temp_dir().join(format!("chat-test-{:016x}.db", rand::random::<u64>()))
+}
+
+// Remove a database, with the files SQLite keeps next to it
+pub fn remove_database(database: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut path = database.to_path_buf().into_os_string();
+        path.push(suffix);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// One server-sent event as the stream framed it
+// -- event -> its name, "message" when it gave none
+// -- data -> its "data:" lines joined
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub retry: Option<u64>,
+    pub data: Option<String>,
+}
+
+impl SseEvent {
+    pub fn name(&self) -> &str {
+        self.event.as_deref().unwrap_or("message")
+    }
+
+    // The Message it carries, when it's one (json format)
+    pub fn message(&self) -> Option<Message> {
+        json::from_str(self.data.as_deref()?).ok()
+    }
+}
+
+// An open /events stream, see TestChat::events
+pub struct Events<'c> {
+    chat: &'c TestChat,
+    body: BufReader<LocalResponse<'c>>,
+    line: Vec<u8>,
+    event: SseEvent,
+}
+
+impl Events<'_> {
+    // The messages of the next `n` events carrying one, waiting at most `wait` for them all
+    // Events carrying no message (ping, stats...) are skipped, a "messages" batch gives all of its messages
+    // Returns fewer than `n` when the wait runs out or the stream ends, the stream can be read on afterwards
+    pub fn messages(&mut self, n: usize, wait: Duration) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let chat = self.chat;
+        chat.block_on(async {
+            let _ = timeout(wait, async {
+                while messages.len() < n {
+                    let Some(event) = self.next_event().await else {
+                        break;
+                    };
+                    let Some(data) = event.data.as_deref() else {
+                        continue;
+                    };
+                    if let Ok(msg) = json::from_str::<Message>(data) {
+                        messages.push(msg);
+                    } else if let Ok(batch) = json::from_str::<Vec<Message>>(data) {
+                        messages.extend(batch);
+                    }
+                }
+            })
+            .await;
+        });
+
+        messages
+    }
+
+    // The next `n` events whatever they carry, waiting at most `wait` for them all
+    pub fn events(&mut self, n: usize, wait: Duration) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        let chat = self.chat;
+        chat.block_on(async {
+            let _ = timeout(wait, async {
+                while events.len() < n {
+                    match self.next_event().await {
+                        Some(event) => events.push(event),
+                        None => break,
+                    }
+                }
+            })
+            .await;
+        });
+
+        events
+    }
+
+    // The next event named `name`, skipping the others, None when it doesn't come within `wait`
+    pub fn find(&mut self, name: &str, wait: Duration) -> Option<SseEvent> {
+        let chat = self.chat;
+        chat.block_on(async {
+            timeout(wait, async {
+                while let Some(event) = self.next_event().await {
+                    if event.name() == name {
+                        return Some(event);
+                    }
+                }
+                None
+            })
+            .await
+            .ok()
+            .flatten()
+        })
+    }
+
+    // Whether the stream ended within `wait`, reading through what's left of it
+    pub fn ended(&mut self, wait: Duration) -> bool {
+        let chat = self.chat;
+        chat.block_on(async {
+            timeout(wait, async { while self.next_event().await.is_some() {} })
+                .await
+                .is_ok()
+        })
+    }
+
+    // The next event, None once the stream ends
+    // What was read of an event when a wait runs out is kept in `line` and `event`, so the next call picks it up
+    async fn next_event(&mut self) -> Option<SseEvent> {
+        loop {
+            let line = self.next_line().await?;
+            if line.is_empty() {
+                let event = std::mem::take(&mut self.event);
+                match event.data.is_some() || event.event.is_some() || event.retry.is_some() {
+                    true => return Some(event),
+                    false => continue,
+                }
+            }
+            let (field, value) = line.split_once(':').unwrap_or((&line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => self.event.event = Some(value.to_string()),
+                "id" => self.event.id = Some(value.to_string()),
+                "retry" => self.event.retry = value.parse().ok(),
+                "data" => match &mut self.event.data {
+                    Some(data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => self.event.data = Some(value.to_string()),
+                },
+                // Comments (the disconnect probes) start with ':', so their field is empty
+                _ => {}
+            }
+        }
+    }
+
+    // The next line of the stream, without its line break
+    // Reads through fill_buf, which loses nothing when cancelled, unlike read_line
+    async fn next_line(&mut self) -> Option<String> {
+        loop {
+            let buf = self.body.fill_buf().await.ok()?;
+            if buf.is_empty() {
+                return None;
+            }
+            let (chunk, end) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (&buf[..=i], true),
+                None => (buf, false),
+            };
+            let read = chunk.len();
+            self.line.extend_from_slice(chunk);
+            self.body.consume(read);
+            if end {
+                let line = String::from_utf8_lossy(&self.line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string();
+                self.line.clear();
+                return Some(line);
+            }
+        }
+    }
+}
+
+// The address test requests come from unless they say otherwise
+pub const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+// Local requests come from no address at all, give them one so the IP based guards (bans, rate limits) see a client
+fn local_ip() -> SocketAddr {
+    SocketAddr::new(LOCAL_IP, 50000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_capture_a_posted_message() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        assert_eq!(chat.post("lobby", "alice", "hi there"), Status::Ok);
+
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].room, "lobby");
+        assert_eq!(messages[0].username, "alice");
+        assert_eq!(messages[0].message, "hi there");
+    }
+
+    #[test]
+    fn messages_come_back_short_when_the_wait_runs_out() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        assert!(events.messages(1, Duration::from_millis(200)).is_empty());
+
+        // A wait running out doesn't lose what comes next
+        assert_eq!(chat.post("lobby", "alice", "late"), Status::Ok);
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages[0].message, "late");
+    }
+
+    #[test]
+    fn replies_are_read_in_full() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.get("/healthz"));
+        assert_eq!(reply.status, Status::Ok);
+        assert_eq!(reply.json()["status"], "ok");
+        assert!(reply
+            .header("content-type")
+            .is_some_and(|value| value.contains("json")));
+    }
+
+    #[test]
+    fn with_admin_takes_requests_passed_through_as_admin() {
+        let chat = TestChat::with_admin();
+        assert_eq!(
+            chat.send(chat.get("/admin/stats")).status,
+            Status::Forbidden
+        );
+        let reply = chat.send(as_admin(chat.get("/admin/stats")));
+        assert_eq!(reply.status, Status::Ok);
+        assert_eq!(reply.json()["bans"], 0);
+    }
+
+    #[test]
+    fn events_are_framed_whatever_they_carry() {
+        let chat = TestChat::configured(|figment| {
+            figment
+                .merge(("chat.heartbeat_interval", 1))
+                .merge(("chat.max_stream_duration", 2))
+        });
+        let mut events = chat.events("room=lobby");
+
+        // The stream opens with the retry hint, then pings while it's quiet
+        let first = events.events(1, WAIT);
+        assert_eq!(first[0].retry, Some(1000));
+        let ping = events.find("ping", WAIT).unwrap();
+        assert!(ping.message().is_none());
+
+        let timeout = events.find("timeout", WAIT).unwrap();
+        assert_eq!(timeout.name(), "timeout");
+        assert!(events.ended(WAIT));
+    }
+}
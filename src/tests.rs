@@ -0,0 +1,950 @@
+// Tests of the routes main.rs defines, /message and /events mostly, run through TestChat
+// Tests of the other modules live at the bottom of each of them
+use crate::channels::Channels;
+use crate::message::{Message, MessageKind, MAX_CLIENT_MSG_ID_LEN};
+use crate::testing::{admin_token, as_admin, unlimited, Events, TestChat, WAIT};
+use rocket::http::Status;
+use rocket::serde::json::{self, json};
+use rocket::tokio::time::Duration;
+use sqlx::{Connection, SqliteConnection};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const QUIET: Duration = Duration::from_millis(300);
+
+// The chat messages among the next events on `events`, leaving out announcements (joins, leaves...)
+fn chats(events: &mut Events<'_>, n: usize, wait: Duration) -> Vec<Message> {
+    let mut chats = Vec::new();
+    let deadline = std::time::Instant::now() + wait;
+    while chats.len() < n {
+        let left = deadline.saturating_duration_since(std::time::Instant::now());
+        let Some(msg) = events.messages(1, left).pop() else {
+            break;
+        };
+        if msg.kind == MessageKind::Chat {
+            chats.push(msg);
+        }
+    }
+    chats
+}
+
+#[test]
+fn room_streams_only_see_their_room() {
+    let chat = TestChat::new();
+    let mut general = chat.events("room=general");
+    let mut random = chat.events("room=random");
+    let mut everything = chat.events("");
+
+    assert_eq!(chat.post("random", "alice", "in random"), Status::Ok);
+    assert_eq!(chat.post("general", "alice", "in general"), Status::Ok);
+
+    let messages = general.messages(1, WAIT);
+    assert_eq!(messages[0].message, "in general");
+    assert!(general.messages(1, QUIET).is_empty());
+
+    let messages = random.messages(1, WAIT);
+    assert_eq!(messages[0].message, "in random");
+    assert!(random.messages(1, QUIET).is_empty());
+
+    // Without a room the stream gets every room, like before rooms were filtered
+    let messages = everything.messages(2, WAIT);
+    let bodies: Vec<_> = messages.iter().map(|msg| msg.message.as_str()).collect();
+    assert_eq!(bodies, ["in random", "in general"]);
+}
+
+#[test]
+fn posting_past_the_burst_gets_429() {
+    let chat = TestChat::configured(|figment| {
+        figment
+            .merge(("chat.rate_limit_per_second", 1))
+            .merge(("chat.rate_limit_burst", 3))
+    });
+    let _events = chat.events("room=lobby");
+
+    let statuses: Vec<_> = (0..5)
+        .map(|i| chat.post("lobby", "alice", &format!("message {}", i)))
+        .collect();
+    assert_eq!(statuses[..3], [Status::Ok; 3]);
+    assert_eq!(statuses[3..], [Status::TooManyRequests; 2]);
+}
+
+#[test]
+fn streams_cant_join_as_the_system_user() {
+    let chat = TestChat::new();
+    let reply = chat.send(chat.get("/events?room=lobby&username=system"));
+    assert_eq!(reply.status, Status::BadRequest);
+    assert_eq!(reply.json()["error"], "username is reserved");
+
+    let users = chat.send(chat.get("/users?room=lobby")).json();
+    assert_eq!(users, json!([]));
+}
+
+#[test]
+fn bad_stream_queries_get_400_before_the_stream_opens() {
+    let chat = TestChat::new();
+    for query in [
+        "room=a%2Fb",
+        "room=lobby&username=two%20words",
+        "room=lobby&batch=maybe",
+        "format=xml",
+    ] {
+        let reply = chat.send(chat.get(format!("/events?{}", query)));
+        assert_eq!(reply.status, Status::BadRequest, "{}", query);
+        assert_eq!(reply.header("Content-Type"), Some("application/json"));
+        assert_eq!(reply.json()["code"], 400);
+    }
+    let reply = chat.send(chat.get(format!("/events?room={}", "a".repeat(30))));
+    assert_eq!(
+        reply.json()["error"],
+        "room must be shorter than 30 characters"
+    );
+
+    // A good query opens the stream, which sees what's posted
+    let mut events = chat.events("room=Lobby&username=alice");
+    assert_eq!(chat.post("lobby", "bob", "hi"), Status::Ok);
+    assert_eq!(chats(&mut events, 1, WAIT)[0].message, "hi");
+}
+
+#[test]
+fn posts_are_censored_before_broadcast() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.blacklist", ["ass"])));
+    let mut events = chat.events("room=lobby");
+    assert_eq!(chat.post("lobby", "alice", "Ass class"), Status::Ok);
+
+    let messages = events.messages(1, WAIT);
+    assert_eq!(messages[0].message, "*** class");
+}
+
+#[test]
+fn message_length_is_capped_at_max_message_len() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.max_message_len", 10)));
+    let _events = chat.events("room=lobby");
+
+    assert_eq!(chat.post("lobby", "alice", &"a".repeat(9)), Status::Ok);
+    assert_eq!(chat.post("lobby", "alice", &"b".repeat(10)), Status::Ok);
+    let body = json!({ "room": "lobby", "username": "alice", "message": "c".repeat(11) });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::UnprocessableEntity);
+    assert_eq!(
+        reply.json()["error"],
+        "message must be at most 10 characters, got 11"
+    );
+}
+
+#[test]
+fn message_length_defaults_to_2000() {
+    let chat = TestChat::new();
+    let _events = chat.events("room=lobby");
+    assert_eq!(chat.post("lobby", "alice", &"a".repeat(2000)), Status::Ok);
+    assert_eq!(
+        chat.post("lobby", "alice", &"a".repeat(2001)),
+        Status::UnprocessableEntity
+    );
+}
+
+#[test]
+fn blank_messages_and_usernames_get_422() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.rate_limit_burst", 100)));
+    let _events = chat.events("room=lobby");
+
+    for blank in ["", "   ", "\t\n"] {
+        assert_eq!(
+            chat.post("lobby", "alice", blank),
+            Status::UnprocessableEntity,
+            "{:?}",
+            blank
+        );
+        assert_eq!(
+            chat.post("lobby", blank, "hi"),
+            Status::UnprocessableEntity,
+            "{:?}",
+            blank
+        );
+    }
+
+    // Forms are checked the same way
+    let reply = chat.send(chat.post_form("/message", "room=lobby&username=alice&message=%20%20"));
+    assert_eq!(reply.status, Status::UnprocessableEntity);
+}
+
+#[test]
+fn messages_are_trimmed_keeping_what_is_inside() {
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby");
+    assert_eq!(
+        chat.post("lobby", "alice", "  hello   world \n"),
+        Status::Ok
+    );
+
+    let messages = events.messages(1, WAIT);
+    assert_eq!(messages[0].message, "hello   world");
+}
+
+#[test]
+fn channel_capacity_comes_from_the_configuration() {
+    let chat = TestChat::new();
+    let channels = chat.client().rocket().state::<Channels>().unwrap();
+    assert_eq!(channels.capacity(), 1024);
+
+    let chat = TestChat::configured(|figment| {
+        figment
+            .merge(("chat.channel_capacity", 2))
+            .merge(("chat.overflow", "reject"))
+            .merge(("chat.dedupe_window_ms", 0))
+    });
+    let channels = chat.client().rocket().state::<Channels>().unwrap();
+    assert_eq!(channels.capacity(), 2);
+
+    // Nothing reads the stream, so the third post finds the channel full
+    let _events = chat.events("room=lobby");
+    assert_eq!(chat.post("lobby", "alice", "one"), Status::Ok);
+    assert_eq!(chat.post("lobby", "alice", "two"), Status::Ok);
+    assert_eq!(
+        chat.post("lobby", "alice", "three"),
+        Status::ServiceUnavailable
+    );
+}
+
+#[test]
+fn forms_and_json_bodies_both_reach_subscribers() {
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby");
+
+    let form = chat.send(chat.post_form("/message", "room=lobby&username=alice&message=as+a+form"));
+    assert_eq!(form.status, Status::Ok);
+    let body = json!({ "room": "lobby", "username": "bob", "message": "as json" });
+    let json = chat.send(chat.post_json("/message", &body));
+    assert_eq!(json.status, Status::Ok);
+
+    let messages = events.messages(2, WAIT);
+    let bodies: Vec<_> = messages.iter().map(|msg| msg.message.as_str()).collect();
+    assert_eq!(bodies, ["as a form", "as json"]);
+}
+
+#[test]
+fn json_bodies_get_the_form_length_checks() {
+    let chat = TestChat::new();
+    let _events = chat.events("room=lobby");
+
+    let body = json!({ "room": "a".repeat(30), "username": "alice", "message": "hi" });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::UnprocessableEntity);
+    let body = json!({ "room": "lobby", "username": "a".repeat(20), "message": "hi" });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::UnprocessableEntity);
+}
+
+#[test]
+fn direct_messages_only_reach_the_sender_and_recipient() {
+    let chat = TestChat::new();
+    let mut alice = chat.events("room=lobby&username=alice");
+    let mut bob = chat.events("room=lobby&username=bob");
+    let mut carol = chat.events("room=lobby&username=carol");
+    let mut public = chat.events("room=lobby");
+    let mut everything = chat.events("");
+
+    let body = json!({ "room": "lobby", "username": "alice", "to": "bob", "message": "psst" });
+    assert_eq!(
+        chat.send(chat.post_json("/message", &body)).status,
+        Status::Ok
+    );
+
+    let received = chats(&mut bob, 1, WAIT);
+    assert_eq!(received[0].message, "psst");
+    assert_eq!(received[0].to.as_deref(), Some("bob"));
+    assert_eq!(chats(&mut alice, 1, WAIT)[0].message, "psst");
+
+    assert!(chats(&mut carol, 1, QUIET).is_empty());
+    assert!(chats(&mut public, 1, QUIET).is_empty());
+    assert!(chats(&mut everything, 1, QUIET).is_empty());
+}
+
+#[test]
+fn streams_falling_behind_get_a_lagged_event() {
+    let chat =
+        TestChat::configured(|figment| unlimited(figment).merge(("chat.channel_capacity", 2)));
+    let mut events = chat.events("room=lobby");
+
+    // Nothing reads the stream while the posts go out, so it misses all but the last two
+    for i in 0..5 {
+        assert_eq!(
+            chat.post("lobby", "alice", &format!("message {}", i)),
+            Status::Ok
+        );
+    }
+
+    let lagged = events.find("lagged", WAIT).unwrap();
+    assert_eq!(lagged.data.as_deref(), Some("3"));
+    let bodies: Vec<_> = events
+        .messages(2, WAIT)
+        .into_iter()
+        .map(|msg| msg.message)
+        .collect();
+    assert_eq!(bodies, ["message 3", "message 4"]);
+}
+
+#[test]
+fn a_busy_room_doesnt_make_quiet_rooms_lag() {
+    let chat =
+        TestChat::configured(|figment| unlimited(figment).merge(("chat.channel_capacity", 2)));
+    let mut busy = chat.events("room=lobby");
+    let mut quiet = chat.events("room=quiet");
+
+    assert_eq!(chat.post("quiet", "bob", "before"), Status::Ok);
+    for i in 0..5 {
+        assert_eq!(
+            chat.post("lobby", "alice", &format!("message {}", i)),
+            Status::Ok
+        );
+    }
+    assert_eq!(chat.post("quiet", "bob", "after"), Status::Ok);
+
+    let events = quiet.events(3, QUIET);
+    assert!(events.iter().all(|event| event.name() == "message"));
+    let bodies: Vec<_> = events
+        .iter()
+        .filter_map(|event| event.message())
+        .map(|msg| msg.message)
+        .collect();
+    assert_eq!(bodies, ["before", "after"]);
+
+    assert_eq!(
+        busy.find("lagged", WAIT).unwrap().data.as_deref(),
+        Some("3")
+    );
+}
+
+#[test]
+fn streams_suggest_a_retry_and_back_off_when_lagging() {
+    let chat = TestChat::configured(|figment| {
+        unlimited(figment)
+            .merge(("chat.channel_capacity", 2))
+            .merge(("chat.retry_ms", 1500))
+            .merge(("chat.backoff_retry_ms", 7000))
+    });
+    let mut events = chat.events("room=lobby");
+    for i in 0..5 {
+        chat.post("lobby", "alice", &format!("message {}", i));
+    }
+
+    let first = events.events(1, WAIT).remove(0);
+    assert_eq!(first.retry, Some(1500));
+    let lagged = events.find("lagged", WAIT).unwrap();
+    assert_eq!(lagged.retry, Some(7000));
+    let msg = events.events(1, WAIT).remove(0);
+    assert_eq!(msg.name(), "message");
+    assert_eq!(msg.retry, None);
+}
+
+#[test]
+fn client_msg_ids_come_back_in_the_response_and_the_broadcast() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=lobby");
+
+    let body =
+        json!({ "room": "lobby", "username": "alice", "message": "hi", "client_msg_id": "c-1" });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::Ok);
+    assert_eq!(reply.json()["client_msg_id"], "c-1");
+    let form = "room=lobby&username=bob&message=hey&client_msg_id=c-2";
+    assert_eq!(
+        chat.send(chat.post_form("/message", form)).json()["client_msg_id"],
+        "c-2"
+    );
+
+    // Without one, neither the response nor the broadcast carries the field
+    let reply = chat.send(chat.post_form("/message", "room=lobby&username=carol&message=yo"));
+    assert_eq!(reply.status, Status::Ok);
+    assert!(reply.json().get("client_msg_id").is_none());
+
+    let ids: Vec<_> = chats(&mut events, 3, WAIT)
+        .into_iter()
+        .map(|msg| msg.client_msg_id)
+        .collect();
+    assert_eq!(
+        ids,
+        [Some("c-1".to_string()), Some("c-2".to_string()), None]
+    );
+}
+
+#[test]
+fn client_msg_ids_are_capped() {
+    let chat = TestChat::new();
+    let id = "x".repeat(MAX_CLIENT_MSG_ID_LEN);
+    let body =
+        json!({ "room": "lobby", "username": "alice", "message": "hi", "client_msg_id": id });
+    assert_eq!(
+        chat.send(chat.post_json("/message", &body)).status,
+        Status::UnprocessableEntity
+    );
+}
+
+#[test]
+fn iso_timestamps_are_only_sent_when_asked_for() {
+    let chat = TestChat::configured(unlimited);
+    let mut plain = chat.events("room=lobby");
+    let mut iso = chat.events("room=lobby&iso=true");
+    chat.post_id("lobby", "alice", "hi");
+
+    let msg = &chats(&mut plain, 1, WAIT)[0];
+    assert_eq!(msg.timestamp_iso, None);
+    let msg = &chats(&mut iso, 1, WAIT)[0];
+    let parsed = OffsetDateTime::parse(msg.timestamp_iso.as_deref().unwrap(), &Rfc3339).unwrap();
+    assert_eq!(
+        parsed.unix_timestamp_nanos() / 1_000_000,
+        msg.timestamp as i128
+    );
+
+    let history = chat.send(chat.get("/history?room=lobby")).json();
+    assert!(history["messages"][0].get("timestamp_iso").is_none());
+    let history = chat.send(chat.get("/history?room=lobby&iso=true")).json();
+    let stamp = history["messages"][0]["timestamp_iso"].as_str().unwrap();
+    assert!(OffsetDateTime::parse(stamp, &Rfc3339).is_ok());
+}
+
+#[test]
+fn only_chat_messages_carry_an_event_id() {
+    let chat = TestChat::configured(|figment| unlimited(figment).merge(("chat.motd", "welcome")));
+    let mut events = chat.events("room=lobby&username=alice");
+    let id = chat.post_id("lobby", "alice", "hi");
+
+    let events = events.events(4, WAIT);
+    let names: Vec<_> = events.iter().map(|event| event.name()).collect();
+    assert_eq!(names, ["message", "system", "system", "message"]);
+    let ids: Vec<_> = events.iter().map(|event| event.id.clone()).collect();
+    assert_eq!(ids, [None, None, None, Some(id.to_string())]);
+}
+
+#[test]
+fn typing_and_reactions_get_their_own_event_names() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=lobby");
+    let id = chat.post_id("lobby", "alice", "hi");
+    let typing = chat.send(chat.post_form("/typing", "room=lobby&username=bob"));
+    assert_eq!(typing.status, Status::NoContent);
+    let body = json!({ "message_id": id, "username": "bob", "emoji": "👍" });
+    assert_eq!(
+        chat.send(chat.post_json("/react", &body)).status,
+        Status::NoContent
+    );
+
+    let names: Vec<_> = events
+        .events(4, WAIT)
+        .iter()
+        .map(|event| event.name().to_string())
+        .collect();
+    assert_eq!(names, ["message", "message", "typing", "reaction"]);
+}
+
+#[test]
+fn reconnecting_after_a_restart_misses_nothing() {
+    let mut chat = TestChat::configured(unlimited);
+    let last_seen = {
+        let mut events = chat.events("room=lobby&username=alice");
+        chat.post_id("lobby", "alice", "before the restart");
+        let mut seen = events.events(3, WAIT);
+        // Bob's join is the last event alice gets, but it's never stored
+        let _bob = chat.events("room=lobby&username=bob");
+        seen.extend(events.events(1, WAIT));
+        assert!(seen[3].data.as_deref().unwrap().contains("bob joined"));
+        let ids: Vec<_> = seen.into_iter().filter_map(|event| event.id).collect();
+        ids.last().unwrap().clone()
+    };
+
+    chat.restart(unlimited);
+    let request = chat
+        .get("/events?room=lobby")
+        .header(rocket::http::Header::new("Last-Event-ID", last_seen));
+    let mut events = chat.stream(request);
+    chat.post_id("lobby", "bob", "after the restart");
+    let bodies: Vec<_> = chats(&mut events, 1, WAIT)
+        .into_iter()
+        .map(|msg| msg.message)
+        .collect();
+    assert_eq!(bodies, ["after the restart"]);
+}
+
+#[test]
+fn streams_open_with_the_motd_when_there_is_one() {
+    let chat = TestChat::configured(|figment| {
+        admin_token(figment).merge(("chat.motd", "Welcome! Be nice."))
+    });
+    let mut events = chat.events("room=lobby");
+    let motd = events.find("system", WAIT).unwrap();
+    assert_eq!(motd.id, None);
+    let motd = motd.message().unwrap();
+    assert_eq!((motd.id, motd.message.as_str()), (0, "Welcome! Be nice."));
+    assert_eq!(motd.room, "lobby");
+
+    let request = as_admin(chat.post_json("/admin/motd", &json!({ "motd": "New rules" })));
+    assert_eq!(chat.send(request).status, Status::NoContent);
+    let mut events = chat.events("room=lobby");
+    let motd = events.find("system", WAIT).unwrap().message().unwrap();
+    assert_eq!(motd.message, "New rules");
+}
+
+#[test]
+fn streams_open_without_a_motd_when_none_is_set() {
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby");
+    // Only the unnamed event setting the retry comes before the stream goes quiet
+    let names: Vec<_> = events
+        .events(2, QUIET)
+        .iter()
+        .map(|event| event.name().to_string())
+        .collect();
+    assert_eq!(names, ["message"]);
+}
+
+#[test]
+fn streams_end_with_a_timeout_after_max_stream_duration() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.max_stream_duration", 1)));
+    let mut events = chat.events("room=lobby");
+    assert!(events.find("timeout", WAIT).is_some());
+    assert!(events.ended(WAIT));
+
+    // 0 keeps them open
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby");
+    assert!(events
+        .find("timeout", Duration::from_millis(1500))
+        .is_none());
+    assert_eq!(chat.post("lobby", "alice", "still there"), Status::Ok);
+}
+
+#[test]
+fn room_names_are_case_insensitive() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=general&username=alice");
+    chat.post_id("General", "bob", "hi");
+    chat.post_id("GENERAL", "carol", "hey");
+
+    let msgs = chats(&mut events, 2, WAIT);
+    assert!(msgs.iter().all(|msg| msg.room == "general"));
+    let users = chat.send(chat.get("/users?room=GeNeRaL")).json();
+    assert_eq!(users, json!(["alice"]));
+    let history = chat.send(chat.get("/history?room=General")).json();
+    assert_eq!(history["messages"].as_array().unwrap().len(), 2);
+    let rooms = chat.send(chat.get("/rooms")).json();
+    assert_eq!(rooms.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn replies_reference_a_message_of_the_same_room() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=lobby");
+    let _games = chat.events("room=games");
+    let parent = chat.post_id("lobby", "alice", "lunch?");
+    let elsewhere = chat.post_id("games", "carol", "gg");
+
+    let reply = |reply_to: u64| {
+        let body =
+            json!({ "room": "lobby", "username": "bob", "message": "sure", "reply_to": reply_to });
+        chat.send(chat.post_json("/message", &body))
+    };
+    let sent = reply(parent);
+    assert_eq!(sent.status, Status::Ok);
+    assert_eq!(reply(parent + 100).status, Status::NotFound);
+    assert_eq!(reply(elsewhere).status, Status::UnprocessableEntity);
+
+    let msgs = chats(&mut events, 2, WAIT);
+    assert_eq!(msgs[1].reply_to, Some(parent));
+    let history = chat.send(chat.get("/history?room=lobby")).json();
+    assert_eq!(history["messages"][0]["reply_to"], parent);
+    assert!(history["messages"][1].get("reply_to").is_none());
+}
+
+#[test]
+fn streams_send_json_or_just_the_text() {
+    let chat = TestChat::configured(unlimited);
+    let mut json_events = chat.events("room=lobby");
+    let mut text_events = chat.events("room=lobby&format=text");
+    let id = chat.post_id("lobby", "alice", "hello {world}");
+
+    let msg = json_events.messages(1, WAIT).remove(0);
+    assert_eq!((msg.id, msg.message.as_str()), (id, "hello {world}"));
+
+    // After the event setting the retry
+    let event = text_events.events(2, WAIT).remove(1);
+    assert_eq!(event.name(), "message");
+    assert_eq!(event.data.as_deref(), Some("hello {world}"));
+    assert_eq!(event.id, Some(id.to_string()));
+}
+
+#[test]
+fn user_history_lists_what_a_user_sent_newest_first() {
+    let chat = TestChat::configured(unlimited);
+    let _lobby = chat.events("room=lobby");
+    let _games = chat.events("room=games");
+    let first = chat.post_id("lobby", "alice", "one");
+    chat.post_id("lobby", "bob", "not alice");
+    let second = chat.post_id("games", "alice", "two");
+    let third = chat.post_id("lobby", "alice", "three");
+
+    let ids = |uri: &str| -> Vec<u64> {
+        chat.send(chat.get(uri))
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|msg| msg["id"].as_u64().unwrap())
+            .collect()
+    };
+    assert_eq!(ids("/history/user/alice"), [third, second, first]);
+    assert_eq!(ids("/history/user/alice?limit=2"), [third, second]);
+    assert_eq!(ids("/history/user/alice?room=Lobby"), [third, first]);
+    assert_eq!(ids("/history/user/nobody"), Vec::<u64>::new());
+}
+
+#[test]
+fn chat_messages_carry_the_instance_id() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.instance_id", "replica-1")));
+    let mut events = chat.events("room=lobby");
+    chat.post_id("lobby", "alice", "hi");
+
+    let msg = &chats(&mut events, 1, WAIT)[0];
+    assert_eq!(msg.origin_instance.as_deref(), Some("replica-1"));
+    let history = chat.send(chat.get("/history?room=lobby")).json();
+    assert_eq!(history["messages"][0]["origin_instance"], "replica-1");
+}
+
+#[test]
+fn stats_events_count_who_is_online() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.stats_interval", 1)));
+    let mut alice = chat.events("room=lobby&username=alice");
+    let _bob = chat.events("room=lobby&username=bob");
+    let _carol = chat.events("room=games");
+
+    let stats = alice.find("stats", WAIT).unwrap();
+    let stats: rocket::serde::json::Value =
+        rocket::serde::json::from_str(stats.data.as_deref().unwrap()).unwrap();
+    assert_eq!(
+        stats,
+        json!({ "room": "lobby", "subscribers": 2, "total": 3 })
+    );
+
+    let mut text = chat.events("room=lobby&format=text");
+    let stats = text.find("stats", WAIT).unwrap();
+    assert_eq!(stats.data.as_deref(), Some("3"));
+}
+
+#[test]
+fn stats_events_are_off_at_0() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.stats_interval", 0)));
+    let mut events = chat.events("room=lobby");
+    assert!(events.find("stats", Duration::from_millis(1500)).is_none());
+}
+
+#[test]
+fn posts_are_answered_once_stored() {
+    let chat = TestChat::new();
+    let _events = chat.events("room=lobby");
+    let body = json!({ "room": "lobby", "username": "alice", "message": "hi" });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::Ok);
+
+    let posted = reply.json();
+    let history = chat.send(chat.get("/history?room=lobby")).json();
+    let stored = &history["messages"][0];
+    assert_eq!(stored["id"], posted["id"]);
+    assert_eq!(stored["timestamp"], posted["timestamp"]);
+    assert_eq!(stored["message"], "hi");
+}
+
+#[test]
+fn posts_the_store_fails_to_save_get_500_but_are_broadcast() {
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby");
+    chat.block_on(async {
+        let url = format!("sqlite://{}", chat.database().display());
+        let mut db = SqliteConnection::connect(&url).await.unwrap();
+        sqlx::query(
+            "CREATE TRIGGER full_disk BEFORE INSERT ON messages BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+        )
+        .execute(&mut db)
+        .await
+        .unwrap();
+    });
+
+    let body = json!({ "room": "lobby", "username": "alice", "message": "hi" });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::InternalServerError);
+    assert_eq!(
+        reply.json(),
+        json!({ "error": "failed to store message", "code": 500 })
+    );
+    assert_eq!(chats(&mut events, 1, WAIT)[0].message, "hi");
+}
+
+#[test]
+fn retrying_a_post_nobody_received_doesnt_store_it_twice() {
+    let chat = TestChat::new();
+    let body = json!({ "room": "lobby", "username": "alice", "message": "hi" });
+    let reply = chat.send(chat.post_json("/message", &body));
+    assert_eq!(reply.status, Status::ServiceUnavailable);
+
+    let _events = chat.events("room=lobby");
+    let retry = chat.send(chat.post_json("/message", &body)).json();
+    assert_eq!(retry["deduped"], true);
+    let history = chat.send(chat.get("/history?room=lobby")).json();
+    let stored = history["messages"].as_array().unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0]["id"], retry["id"]);
+}
+
+#[test]
+fn keyword_streams_only_get_messages_with_a_keyword() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=lobby&keywords=Deploy,%20alice");
+    for body in ["lunch?", "DEPLOY done", "ping @Alice", "nothing here"] {
+        assert_eq!(chat.post("lobby", "bob", body), Status::Ok);
+    }
+
+    let bodies: Vec<_> = chats(&mut events, 3, QUIET)
+        .into_iter()
+        .map(|msg| msg.message)
+        .collect();
+    assert_eq!(bodies, ["DEPLOY done", "ping @Alice"]);
+}
+
+#[test]
+fn keyword_streams_still_get_announcements() {
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby&keywords=deploy");
+    let _bob = chat.events("room=lobby&username=bob");
+
+    let joined = events.messages(1, WAIT);
+    assert_eq!(joined[0].message, "bob joined lobby");
+    assert!(joined[0].system);
+}
+
+#[test]
+fn streams_without_keywords_get_everything() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=lobby&keywords=,");
+    assert_eq!(chat.post("lobby", "bob", "lunch?"), Status::Ok);
+    assert_eq!(chats(&mut events, 1, WAIT)[0].message, "lunch?");
+}
+
+#[test]
+fn mentioned_users_get_a_mention_event() {
+    let chat = TestChat::new();
+    let mut alice = chat.events("room=lobby&username=alice");
+    let mut bob = chat.events("room=lobby&username=bob");
+    let id = chat.post_id("lobby", "carol", "hey @alice, @dave isn't here");
+
+    let mention = alice.find("mention", WAIT).unwrap().message().unwrap();
+    assert_eq!(mention.kind, MessageKind::Mention);
+    assert_eq!(mention.target, Some(id));
+    assert_eq!(
+        (mention.username.as_str(), mention.to.as_deref()),
+        ("carol", Some("alice"))
+    );
+
+    // bob still gets the message itself, but no mention
+    assert_eq!(chats(&mut bob, 1, WAIT)[0].id, id);
+    assert!(bob.find("mention", QUIET).is_none());
+}
+
+#[test]
+fn world_answers_with_a_growing_uptime() {
+    let chat = TestChat::new();
+    let pong = || {
+        let reply = chat.send(chat.get("/world"));
+        assert_eq!(reply.status, Status::Ok);
+        reply.json()["pong"].as_u64().unwrap()
+    };
+
+    let first = pong();
+    std::thread::sleep(Duration::from_millis(1100));
+    assert!(pong() > first);
+}
+
+#[test]
+fn rooms_number_their_messages_independently() {
+    let mut chat = TestChat::configured(unlimited);
+    {
+        let mut lobby = chat.events("room=lobby&username=alice");
+        let _games = chat.events("room=games&username=bob");
+        for (room, body) in [
+            ("lobby", "1"),
+            ("games", "1"),
+            ("lobby", "2"),
+            ("lobby", "3"),
+            ("games", "2"),
+        ] {
+            assert_eq!(chat.post(room, "alice", body), Status::Ok);
+        }
+        let dm = json!({ "room": "lobby", "username": "bob", "to": "alice", "message": "psst" });
+        assert_eq!(
+            chat.send(chat.post_json("/message", &dm)).status,
+            Status::Ok
+        );
+
+        let seqs: Vec<_> = chats(&mut lobby, 4, WAIT)
+            .into_iter()
+            .map(|msg| msg.seq)
+            .collect();
+        assert_eq!(seqs, [Some(1), Some(2), Some(3), None]);
+        let history = chat.send(chat.get("/history?room=games")).json();
+        assert_eq!(history["messages"][0]["seq"], 2);
+        assert_eq!(history["messages"][1]["seq"], 1);
+    }
+
+    // The stored seqs carry on after a restart
+    chat.restart(unlimited);
+    let _events = chat.events("room=lobby");
+    chat.post_id("lobby", "alice", "4");
+    let history = chat.send(chat.get("/history?room=lobby&limit=1")).json();
+    assert_eq!(history["messages"][0]["seq"], 4);
+}
+
+#[test]
+fn batch_streams_send_waiting_messages_as_one_event() {
+    let chat =
+        TestChat::configured(|figment| unlimited(figment).merge(("chat.max_event_batch", 3)));
+    let mut batched = chat.events("room=lobby&batch=true");
+    let mut single = chat.events("room=lobby");
+    let ids: Vec<_> = (0..5)
+        .map(|i| chat.post_id("lobby", "alice", &format!("message {}", i)))
+        .collect();
+
+    // The retry event comes first, then the posts in batches of at most max_event_batch
+    let events = batched.events(3, WAIT);
+    assert_eq!(events[1].name(), "messages");
+    assert_eq!(events[1].id, Some(ids[2].to_string()));
+    let first: Vec<Message> = json::from_str(events[1].data.as_deref().unwrap()).unwrap();
+    assert_eq!(first.iter().map(|msg| msg.id).collect::<Vec<_>>(), ids[..3]);
+    assert_eq!(events[2].name(), "messages");
+    assert_eq!(batched.messages(5, QUIET).len(), 0);
+
+    // Without batch every message is an event of its own
+    let events = single.events(6, WAIT);
+    assert!(events[1..]
+        .iter()
+        .all(|event| event.name() == "message" && event.message().is_some()));
+}
+
+#[test]
+fn system_only_streams_skip_chat() {
+    let chat = TestChat::configured(unlimited);
+    let mut events = chat.events("room=lobby&system_only=true");
+    assert_eq!(chat.post("lobby", "alice", "chatter"), Status::Ok);
+    let _bob = chat.events("room=lobby&username=bob");
+    assert_eq!(chat.post("lobby", "bob", "more chatter"), Status::Ok);
+    let _games = chat.events("room=games&username=carol");
+
+    // Only the lobby's announcements come through
+    let messages = events.messages(3, QUIET);
+    let bodies: Vec<_> = messages.iter().map(|msg| msg.message.as_str()).collect();
+    assert_eq!(bodies, ["bob joined lobby"]);
+    assert!(messages[0].system);
+}
+
+#[test]
+fn search_finds_bodies_containing_the_query() {
+    let chat = TestChat::configured(unlimited);
+    let _events = chat.events("room=lobby");
+    chat.post("lobby", "alice", "Deploy started");
+    chat.post("lobby", "bob", "lunch?");
+    chat.post("lobby", "bob", "deploy done");
+    chat.post("other", "alice", "deploy elsewhere");
+
+    // Newest first, ignoring case, only in the room
+    let found = chat.send(chat.get("/search?room=lobby&q=DEPLOY")).json();
+    let bodies: Vec<_> = found
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|msg| msg["message"].as_str().unwrap())
+        .collect();
+    assert_eq!(bodies, ["deploy done", "Deploy started"]);
+
+    let limited = chat
+        .send(chat.get("/search?room=lobby&q=deploy&limit=1"))
+        .json();
+    assert_eq!(limited.as_array().unwrap().len(), 1);
+
+    let bobs = chat
+        .send(chat.get("/search?room=lobby&q=deploy&username=bob"))
+        .json();
+    assert_eq!(bobs.as_array().unwrap().len(), 1);
+    assert_eq!(bobs[0]["username"], "bob");
+
+    let nothing = chat.send(chat.get("/search?room=lobby&q=release")).json();
+    assert_eq!(nothing, json!([]));
+}
+
+#[test]
+fn search_takes_a_query() {
+    let chat = TestChat::new();
+    let reply = chat.send(chat.get("/search?room=lobby&q=%20"));
+    assert_eq!(reply.status, Status::UnprocessableEntity);
+}
+
+#[test]
+fn markdown_is_rendered_when_asked_for() {
+    let chat = TestChat::new();
+    let mut events = chat.events("room=lobby");
+
+    let body = json!({ "room": "lobby", "username": "alice", "message": "**plain**" });
+    chat.send(chat.post_json("/message", &body));
+    let body =
+        json!({ "room": "lobby", "username": "alice", "message": "**bold**", "markdown": true });
+    chat.send(chat.post_json("/message", &body));
+
+    let messages = events.messages(2, WAIT);
+    assert_eq!(messages[0].html, None);
+    assert_eq!(
+        messages[1].html.as_deref(),
+        Some("<p><strong>bold</strong></p>\n")
+    );
+    assert_eq!(messages[1].message, "**bold**");
+}
+
+#[test]
+fn render_markdown_renders_every_message() {
+    let chat = TestChat::configured(|figment| figment.merge(("chat.render_markdown", true)));
+    let mut events = chat.events("room=lobby");
+    chat.post("lobby", "alice", "*hi* <script>x</script>");
+
+    let messages = events.messages(1, WAIT);
+    assert_eq!(messages[0].html.as_deref(), Some("<p><em>hi</em> </p>\n"));
+}
+
+#[test]
+fn history_pages_walk_back_through_a_room() {
+    let chat = TestChat::configured(unlimited);
+    let _events = chat.events("room=lobby");
+    let ids: Vec<_> = (0..5)
+        .map(|i| chat.post_id("lobby", "alice", &format!("message {}", i)))
+        .collect();
+    let page_ids = |page: &rocket::serde::json::Value| -> Vec<u64> {
+        page["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|msg| msg["id"].as_u64().unwrap())
+            .collect()
+    };
+
+    let first = chat.send(chat.get("/history?room=lobby&limit=2")).json();
+    assert_eq!(page_ids(&first), [ids[4], ids[3]]);
+    assert_eq!(first["has_more"], true);
+    assert_eq!(first["next_before"], ids[3]);
+
+    let uri = format!("/history?room=lobby&limit=2&before={}", ids[3]);
+    let second = chat.send(chat.get(uri)).json();
+    assert_eq!(page_ids(&second), [ids[2], ids[1]]);
+    assert_eq!(second["has_more"], true);
+
+    let uri = format!("/history?room=lobby&limit=2&before={}", ids[1]);
+    let last = chat.send(chat.get(uri)).json();
+    assert_eq!(page_ids(&last), [ids[0]]);
+    assert_eq!(last["has_more"], false);
+
+    let uri = format!("/history?room=lobby&limit=2&before={}", ids[0]);
+    let empty = chat.send(chat.get(uri)).json();
+    assert_eq!(empty["messages"], json!([]));
+    assert!(empty["next_before"].is_null());
+}
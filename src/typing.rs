@@ -0,0 +1,55 @@
+use crate::auth::SessionToken;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::guards::ClientIp;
+use crate::message::{
+    max_graphemes, normalize_room, not_reserved, safe_name, Message, MAX_ROOM_LEN, MAX_USERNAME_LEN,
+};
+use crate::rate_limit::{RateLimited, Typing};
+use rocket::form::{self, Form};
+use rocket::http::Status;
+use rocket::serde::Deserialize;
+
+// Form data accepted by /typing, the same room and username rules as a MessageForm
+#[derive(Debug, FromForm, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TypingForm {
+    #[field(validate = max_graphemes(..MAX_ROOM_LEN))]
+    #[field(validate = safe_name())]
+    pub room: String,
+    #[field(validate = max_graphemes(1..MAX_USERNAME_LEN))]
+    #[field(validate = safe_name())]
+    #[field(validate = not_reserved())]
+    pub username: String,
+}
+
+// Endpoint to Announce Typing
+// Broadcasts a transient Message of kind Typing, /events subscribers of the room receive it as a "typing" event
+// -- Never persisted and limited by its own, looser, rate limit since clients send it on every few keystrokes
+// -- A reserved username still needs its session token, and banned users get a 403
+#[post("/typing", data = "<form>")]
+pub fn typing(
+    _limit: RateLimited<Typing>,
+    form: Result<Form<TypingForm>, form::Errors<'_>>,
+    token: SessionToken,
+    ip: ClientIp,
+    chat: Chat<'_>,
+) -> Result<Status, ApiError> {
+    let ClientIp(ip) = ip;
+    let TypingForm { room, username } = form.map_err(ApiError::from_form)?.into_inner();
+    let room = normalize_room(&room);
+    chat.check_ban(Some(&room), Some(&username), ip)?;
+    if !chat.reservations.authorize(&username, token.0.as_deref()) {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            format!("username {} is reserved", username),
+        ));
+    }
+
+    // Nobody listening just means nobody to tell
+    let _res = chat
+        .queue
+        .send(Message::typing(chat.ids.next(), room, username));
+
+    Ok(Status::NoContent)
+}
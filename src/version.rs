@@ -0,0 +1,45 @@
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+
+// Body returned by /version, i.e {"version":"0.1.0","git_hash":"a522a5d","rustc":"rustc 1.95.0 (...)"}
+// -- version -> the crate version from Cargo.toml
+// -- git_hash -> the commit the server was built from, left out when it wasn't built from a git checkout
+// -- rustc -> the compiler it was built with, see build.rs
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Version {
+    pub version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_hash: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rustc: Option<&'static str>,
+}
+
+// Endpoint to tell which build is running, i.e behind a load balancer
+#[get("/version")]
+pub fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: option_env!("GIT_HASH"),
+        rustc: option_env!("RUSTC_VERSION"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestChat;
+    use rocket::http::Status;
+
+    #[test]
+    fn version_tells_the_crate_version() {
+        let chat = TestChat::new();
+        let reply = chat.send(chat.get("/version"));
+        assert_eq!(reply.status, Status::Ok);
+        let body = reply.json();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["git_hash"].as_str(), option_env!("GIT_HASH"));
+        assert!(body["rustc"]
+            .as_str()
+            .is_some_and(|rustc| rustc.starts_with("rustc ")));
+    }
+}
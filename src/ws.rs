@@ -0,0 +1,620 @@
+use crate::auth::{ApiKey, SessionToken, User};
+use crate::bans::BAN_CHECK_INTERVAL;
+use crate::chat::Chat;
+use crate::error::ApiError;
+use crate::guards::ClientIp;
+use crate::message::{
+    check_name, graphemes, normalize_room, Message, MessageForm, MAX_ROOM_LEN, MAX_USERNAME_LEN,
+    SYSTEM_USERNAME,
+};
+use crate::rate_limit::{Messages, RateLimiter};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::Status;
+use rocket::serde::json::{self, json, Value};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::time::{interval_at, sleep, Duration, Instant};
+use rocket::{Shutdown, State};
+use rocket_ws::{Channel, WebSocket};
+use std::net::IpAddr;
+
+// WebSocket Endpoint
+// An alternative to /message + /events where a single connection carries traffic both ways
+// -- Text frames sent by the client are JSON encoded MessageForms, published exactly like a posted form
+//    one that isn't posted is answered with a text frame holding the error, i.e {"error":"invalid message format","code":422}
+// -- A reserved username needs its session token on the upgrade request, as a header or cookie
+// -- Once an api_key is configured, frames are only posted when the upgrade request had the X-API-Key header, others get a 401 error frame
+//    listening doesn't need the key, as with /events
+// -- Each frame takes a token from the client's rate limit like a post to /message does, frames over it are answered with a 429 error frame
+// -- A browser signed in through /session posts and listens as its User, whatever username the frames or the query give
+// -- Every broadcast Message (optionally just for one room, i.e /ws?room=lobby) is sent back as a JSON text frame
+//    the socket opens with the same history /events replays, everything after last_id for a socket reconnecting with /ws?last_id=41
+// -- Direct messages are only sent when the socket says who it is, i.e /ws?room=lobby&username=alice
+// -- What /events tells with a named event comes as a text frame naming it, i.e {"event":"lagged","skipped":3}
+//    lagged -> the socket fell behind and skipped messages, slow_consumer -> it fell behind max_buffered_events and is closed
+//    banned -> the user got banned while connected, shutdown -> the server is shutting down (after shutdown_drain_ms), both close the socket
+// -- Banned users get a 403 instead of the upgrade
+// Both transports share the same broadcast channel, so WebSocket and SSE clients see each other's messages
+#[get("/ws?<room>&<username>&<last_id>")]
+#[allow(clippy::too_many_arguments)]
+pub fn ws<'r>(
+    room: Option<String>,
+    username: Option<String>,
+    last_id: Option<u64>,
+    ws: WebSocket,
+    user: Option<User>,
+    token: SessionToken,
+    key: Result<ApiKey, ()>,
+    ip: ClientIp,
+    limiter: &'r State<RateLimiter<Messages>>,
+    chat: Chat<'r>,
+    mut end: Shutdown,
+) -> Result<Channel<'r>, ApiError> {
+    let ClientIp(ip) = ip;
+    let user = user.map(|User(username)| username);
+    let username = user.clone().or(username);
+    check_query(room.as_deref(), username.as_deref())?;
+    let room = room.as_deref().map(normalize_room);
+    chat.check_ban(room.as_deref(), username.as_deref(), ip)?;
+    if let Some(room) = &room {
+        chat.check_room(room)?;
+    }
+
+    // Like /events, receiving a reserved username's direct messages takes its session token
+    let claimed = username.clone();
+    let username = username.filter(|name| chat.reservations.authorize(name, token.0.as_deref()));
+    // Subscribed before the history is read, as /events does
+    let max_buffered = chat.config.max_buffered_events;
+    let mut rx = chat
+        .queue
+        .subscribe(room.as_deref(), username.as_deref())
+        .buffered(max_buffered);
+    let mut ban_check = interval_at(Instant::now() + BAN_CHECK_INTERVAL, BAN_CHECK_INTERVAL);
+    let drain = Duration::from_millis(chat.config.shutdown_drain_ms);
+    let mut drained = Box::pin(sleep(drain));
+    let mut draining = false;
+
+    Ok(ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let poster = Poster {
+                chat: &chat,
+                limiter,
+                user: user.as_deref(),
+                token: token.0.as_deref(),
+                key: key.ok(),
+                ip,
+            };
+
+            // Replay history first, remembering the newest id so it isn't repeated by the live messages
+            let history = chat
+                .backlog(room.as_deref(), username.as_deref(), last_id)
+                .await;
+            let mut last_replayed = last_id.unwrap_or_default();
+            for msg in history {
+                last_replayed = msg.id;
+                stream.send(message_frame(&msg)).await?;
+            }
+
+            loop {
+                select! {
+                    // A frame from the client
+                    frame = stream.next() => match frame {
+                        Some(Ok(rocket_ws::Message::Text(text))) => {
+                            // A frame that can't be posted is answered with an error frame, the socket stays open for the next one
+                            if let Err(e) = poster.submit(&text).await {
+                                stream.send(rocket_ws::Message::Text(e.to_json())).await?;
+                            }
+                        }
+                        Some(Ok(rocket_ws::Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,                // Pings are answered by the library, binary frames are ignored
+                        Some(Err(e)) => return Err(e),
+                    },
+
+                    // A message broadcast by anyone, mirroring what /events yields
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => {
+                            if msg.id <= last_replayed || !msg.visible_to(room.as_deref(), username.as_deref()) {
+                                continue;
+                            }
+
+                            stream.send(message_frame(&msg)).await?;
+                        }
+                        Err(RecvError::Closed) => break,
+                        Err(RecvError::Lagged(n)) => {
+                            if max_buffered.is_some() {
+                                let notice = json!({ "event": "slow_consumer", "message": "you are reading too slowly" });
+                                stream.send(notice_frame(notice)).await?;
+                                break;
+                            }
+                            chat.metrics.lagged();
+                            stream.send(notice_frame(json!({ "event": "lagged", "skipped": n }))).await?;
+                        }
+                    },
+
+                    _ = ban_check.tick() => {
+                        if chat.bans.is_banned_from(room.as_deref(), claimed.as_deref(), ip) {
+                            let notice = json!({ "event": "banned", "message": "you are banned" });
+                            stream.send(notice_frame(notice)).await?;
+                            break;
+                        }
+                    },
+
+                    // Like /events, keep delivering what's already been broadcast for shutdown_drain_ms once shutdown is requested
+                    _ = &mut end, if !draining => {
+                        draining = true;
+                        drained.as_mut().reset(Instant::now() + drain);
+                    },
+
+                    _ = &mut drained, if draining => {
+                        let notice = json!({ "event": "shutdown", "message": "server is shutting down" });
+                        stream.send(notice_frame(notice)).await?;
+                        break;
+                    },
+                }
+            }
+
+            Ok(())
+        })
+    }))
+}
+
+// A Message as the text frame a socket gets it in
+fn message_frame(msg: &Message) -> rocket_ws::Message {
+    rocket_ws::Message::Text(json::to_string(msg).expect("messages serialize to json"))
+}
+
+// What /events sends as a named event as the text frame a socket gets it in, see ws
+fn notice_frame(notice: Value) -> rocket_ws::Message {
+    rocket_ws::Message::Text(notice.to_string())
+}
+
+// What the frames of a socket are posted with, taken from its upgrade request
+// -- user -> the username signed in through /session, it replaces the one each frame gives
+// -- token -> the session token the upgrade request presented, needed to post as a reserved username
+// -- key -> what the ApiKey guard made of the upgrade request, None when it presented no api_key or a wrong one
+struct Poster<'a, 'r> {
+    chat: &'a Chat<'r>,
+    limiter: &'a RateLimiter<Messages>,
+    user: Option<&'a str>,
+    token: Option<&'a str>,
+    key: Option<ApiKey>,
+    ip: Option<IpAddr>,
+}
+
+impl Poster<'_, '_> {
+    // Post the MessageForm a client sent as a text frame
+    // Fails like /message does, or with a 422 when the frame isn't a MessageForm at all
+    async fn submit(&self, text: &str) -> Result<(), ApiError> {
+        let Some(key) = self.key else {
+            return Err(ApiError::new(
+                Status::Unauthorized,
+                "missing or invalid API key",
+            ));
+        };
+        if self.ip.is_some_and(|ip| !self.limiter.check(ip)) {
+            return Err(ApiError::new(
+                Status::TooManyRequests,
+                "rate limit exceeded",
+            ));
+        }
+
+        let mut form = json::from_str::<MessageForm>(text).map_err(|e| {
+            debug!("malformed websocket frame: {}", e);
+            ApiError::new(Status::UnprocessableEntity, "invalid message format")
+        })?;
+        if let Some(user) = self.user {
+            form.username = user.to_string();
+        }
+        form.validate()
+            .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+
+        // A frame to a room nobody listens to (this socket may only listen to its own) gets a 503 like a post would
+        self.chat
+            .submit(form, self.token, key, None, self.ip)
+            .await?;
+        Ok(())
+    }
+}
+
+// Rooms and usernames given in the query string of /events or /ws must be safe names (see is_safe_name) no longer than posted ones
+// The username can't be the reserved "system" either, or the client would show up in /users and the join announcements as the server
+// Checked before the stream or socket opens, so a bad parameter gets a 400 instead of a connection that never yields anything
+pub fn check_query(room: Option<&str>, username: Option<&str>) -> Result<(), ApiError> {
+    let invalid = |e: String| ApiError::new(Status::BadRequest, e);
+    if let Some(room) = room {
+        if graphemes(room) >= MAX_ROOM_LEN {
+            return Err(invalid(format!(
+                "room must be shorter than {} characters",
+                MAX_ROOM_LEN
+            )));
+        }
+        check_name("room", room).map_err(invalid)?;
+    }
+    if let Some(username) = username {
+        if graphemes(username) >= MAX_USERNAME_LEN {
+            return Err(invalid(format!(
+                "username must be shorter than {} characters",
+                MAX_USERNAME_LEN
+            )));
+        }
+        check_name("username", username).map_err(invalid)?;
+        if username == SYSTEM_USERNAME {
+            return Err(invalid("username is reserved".into()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::API_KEY_HEADER;
+    use crate::testing::{
+        admin_token, unlimited, TestChat, TestServer, ADMIN_TOKEN, LOCAL_IP, WAIT,
+    };
+    use rocket::http::Header;
+    use rocket::request::FromRequest;
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::TcpStream;
+    use rocket::tokio::time::timeout;
+
+    // Post `frames` the way a socket signed in as `user` would, answering the status each one got
+    // -- api_key -> the X-API-Key header of the upgrade request, if any
+    fn send_frames(
+        chat: &TestChat,
+        limiter: &RateLimiter<Messages>,
+        user: Option<&str>,
+        api_key: Option<&str>,
+        frames: &[&str],
+    ) -> Vec<Result<(), Status>> {
+        submit_frames(chat, limiter, user, api_key, frames)
+            .into_iter()
+            .map(|result| result.map_err(|e| e.status))
+            .collect()
+    }
+
+    // Post `frames` like send_frames, answering the error frame each rejected one got back
+    fn error_frames(chat: &TestChat, frames: &[&str]) -> Vec<Option<String>> {
+        let limiter = RateLimiter::new(100, 100);
+        submit_frames(chat, &limiter, None, None, frames)
+            .into_iter()
+            .map(|result| result.err().map(|e| e.to_json()))
+            .collect()
+    }
+
+    fn submit_frames(
+        chat: &TestChat,
+        limiter: &RateLimiter<Messages>,
+        user: Option<&str>,
+        api_key: Option<&str>,
+        frames: &[&str],
+    ) -> Vec<Result<(), ApiError>> {
+        let mut request = chat.get("/ws");
+        if let Some(key) = api_key {
+            request = request.header(Header::new(API_KEY_HEADER, key.to_string()));
+        }
+        chat.block_on(async {
+            let guard = Chat::from_request(request.inner())
+                .await
+                .succeeded()
+                .unwrap();
+            let key = ApiKey::from_request(request.inner()).await.succeeded();
+            let poster = Poster {
+                chat: &guard,
+                limiter,
+                user,
+                token: None,
+                key,
+                ip: Some(LOCAL_IP),
+            };
+            let mut results = Vec::new();
+            for frame in frames {
+                results.push(poster.submit(frame).await);
+            }
+            results
+        })
+    }
+
+    // A WebSocket client just good enough for the tests, on a real socket of a TestServer
+    // Frames from the server are never masked, the ones sent to it are masked with a key of zeros (leaving them as they are)
+    struct Socket {
+        stream: TcpStream,
+        read: Vec<u8>,
+    }
+
+    impl Socket {
+        // Open /ws with the given query, panics when the upgrade is turned down
+        async fn open(server: &TestServer, query: &str) -> Socket {
+            let mut stream = server.connect().await;
+            let upgrade = format!(
+                "GET /ws?{} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                query
+            );
+            stream.write_all(upgrade.as_bytes()).await.unwrap();
+            let mut socket = Socket {
+                stream,
+                read: Vec::new(),
+            };
+
+            let end = timeout(WAIT, async {
+                loop {
+                    if let Some(end) = socket.read.windows(4).position(|w| w == b"\r\n\r\n") {
+                        return end + 4;
+                    }
+                    assert!(socket.fill().await, "the server closed the connection");
+                }
+            })
+            .await
+            .expect("the upgrade wasn't answered");
+            let response = String::from_utf8_lossy(&socket.read[..end]).into_owned();
+            assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+            socket.read.drain(..end);
+            socket
+        }
+
+        // Read what the server sent next, false once it closed the connection
+        async fn fill(&mut self) -> bool {
+            let mut buf = [0; 4096];
+            let n = self.stream.read(&mut buf).await.unwrap_or(0);
+            self.read.extend_from_slice(&buf[..n]);
+            n > 0
+        }
+
+        // The next text frame as JSON, None once the server closed the socket
+        async fn next(&mut self) -> Option<Value> {
+            timeout(WAIT, async {
+                loop {
+                    if let Some((opcode, payload)) = self.frame() {
+                        match opcode {
+                            0x1 => return Some(json::from_slice(&payload).unwrap()),
+                            0x8 => return None,
+                            _ => continue,
+                        }
+                    }
+                    if !self.fill().await {
+                        return None;
+                    }
+                }
+            })
+            .await
+            .expect("no frame came")
+        }
+
+        // Take the frame at the start of what was read, with its opcode, if it came in full
+        fn frame(&mut self) -> Option<(u8, Vec<u8>)> {
+            let read = &self.read;
+            if read.len() < 2 {
+                return None;
+            }
+            let (len, start) = match read[1] & 0x7f {
+                126 if read.len() >= 4 => (u16::from_be_bytes([read[2], read[3]]) as usize, 4),
+                127 if read.len() >= 10 => (
+                    u64::from_be_bytes(read[2..10].try_into().unwrap()) as usize,
+                    10,
+                ),
+                126 | 127 => return None,
+                len => (len as usize, 2),
+            };
+            if read.len() < start + len {
+                return None;
+            }
+            let opcode = read[0] & 0x0f;
+            let payload = read[start..start + len].to_vec();
+            self.read.drain(..start + len);
+            Some((opcode, payload))
+        }
+
+        // Send `text` as a text frame
+        async fn send(&mut self, text: &str) {
+            let mut frame = vec![0x81];
+            match text.len() {
+                len @ 0..=125 => frame.push(0x80 | len as u8),
+                len => {
+                    frame.push(0x80 | 126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+            }
+            frame.extend_from_slice(&[0; 4]);
+            frame.extend_from_slice(text.as_bytes());
+            self.stream.write_all(&frame).await.unwrap();
+        }
+    }
+
+    // Send an HTTP request with the admin token over a connection of its own, answering the status line
+    async fn admin_request(server: &TestServer, uri: &str, body: &Value) -> String {
+        let mut stream = server.connect().await;
+        let body = body.to_string();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\n\
+             {}: {}\r\nContent-Length: {}\r\n\r\n{}",
+            uri,
+            crate::admin::ADMIN_TOKEN_HEADER,
+            ADMIN_TOKEN,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        timeout(WAIT, stream.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    fn frame(username: &str, message: &str) -> String {
+        json::json!({ "room": "lobby", "username": username, "message": message }).to_string()
+    }
+
+    #[test]
+    fn queries_take_safe_names_only() {
+        assert!(check_query(Some("lobby"), Some("alice")).is_ok());
+        assert!(check_query(None, None).is_ok());
+        assert!(check_query(Some("lob by"), None).is_err());
+        assert!(check_query(None, Some("alice/bob")).is_err());
+        assert!(check_query(Some(&"a".repeat(MAX_ROOM_LEN)), None).is_err());
+        assert!(check_query(None, Some(&"a".repeat(MAX_USERNAME_LEN))).is_err());
+    }
+
+    #[test]
+    fn queries_cant_claim_the_system_username() {
+        let e = check_query(Some("lobby"), Some(SYSTEM_USERNAME)).unwrap_err();
+        assert_eq!(e.status, Status::BadRequest);
+        assert_eq!(e.message, "username is reserved");
+    }
+
+    #[test]
+    fn bad_frames_get_an_error_frame_and_the_next_one_goes_through() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let long = frame(&"a".repeat(MAX_USERNAME_LEN), "hi");
+        let frames = [
+            "not json {",
+            r#"{"room":"lobby"}"#,
+            &long,
+            &frame("alice", "hi"),
+        ];
+
+        let errors = error_frames(&chat, &frames);
+        assert_eq!(
+            errors,
+            [
+                Some(r#"{"error":"invalid message format","code":422}"#.to_string()),
+                Some(r#"{"error":"invalid message format","code":422}"#.to_string()),
+                Some(format!(
+                    r#"{{"error":"username must be shorter than {} characters","code":422}}"#,
+                    MAX_USERNAME_LEN
+                )),
+                None,
+            ]
+        );
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages[0].message, "hi");
+    }
+
+    #[test]
+    fn frames_past_the_burst_get_429() {
+        let chat = TestChat::new();
+        let _events = chat.events("room=lobby");
+        let limiter = RateLimiter::new(1, 2);
+        let frames = [
+            frame("alice", "one"),
+            frame("alice", "two"),
+            frame("alice", "three"),
+        ];
+        let frames: Vec<_> = frames.iter().map(String::as_str).collect();
+
+        let results = send_frames(&chat, &limiter, None, None, &frames);
+        assert_eq!(results, [Ok(()), Ok(()), Err(Status::TooManyRequests)]);
+    }
+
+    #[test]
+    fn frames_post_as_the_signed_in_user() {
+        let chat = TestChat::new();
+        let mut events = chat.events("room=lobby");
+        let limiter = RateLimiter::new(5, 5);
+
+        let results = send_frames(
+            &chat,
+            &limiter,
+            Some("alice"),
+            None,
+            &[&frame("mallory", "hi")],
+        );
+        assert_eq!(results, [Ok(())]);
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages[0].username, "alice");
+    }
+
+    #[test]
+    fn frames_take_the_api_key_once_one_is_configured() {
+        let chat = TestChat::configured(|figment| figment.merge(("chat.api_key", "bot-key")));
+        let mut events = chat.events("room=lobby");
+        let limiter = RateLimiter::new(5, 5);
+        let hi = frame("bot", "hi");
+
+        let results = send_frames(&chat, &limiter, None, None, &[&hi]);
+        assert_eq!(results, [Err(Status::Unauthorized)]);
+        let results = send_frames(&chat, &limiter, None, Some("guess"), &[&hi]);
+        assert_eq!(results, [Err(Status::Unauthorized)]);
+        let results = send_frames(&chat, &limiter, None, Some("bot-key"), &[&hi]);
+        assert_eq!(results, [Ok(())]);
+
+        let messages = events.messages(1, WAIT);
+        assert_eq!(messages[0].username, "bot");
+    }
+
+    #[test]
+    fn frames_need_no_api_key_when_none_is_configured() {
+        let chat = TestChat::new();
+        let _events = chat.events("room=lobby");
+        let limiter = RateLimiter::new(5, 5);
+        let results = send_frames(&chat, &limiter, None, None, &[&frame("alice", "hi")]);
+        assert_eq!(results, [Ok(())]);
+    }
+
+    #[rocket::async_test]
+    async fn sockets_open_with_the_history_and_pick_up_after_last_id() {
+        let server = TestServer::launch(unlimited).await;
+        let mut alice = Socket::open(&server, "room=lobby").await;
+        let mut ids = Vec::new();
+        for message in ["one", "two", "three"] {
+            alice.send(&frame("alice", message)).await;
+            let msg = alice.next().await.unwrap();
+            assert_eq!(msg["message"], message);
+            ids.push(msg["id"].as_u64().unwrap());
+        }
+
+        // A socket connecting late catches up on what it missed
+        let mut late = Socket::open(&server, "room=lobby").await;
+        for message in ["one", "two", "three"] {
+            assert_eq!(late.next().await.unwrap()["message"], message);
+        }
+
+        // A reconnecting one only gets what came after the last id it saw, and nothing twice
+        let query = format!("room=lobby&last_id={}", ids[0]);
+        let mut back = Socket::open(&server, &query).await;
+        alice.send(&frame("alice", "four")).await;
+        let bodies = [
+            back.next().await.unwrap(),
+            back.next().await.unwrap(),
+            back.next().await.unwrap(),
+        ]
+        .map(|msg| msg["message"].as_str().unwrap().to_string());
+        assert_eq!(bodies, ["two", "three", "four"]);
+
+        server.stop().await;
+    }
+
+    #[rocket::async_test]
+    async fn sockets_get_a_shutdown_frame_once_drained() {
+        let server =
+            TestServer::launch(|figment| unlimited(figment).merge(("chat.shutdown_drain_ms", 300)))
+                .await;
+        let mut socket = Socket::open(&server, "room=lobby").await;
+
+        server.shutdown();
+        let notice = socket.next().await.unwrap();
+        assert_eq!(
+            notice,
+            json!({ "event": "shutdown", "message": "server is shutting down" })
+        );
+        assert!(socket.next().await.is_none());
+        server.stop().await;
+    }
+
+    #[rocket::async_test]
+    async fn sockets_of_a_newly_banned_user_get_a_banned_frame() {
+        let server = TestServer::launch(admin_token).await;
+        let mut socket = Socket::open(&server, "room=lobby&username=mallory").await;
+
+        let ban = admin_request(&server, "/admin/ban", &json!({ "username": "mallory" })).await;
+        assert!(ban.contains("204"), "{}", ban);
+        let notice = socket.next().await.unwrap();
+        assert_eq!(notice["event"], "banned");
+        assert!(socket.next().await.is_none());
+        server.stop().await;
+    }
+}
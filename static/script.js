@@ -93,12 +93,37 @@ function subscribe(uri) {
     // Will stay open until event.close() is called
     const events = new EventSource(uri);
 
-    events.addEventListener("message", (ev) => {
+    // Chat messages come as the default "message" event, announcements from the server as "system" events
+    const showMessage = (ev) => {
       console.log("raw data", JSON.stringify(ev.data));
       console.log("decoded data", JSON.stringify(JSON.parse(ev.data)));
       const msg = JSON.parse(ev.data);
       if (!"message" in msg || !"room" in msg || !"username" in msg) return;
       addMessage(msg.room, msg.username, msg.message, true);
+    };
+    events.addEventListener("message", showMessage);
+    events.addEventListener("system", showMessage);
+
+    // The server announces it is going down, show we're reconnecting before the stream drops
+    events.addEventListener("shutdown", () => {
+      setConnectedStatus(false);
+      console.log("server is shutting down");
+    });
+
+    // The server closes streams that have been open for too long, we reconnect right after
+    events.addEventListener("timeout", () => {
+      console.log("event stream timed out, reconnecting");
+    });
+
+    // Every few seconds the server tells how many are listening
+    events.addEventListener("stats", (ev) => {
+      const stats = JSON.parse(ev.data);
+      statusDiv.title = `${stats.total} online`;
+    });
+
+    // The server skipped messages because we fell too far behind, the history has them
+    events.addEventListener("lagged", (ev) => {
+      console.log(`missed ${ev.data} messages, check /history to catch up`);
     });
 
     events.addEventListener("open", () => {
@@ -165,7 +190,8 @@ function init() {
   newRoomForm.addEventListener("submit", (e) => {
     e.preventDefault();
 
-    const room = roomNameField.value;
+    // Rooms are case-insensitive, the server sends them back lowercased
+    const room = roomNameField.value.toLowerCase();
     if (!room) return;
 
     roomNameField.value = "";